@@ -14,12 +14,15 @@ use crate::{
     expn::{command_expn_args, ExpnCommand},
     helo::{command_helo_args, HeloCommand},
     help::{command_help_args, HelpCommand},
+    lhlo::{command_lhlo_args, LhloCommand},
     mail::{command_mail_args, MailCommand},
     noop::{command_noop_args, NoopCommand},
     quit::{command_quit_args, QuitCommand},
     rcpt::{command_rcpt_args, RcptCommand},
     rset::{command_rset_args, RsetCommand},
     vrfy::{command_vrfy_args, VrfyCommand},
+    xclient::{command_xclient_args, XclientCommand},
+    xforward::{command_xforward_args, XforwardCommand},
 };
 
 #[cfg_attr(test, derive(PartialEq))]
@@ -30,12 +33,17 @@ pub enum Command {
     Expn(ExpnCommand), // EXPN <name> <CRLF>
     Helo(HeloCommand), // HELO <domain> <CRLF>
     Help(HelpCommand), // HELP [<subject>] <CRLF>
+    Lhlo(LhloCommand), // LHLO <domain> <CRLF> (RFC 2033)
     Mail(MailCommand), // MAIL FROM:<@ONE,@TWO:JOE@THREE> [SP <mail-parameters>] <CRLF>
     Noop(NoopCommand), // NOOP [<string>] <CRLF>
     Quit(QuitCommand), // QUIT <CRLF>
     Rcpt(RcptCommand), // RCPT TO:<@ONE,@TWO:JOE@THREE> [SP <rcpt-parameters] <CRLF>
     Rset(RsetCommand), // RSET <CRLF>
     Vrfy(VrfyCommand), // VRFY <name> <CRLF>
+    // XCLIENT <attribute=value> [SP <attribute=value>...] <CRLF> (Postfix)
+    Xclient(XclientCommand),
+    // XFORWARD <attribute=value> [SP <attribute=value>...] <CRLF> (Postfix)
+    Xforward(XforwardCommand),
 }
 
 impl Command {
@@ -50,12 +58,15 @@ impl Command {
             &Command::Expn(ref c) => c.send_to(w),
             &Command::Helo(ref c) => c.send_to(w),
             &Command::Help(ref c) => c.send_to(w),
+            &Command::Lhlo(ref c) => c.send_to(w),
             &Command::Mail(ref c) => c.send_to(w),
             &Command::Noop(ref c) => c.send_to(w),
             &Command::Quit(ref c) => c.send_to(w),
             &Command::Rcpt(ref c) => c.send_to(w),
             &Command::Rset(ref c) => c.send_to(w),
             &Command::Vrfy(ref c) => c.send_to(w),
+            &Command::Xclient(ref c) => c.send_to(w),
+            &Command::Xforward(ref c) => c.send_to(w),
         }
     }
 }
@@ -66,12 +77,15 @@ named!(command(ByteSlice) -> Command, alt!(
     map!(command_expn_args, Command::Expn) |
     map!(command_helo_args, Command::Helo) |
     map!(command_help_args, Command::Help) |
+    map!(command_lhlo_args, Command::Lhlo) |
     map!(command_mail_args, Command::Mail) |
     map!(command_noop_args, Command::Noop) |
     map!(command_quit_args, Command::Quit) |
     map!(command_rcpt_args, Command::Rcpt) |
     map!(command_rset_args, Command::Rset) |
-    map!(command_vrfy_args, Command::Vrfy)
+    map!(command_vrfy_args, Command::Vrfy) |
+    map!(command_xclient_args, Command::Xclient) |
+    map!(command_xforward_args, Command::Xforward)
 ));
 
 #[cfg(test)]
@@ -155,6 +169,17 @@ mod tests {
                     }
                 }),
             ),
+            (
+                &b"LHLO foo.bar.baz\r\n"[..],
+                Box::new(|x| {
+                    if let Command::Lhlo(r) = x {
+                        SmtpString::from_sendable(r.domain()).unwrap()
+                            == SmtpString::from(&b"foo.bar.baz"[..])
+                    } else {
+                        false
+                    }
+                }),
+            ),
             (
                 &b"MAIL FROM:<hello@world.example>\r\n"[..],
                 Box::new(|x| {
@@ -241,6 +266,26 @@ mod tests {
                     }
                 }),
             ),
+            (
+                &b"XCLIENT ADDR=192.0.2.1\r\n"[..],
+                Box::new(|x| {
+                    if let Command::Xclient(r) = x {
+                        r.attrs.get(b"addr") == Some(&SmtpString::from(&b"192.0.2.1"[..]))
+                    } else {
+                        false
+                    }
+                }),
+            ),
+            (
+                &b"XFORWARD HELO=foo.example.org\r\n"[..],
+                Box::new(|x| {
+                    if let Command::Xforward(r) = x {
+                        r.attrs.get(b"HELO") == Some(&SmtpString::from(&b"foo.example.org"[..]))
+                    } else {
+                        false
+                    }
+                }),
+            ),
         ];
         for (s, r) in tests.into_iter() {
             let b = Bytes::from(s);
@@ -10,7 +10,7 @@ use crate::{
 };
 
 // TODO: (C) Make equivalent emails (modulo escaping) be equal?
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Email {
     localpart: SmtpString,
     hostname:  Option<Domain>,
@@ -0,0 +1,82 @@
+use std::io;
+
+use crate::{
+    byteslice::ByteSlice,
+    domain::{hostname, Domain},
+    sendable::Sendable,
+    stupidparsers::eat_spaces,
+};
+
+#[cfg_attr(test, derive(PartialEq))]
+#[derive(Debug)]
+pub struct LhloCommand {
+    domain: Domain,
+}
+
+impl LhloCommand {
+    pub fn new(domain: Domain) -> LhloCommand {
+        LhloCommand { domain }
+    }
+
+    pub fn domain(&self) -> &Domain {
+        &self.domain
+    }
+
+    pub fn send_to(&self, w: &mut io::Write) -> io::Result<()> {
+        w.write_all(b"LHLO ")?;
+        self.domain.send_to(w)?;
+        w.write_all(b"\r\n")
+    }
+}
+
+named!(pub command_lhlo_args(ByteSlice) -> LhloCommand,
+    sep!(eat_spaces, do_parse!(
+        tag_no_case!("LHLO") >>
+        domain: hostname >>
+        tag!("\r\n") >>
+        (LhloCommand {
+            domain: domain.into(),
+        })
+    ))
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use nom::IResult;
+
+    use crate::smtpstring::SmtpString;
+
+    #[test]
+    fn valid_command_lhlo_args() {
+        let tests = vec![
+            (&b"lHlO \t hello.world \t \r\n"[..], b"hello.world"),
+            (&b"LHLO hello.world\r\n"[..], b"hello.world"),
+        ];
+        for (s, r) in tests.into_iter() {
+            let b = Bytes::from(s);
+            match command_lhlo_args(ByteSlice::from(&b)) {
+                IResult::Done(rem, LhloCommand { ref domain })
+                    if rem.len() == 0
+                        && SmtpString::from_sendable(domain).unwrap().bytes()
+                            == &Bytes::from(&r[..]) =>
+                {
+                    ()
+                }
+                x => panic!("Unexpected result: {:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn valid_builds() {
+        let mut v = Vec::new();
+        let b = Bytes::from(&b"test.foo.bar"[..]);
+        LhloCommand::new(Domain::parse_slice(&b).unwrap())
+            .send_to(&mut v)
+            .unwrap();
+        assert_eq!(v, b"LHLO test.foo.bar\r\n");
+    }
+}
@@ -24,18 +24,22 @@ mod parseresult;
 mod sendable;
 mod smtpstring;
 mod streamext;
+mod xtext;
 
 mod data;
 mod ehlo;
 mod expn;
 mod helo;
 mod help;
+mod lhlo;
 mod mail;
 mod noop;
 mod quit;
 mod rcpt;
 mod rset;
 mod vrfy;
+mod xclient;
+mod xforward;
 
 mod command;
 mod reply;
@@ -46,6 +50,7 @@ pub use parseresult::ParseError;
 pub use sendable::Sendable;
 pub use smtpstring::SmtpString;
 pub use streamext::{Prependable, StreamExt};
+pub use xtext::Attributes;
 
 pub use command::Command;
 pub use reply::{IsLastLine, ReplyCode, ReplyLine};
@@ -55,9 +60,12 @@ pub use ehlo::EhloCommand;
 pub use expn::ExpnCommand;
 pub use helo::HeloCommand;
 pub use help::HelpCommand;
+pub use lhlo::LhloCommand;
 pub use mail::MailCommand;
 pub use noop::NoopCommand;
 pub use quit::QuitCommand;
 pub use rcpt::RcptCommand;
 pub use rset::RsetCommand;
 pub use vrfy::VrfyCommand;
+pub use xclient::XclientCommand;
+pub use xforward::XforwardCommand;
@@ -20,6 +20,22 @@ impl MailCommand {
     pub fn new(from: Option<Email>, params: Parameters) -> MailCommand {
         MailCommand { from, params }
     }
+
+    // Size of the mail declared by the SIZE parameter (RFC 1870), if any, or
+    // `Err(())` if the parameter is malformed
+    pub fn size(&self) -> Result<Option<usize>, ()> {
+        match self.params.get(b"SIZE") {
+            None => Ok(None),
+            Some(Some(size)) if size.iter_bytes().all(u8::is_ascii_digit) => {
+                std::str::from_utf8(&size.bytes()[..])
+                    .unwrap()
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| ())
+            }
+            Some(_) => Err(()),
+        }
+    }
 }
 
 impl Sendable for MailCommand {
@@ -112,6 +128,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn declared_size() {
+        let tests: &[(&[u8], Result<Option<usize>, ()>)] = &[
+            (b"MAIL FROM:<>\r\n", Ok(None)),
+            (b"MAIL FROM:<> size=1234\r\n", Ok(Some(1234))),
+            (b"MAIL FROM:<> SIZE\r\n", Err(())),
+            (b"MAIL FROM:<> SIZE=12a\r\n", Err(())),
+            (b"MAIL FROM:<> SIZE=99999999999999999999999\r\n", Err(())),
+        ];
+        for (inp, size) in tests {
+            let b = Bytes::from(*inp);
+            assert_eq!(
+                command_mail_args(ByteSlice::from(&b)).unwrap().1.size(),
+                *size
+            );
+        }
+    }
+
     #[test]
     fn incomplete_args() {
         let b = Bytes::from(&b"MAIL FROM:<foo@bar.com"[..]);
@@ -12,6 +12,14 @@ impl Parameters {
     pub fn none() -> Parameters {
         Parameters(HashMap::new())
     }
+
+    // Parameter names are case-insensitive
+    pub fn get(&self, name: &[u8]) -> Option<&Option<SmtpString>> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.bytes()[..].eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
 }
 
 impl Sendable for Parameters {
@@ -0,0 +1,98 @@
+use nom::crlf;
+use std::io;
+
+use crate::{
+    byteslice::ByteSlice,
+    sendable::Sendable,
+    stupidparsers::eat_spaces,
+    xtext::{parse_attributes, Attributes},
+};
+
+// Postfix extension, for a trusted proxy to override the attributes of the
+// client (NAME, ADDR, PORT, PROTO, HELO, LOGIN, etc.), see
+// http://www.postfix.org/XCLIENT_README.html
+#[cfg_attr(test, derive(PartialEq))]
+#[derive(Debug)]
+pub struct XclientCommand {
+    pub attrs: Attributes,
+}
+
+impl XclientCommand {
+    pub fn new(attrs: Attributes) -> XclientCommand {
+        XclientCommand { attrs }
+    }
+
+    pub fn send_to(&self, w: &mut io::Write) -> io::Result<()> {
+        w.write_all(b"XCLIENT")?;
+        self.attrs.send_to(w)?;
+        w.write_all(b"\r\n")
+    }
+}
+
+named!(pub command_xclient_args(ByteSlice) -> XclientCommand,
+    do_parse!(
+        tag_no_case!("XCLIENT") >>
+        attrs: parse_attributes >> eat_spaces >>
+        crlf >>
+        (XclientCommand { attrs })
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use nom::IResult;
+
+    use crate::smtpstring::SmtpString;
+
+    #[test]
+    fn valid_command_xclient_args() {
+        let tests: &[(&[u8], &[(&[u8], &[u8])])] = &[
+            (
+                b"XCLIENT NAME=foo.example.org ADDR=192.0.2.1\r\n",
+                &[(b"NAME", b"foo.example.org"), (b"ADDR", b"192.0.2.1")],
+            ),
+            (
+                b"xclient  LOGIN=j+2Bdoe HELO=[UNAVAILABLE] \r\n",
+                &[(b"LOGIN", b"j+doe"), (b"HELO", b"[UNAVAILABLE]")],
+            ),
+        ];
+        for (inp, out) in tests {
+            let b = Bytes::from(*inp);
+            match command_xclient_args(ByteSlice::from(&b)) {
+                IResult::Done(rem, res) => {
+                    assert_eq!(rem.len(), 0);
+                    let reference = out
+                        .iter()
+                        .map(|(k, v)| ((*k).into(), (*v).into()))
+                        .collect::<Vec<(SmtpString, SmtpString)>>();
+                    assert_eq!(res.attrs.0, reference);
+                }
+                x => panic!("Unexpected result: {:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_command_xclient_args() {
+        let tests: &[&[u8]] = &[b"XCLIENT\r\n", b"XCLIENT NAME\r\n", b"XCLIENTNAME=foo\r\n"];
+        for inp in tests {
+            let b = Bytes::from(*inp);
+            assert!(command_xclient_args(ByteSlice::from(&b)).is_err());
+        }
+    }
+
+    #[test]
+    fn valid_send_to() {
+        let mut v = Vec::new();
+        XclientCommand::new(Attributes(vec![
+            ("ADDR".into(), "192.0.2.1".into()),
+            ("LOGIN".into(), "j doe".into()),
+        ]))
+        .send_to(&mut v)
+        .unwrap();
+        assert_eq!(v, &b"XCLIENT ADDR=192.0.2.1 LOGIN=j+20doe\r\n"[..]);
+    }
+}
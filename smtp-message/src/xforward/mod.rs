@@ -0,0 +1,103 @@
+use nom::crlf;
+use std::io;
+
+use crate::{
+    byteslice::ByteSlice,
+    sendable::Sendable,
+    stupidparsers::eat_spaces,
+    xtext::{parse_attributes, Attributes},
+};
+
+// Postfix extension, for a trusted content filter to forward the attributes
+// of the original client of a mail (NAME, ADDR, PORT, PROTO, HELO, IDENT,
+// SOURCE), see
+// http://www.postfix.org/XFORWARD_README.html
+#[cfg_attr(test, derive(PartialEq))]
+#[derive(Debug)]
+pub struct XforwardCommand {
+    pub attrs: Attributes,
+}
+
+impl XforwardCommand {
+    pub fn new(attrs: Attributes) -> XforwardCommand {
+        XforwardCommand { attrs }
+    }
+
+    pub fn send_to(&self, w: &mut io::Write) -> io::Result<()> {
+        w.write_all(b"XFORWARD")?;
+        self.attrs.send_to(w)?;
+        w.write_all(b"\r\n")
+    }
+}
+
+named!(pub command_xforward_args(ByteSlice) -> XforwardCommand,
+    do_parse!(
+        tag_no_case!("XFORWARD") >>
+        attrs: parse_attributes >> eat_spaces >>
+        crlf >>
+        (XforwardCommand { attrs })
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use nom::IResult;
+
+    use crate::smtpstring::SmtpString;
+
+    #[test]
+    fn valid_command_xforward_args() {
+        let tests: &[(&[u8], &[(&[u8], &[u8])])] = &[
+            (
+                b"XFORWARD NAME=foo.example.org ADDR=192.0.2.1\r\n",
+                &[(b"NAME", b"foo.example.org"), (b"ADDR", b"192.0.2.1")],
+            ),
+            (
+                b"xforward  IDENT=j+2Bdoe HELO=[UNAVAILABLE] \r\n",
+                &[(b"IDENT", b"j+doe"), (b"HELO", b"[UNAVAILABLE]")],
+            ),
+        ];
+        for (inp, out) in tests {
+            let b = Bytes::from(*inp);
+            match command_xforward_args(ByteSlice::from(&b)) {
+                IResult::Done(rem, res) => {
+                    assert_eq!(rem.len(), 0);
+                    let reference = out
+                        .iter()
+                        .map(|(k, v)| ((*k).into(), (*v).into()))
+                        .collect::<Vec<(SmtpString, SmtpString)>>();
+                    assert_eq!(res.attrs.0, reference);
+                }
+                x => panic!("Unexpected result: {:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_command_xforward_args() {
+        let tests: &[&[u8]] = &[
+            b"XFORWARD\r\n",
+            b"XFORWARD NAME\r\n",
+            b"XFORWARDNAME=foo\r\n",
+        ];
+        for inp in tests {
+            let b = Bytes::from(*inp);
+            assert!(command_xforward_args(ByteSlice::from(&b)).is_err());
+        }
+    }
+
+    #[test]
+    fn valid_send_to() {
+        let mut v = Vec::new();
+        XforwardCommand::new(Attributes(vec![
+            ("ADDR".into(), "192.0.2.1".into()),
+            ("IDENT".into(), "j doe".into()),
+        ]))
+        .send_to(&mut v)
+        .unwrap();
+        assert_eq!(v, &b"XFORWARD ADDR=192.0.2.1 IDENT=j+20doe\r\n"[..]);
+    }
+}
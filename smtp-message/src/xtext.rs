@@ -0,0 +1,147 @@
+use std::io;
+
+use crate::{
+    byteslice::ByteSlice, sendable::Sendable, smtpstring::SmtpString, stupidparsers::eat_spaces,
+};
+
+// Attributes of the XCLIENT and XFORWARD commands, as `NAME=value` pairs with
+// the value decoded from xtext
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Attributes(pub Vec<(SmtpString, SmtpString)>);
+
+impl Attributes {
+    // Attribute names are case-insensitive. The last value sent wins.
+    pub fn get(&self, name: &[u8]) -> Option<&SmtpString> {
+        self.0
+            .iter()
+            .rev()
+            .find(|(k, _)| k.bytes()[..].eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+}
+
+impl Sendable for Attributes {
+    fn send_to(&self, w: &mut io::Write) -> io::Result<()> {
+        for (k, v) in self.0.iter() {
+            w.write_all(b" ")?;
+            w.write_all(&k.bytes()[..])?;
+            w.write_all(b"=")?;
+            w.write_all(&xtext_encode(&v.bytes()[..]))?;
+        }
+        Ok(())
+    }
+}
+
+// See RFC 3461 § 4
+fn xtext_encode(b: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(b.len());
+    for &c in b {
+        if c == b'+' || c == b'=' || c <= b' ' || c > b'~' {
+            res.extend_from_slice(format!("+{:02X}", c).as_bytes());
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
+
+fn xtext_decode(b: ByteSlice) -> Result<SmtpString, ()> {
+    let mut res = Vec::with_capacity(b.len());
+    let mut bytes = b.iter();
+    while let Some(&c) = bytes.next() {
+        if c != b'+' {
+            res.push(c);
+            continue;
+        }
+        let high = hex_digit(*bytes.next().ok_or(())?)?;
+        let low = hex_digit(*bytes.next().ok_or(())?)?;
+        res.push(high << 4 | low);
+    }
+    Ok(SmtpString::from(res))
+}
+
+// RFC 3461 only allows uppercase hexadecimal digits after a `+`
+fn hex_digit(c: u8) -> Result<u8, ()> {
+    match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'A'..=b'F' => Ok(c - b'A' + 10),
+        _ => Err(()),
+    }
+}
+
+named!(pub parse_attributes(ByteSlice) -> Attributes, do_parse!(
+    attrs: many1!(
+        do_parse!(
+            one_of!(spaces!()) >> eat_spaces >>
+            key: is_a!(alpha!()) >>
+            tag!("=") >>
+            value: map_res!(is_a!(graph_except_equ!()), xtext_decode) >>
+            (key.promote().into(), value)
+        )
+    ) >>
+    (Attributes(attrs))
+));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn valid_attributes() {
+        let tests: &[(&[u8], &[(&[u8], &[u8])])] = &[
+            (
+                b" NAME=foo.example.org\r\n",
+                &[(b"NAME", b"foo.example.org")],
+            ),
+            (
+                b" addr=192.0.2.1\tPORT=25 HELO=+5Bfoo+5D+2B\r\n",
+                &[
+                    (b"addr", b"192.0.2.1"),
+                    (b"PORT", b"25"),
+                    (b"HELO", b"[foo]+"),
+                ],
+            ),
+            (b" NAME=[UNAVAILABLE]\r\n", &[(b"NAME", b"[UNAVAILABLE]")]),
+        ];
+        for (inp, out) in tests {
+            let b = Bytes::from(*inp);
+            let (rem, res) = parse_attributes(ByteSlice::from(&b)).unwrap();
+            assert_eq!(&rem[..], b"\r\n");
+            let reference = out
+                .iter()
+                .map(|(k, v)| ((*k).into(), (*v).into()))
+                .collect::<Vec<_>>();
+            assert_eq!(res.0, reference);
+        }
+    }
+
+    #[test]
+    fn invalid_attributes() {
+        let tests: &[&[u8]] = &[
+            b" NAME\r\n",
+            b" NAME=+4\r\n",
+            b" NAME=+ZZ\r\n",
+            b" NAME=++4\r\n",
+            b" NAME=+-4\r\n",
+            b" NAME=+2b\r\n",
+            b" NA-ME=foo\r\n",
+        ];
+        for inp in tests {
+            let b = Bytes::from(*inp);
+            assert!(parse_attributes(ByteSlice::from(&b)).is_err());
+        }
+    }
+
+    #[test]
+    fn xtext_roundtrip() {
+        let raw: &[u8] = b"a b=c+d\xff";
+        let encoded = xtext_encode(raw);
+        assert_eq!(encoded, b"a+20b+3Dc+2Bd+FF");
+        let b = Bytes::from(encoded);
+        assert_eq!(
+            xtext_decode(ByteSlice::from(&b)).unwrap(),
+            SmtpString::from(raw)
+        );
+    }
+}
@@ -0,0 +1,633 @@
+use smtp_message::{Email, ReplyCode, SmtpString};
+use std::{collections::HashMap, io, net::IpAddr, path::Path, rc::Rc, str};
+use tokio::prelude::*;
+
+use chain::{run_layers, Flow, Layer};
+use decision::{is_reply_text, Decision, Refusal};
+use mapfile::MapFile;
+use metadata::{ConnectionMetadata, MailMetadata};
+
+// What an access map says about a client, HELO name, sender or recipient
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AccessAction {
+    // Lets the event through, without asking the next layers nor the handler
+    Ok,
+    // Refuses, permanently or temporarily, with this text in place of the
+    // default one
+    Reject(Option<SmtpString>),
+    Defer(Option<SmtpString>),
+    // Stops looking in the map, as if nothing was found (eg. for exceptions to
+    // the entry of a parent domain)
+    Dunno,
+    // Runs the layers of the restriction class with this name
+    Class(String),
+}
+
+#[derive(Default)]
+struct Table {
+    entries:  HashMap<String, AccessAction>,
+    // Matched in the order of the file, the first one matching deciding
+    networks: Vec<(IpAddr, u8, AccessAction)>,
+}
+
+// Postfix-style access map, read from a text file with one entry per line:
+//   <key> OK | REJECT [text] | DEFER [text] | DUNNO | <restriction class>
+// where keys are addresses, domains, `localpart@`, `<>` for the null sender,
+// or client addresses and networks in CIDR notation. Empty lines and those
+// starting with `#` are ignored, and keys are case-insensitive. Texts must be
+// printable ASCII, and may start with an enhanced status code (eg. `5.7.26`).
+//
+// The file is read again when it changed, on the next lookup. Clones share
+// the same table.
+#[derive(Clone)]
+pub struct AccessMap(MapFile<Table>);
+
+impl AccessMap {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<AccessMap> {
+        MapFile::open(path, Table::parse).map(AccessMap)
+    }
+
+    fn get(&self, key: &str) -> Option<AccessAction> {
+        self.0.table().entries.get(key).cloned()
+    }
+
+    // Looks up the networks containing `ip`
+    pub fn lookup_ip(&self, ip: IpAddr) -> Option<AccessAction> {
+        let table = self.0.table();
+        table
+            .networks
+            .iter()
+            .find(|(net, prefix, _)| contains(*net, *prefix, ip))
+            .map(|(_, _, action)| action.clone())
+    }
+
+    // Looks up `domain`, then its parent domains
+    pub fn lookup_domain(&self, domain: &str) -> Option<AccessAction> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let mut name = &domain[..];
+        loop {
+            if let Some(action) = self.get(name) {
+                return Some(action);
+            }
+            match name.find('.') {
+                Some(dot) => name = &name[dot + 1..],
+                None => return None,
+            }
+        }
+    }
+
+    // Looks up the whole address, then its domain and parent domains, then
+    // `localpart@`. The null sender is looked up as `<>`.
+    pub fn lookup_email(&self, email: Option<&Email>) -> Option<AccessAction> {
+        let email = match email {
+            Some(email) => email,
+            None => return self.get("<>"),
+        };
+        let localpart = email.localpart();
+        let localpart = String::from_utf8_lossy(&localpart.bytes()[..]).to_ascii_lowercase();
+        let domain = email
+            .hostname()
+            .as_ref()
+            .and_then(|d| SmtpString::from_sendable(d).ok())
+            .map(|d| String::from_utf8_lossy(&d.bytes()[..]).to_ascii_lowercase());
+        let domain = match domain {
+            Some(domain) => domain,
+            None => {
+                return self
+                    .get(&localpart)
+                    .or_else(|| self.get(&format!("{}@", localpart)))
+            }
+        };
+        self.get(&format!("{}@{}", localpart, domain))
+            .or_else(|| self.lookup_domain(&domain))
+            .or_else(|| self.get(&format!("{}@", localpart)))
+    }
+}
+
+impl Table {
+    fn parse(content: &[u8]) -> io::Result<Table> {
+        let content = str::from_utf8(content)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "access map is not UTF-8"))?;
+        let mut table = Table::default();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                let msg = format!("invalid access map entry on line {}", i + 1);
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            };
+            let (key, rest) = split_word(line);
+            let (action, text) = split_word(rest);
+            let text = match text {
+                "" => None,
+                text if is_reply_text(text.as_bytes()) => Some(SmtpString::from(text)),
+                _ => return Err(invalid()),
+            };
+            let action = match (&action.to_ascii_uppercase()[..], text) {
+                ("", _) => return Err(invalid()),
+                ("OK", None) => AccessAction::Ok,
+                ("REJECT", text) => AccessAction::Reject(text),
+                ("DEFER", text) => AccessAction::Defer(text),
+                ("DUNNO", None) => AccessAction::Dunno,
+                (_, None) => AccessAction::Class(action.to_owned()),
+                (_, Some(_)) => return Err(invalid()),
+            };
+            match parse_network(key) {
+                Some(Ok((net, prefix))) => table.networks.push((net, prefix, action)),
+                Some(Err(())) => return Err(invalid()),
+                None => {
+                    table.entries.insert(key.to_ascii_lowercase(), action);
+                }
+            }
+        }
+        Ok(table)
+    }
+}
+
+// Whether `text` starts with an enhanced status code (see RFC 3463), like
+// `5.7.1`, followed by a space or nothing
+fn starts_with_status(text: &[u8]) -> bool {
+    let mut parts = text.splitn(4, |&c| c == b'.');
+    let class = parts.next().unwrap_or(b"");
+    let subject = parts.next().unwrap_or(b"");
+    let detail = match parts.next() {
+        Some(rest) if parts.next().is_none() => rest.split(|&c| c == b' ').next().unwrap(),
+        _ => return false,
+    };
+    let digits = |p: &[u8]| !p.is_empty() && p.len() <= 3 && p.iter().all(u8::is_ascii_digit);
+    (class == b"2" || class == b"4" || class == b"5") && digits(subject) && digits(detail)
+}
+
+fn split_word(s: &str) -> (&str, &str) {
+    match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], s[i..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+// Addresses and networks in CIDR notation, or `None` for other keys
+fn parse_network(key: &str) -> Option<Result<(IpAddr, u8), ()>> {
+    let (ip, prefix) = match key.find('/') {
+        Some(i) => (&key[..i], Some(&key[i + 1..])),
+        None => (key, None),
+    };
+    let ip = match ip.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) if prefix.is_some() => return Some(Err(())),
+        Err(_) => return None,
+    };
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    Some(match prefix.map(|p| p.parse::<u8>()) {
+        None => Ok((ip, max)),
+        Some(Ok(prefix)) if prefix <= max => Ok((ip, prefix)),
+        Some(_) => Err(()),
+    })
+}
+
+// Whether `ip` is in the network `net`/`prefix`, whose prefix must be at most
+// the length of its address
+pub fn contains(net: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    match (net, ip) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            let mask = (!0u32).checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(net) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(ip)) => {
+            let mask = (!0u128).checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(net) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+// What a policy does for an action
+enum Outcome<U: 'static> {
+    Flow(Flow),
+    Class(Vec<Rc<Layer<U>>>),
+}
+
+// Access table policy, checking the client against its map at connection
+// time (by its confirmed name, then by its address), the HELO name and the
+// sender against theirs on MAIL FROM, and each recipient against its map.
+//
+// The first action found, other than `DUNNO`, decides. A restriction class
+// runs its layers, that were given with `class`, as a `Chain` would: the
+// event goes on to the next layers if none of them stops it.
+pub struct Access<U: 'static> {
+    client:    Option<AccessMap>,
+    helo:      Option<AccessMap>,
+    sender:    Option<AccessMap>,
+    recipient: Option<AccessMap>,
+    classes:   HashMap<String, Vec<Rc<Layer<U>>>>,
+}
+
+impl<U: 'static> Access<U> {
+    pub fn new() -> Access<U> {
+        Access {
+            client:    None,
+            helo:      None,
+            sender:    None,
+            recipient: None,
+            classes:   HashMap::new(),
+        }
+    }
+
+    pub fn client(mut self, map: AccessMap) -> Access<U> {
+        self.client = Some(map);
+        self
+    }
+
+    pub fn helo(mut self, map: AccessMap) -> Access<U> {
+        self.helo = Some(map);
+        self
+    }
+
+    pub fn sender(mut self, map: AccessMap) -> Access<U> {
+        self.sender = Some(map);
+        self
+    }
+
+    pub fn recipient(mut self, map: AccessMap) -> Access<U> {
+        self.recipient = Some(map);
+        self
+    }
+
+    // Adds a layer to the restriction class `name`
+    pub fn class<L: Layer<U>>(mut self, name: &str, layer: L) -> Access<U> {
+        let layers = self.classes.entry(name.to_owned()).or_insert_with(Vec::new);
+        layers.push(Rc::new(layer));
+        self
+    }
+
+    fn outcome(
+        &self,
+        action: Option<AccessAction>,
+        code: ReplyCode,
+        defer: ReplyCode,
+    ) -> Outcome<U> {
+        let refuse = |code: ReplyCode, text: Option<SmtpString>, default: &str, status: &str| {
+            let text = match text {
+                Some(text) => text.bytes().to_vec(),
+                None => default.as_bytes().to_vec(),
+            };
+            // As with Postfix, a status code in the text replaces the default
+            // one, its class being made to match the reply code
+            let msg = if starts_with_status(&text) {
+                let mut msg = text;
+                msg[0] = b'0' + (code.code() / 100) as u8;
+                msg
+            } else {
+                let mut msg = status.as_bytes().to_vec();
+                msg.extend_from_slice(&text);
+                msg
+            };
+            let msg = SmtpString::from(msg);
+            Outcome::Flow(Flow::Stop(Decision::Reject(Refusal { code, msg })))
+        };
+        match action {
+            None | Some(AccessAction::Dunno) => Outcome::Flow(Flow::Next),
+            Some(AccessAction::Ok) => Outcome::Flow(Flow::Stop(Decision::Accept)),
+            Some(AccessAction::Reject(text)) => refuse(code, text, "Access denied", "5.7.1 "),
+            Some(AccessAction::Defer(text)) => refuse(defer, text, "Try again later", "4.7.1 "),
+            Some(AccessAction::Class(name)) => match self.classes.get(&name) {
+                Some(layers) => Outcome::Class(layers.clone()),
+                None => refuse(
+                    ReplyCode::LOCAL_ERROR,
+                    None,
+                    "Server configuration error",
+                    "4.3.5 ",
+                ),
+            },
+        }
+    }
+}
+
+impl<U: 'static> Default for Access<U> {
+    fn default() -> Access<U> {
+        Access::new()
+    }
+}
+
+impl<U: 'static> Clone for Access<U> {
+    fn clone(&self) -> Access<U> {
+        Access {
+            client:    self.client.clone(),
+            helo:      self.helo.clone(),
+            sender:    self.sender.clone(),
+            recipient: self.recipient.clone(),
+            classes:   self.classes.clone(),
+        }
+    }
+}
+
+// Looks up in the first map that has something to say
+fn first_action<I>(lookups: I) -> Option<AccessAction>
+where
+    I: IntoIterator<Item = Option<AccessAction>>,
+{
+    lookups
+        .into_iter()
+        .find(|a| a.is_some() && *a != Some(AccessAction::Dunno))
+        .unwrap_or(None)
+}
+
+impl<U: 'static> Layer<U> for Access<U> {
+    fn on_connect(
+        &self,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (ConnectionMetadata<U>, Flow), Error = ()>> {
+        let action = self.client.as_ref().and_then(|map| {
+            let name = conn_meta
+                .peer_name
+                .as_ref()
+                .and_then(|n| str::from_utf8(&n.bytes()[..]).ok());
+            first_action(vec![
+                name.and_then(|n| map.lookup_domain(n)),
+                conn_meta.peer_addr.and_then(|a| map.lookup_ip(a.ip())),
+            ])
+        });
+        // RFC 5321 § 3.1 calls for a 554 in place of the banner
+        let outcome = self.outcome(
+            action,
+            ReplyCode::TRANSACTION_FAILED,
+            ReplyCode::SERVICE_NOT_AVAILABLE,
+        );
+        match outcome {
+            Outcome::Flow(flow) => Box::new(future::ok((conn_meta, flow))),
+            Outcome::Class(layers) => Box::new(run_layers(layers, conn_meta, |l, conn_meta| {
+                l.on_connect(conn_meta)
+            })),
+        }
+    }
+
+    fn filter_from(
+        &self,
+        from: Option<Email>,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Option<Email>, ConnectionMetadata<U>, Flow), Error = ()>> {
+        let action = {
+            let helo = conn_meta
+                .helo
+                .as_ref()
+                .and_then(|h| str::from_utf8(&h.bytes()[..]).ok());
+            first_action(vec![
+                self.helo
+                    .as_ref()
+                    .and_then(|map| helo.and_then(|h| map.lookup_domain(h))),
+                self.sender
+                    .as_ref()
+                    .and_then(|map| map.lookup_email(from.as_ref())),
+            ])
+        };
+        let outcome = self.outcome(
+            action,
+            ReplyCode::POLICY_REASON,
+            ReplyCode::MAILBOX_TEMPORARILY_UNAVAILABLE,
+        );
+        match outcome {
+            Outcome::Flow(flow) => Box::new(future::ok((from, conn_meta, flow))),
+            Outcome::Class(layers) => Box::new(
+                run_layers(layers, (from, conn_meta), |l, (from, conn_meta)| {
+                    Box::new(
+                        l.filter_from(from, conn_meta)
+                            .map(|(from, conn_meta, flow)| ((from, conn_meta), flow)),
+                    )
+                })
+                .map(|((from, conn_meta), flow)| (from, conn_meta, flow)),
+            ),
+        }
+    }
+
+    fn filter_to(
+        &self,
+        to: Email,
+        meta: MailMetadata,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Email, MailMetadata, ConnectionMetadata<U>, Flow), Error = ()>> {
+        let action = first_action(vec![self
+            .recipient
+            .as_ref()
+            .and_then(|map| map.lookup_email(Some(&to)))]);
+        let outcome = self.outcome(
+            action,
+            ReplyCode::POLICY_REASON,
+            ReplyCode::MAILBOX_TEMPORARILY_UNAVAILABLE,
+        );
+        match outcome {
+            Outcome::Flow(flow) => Box::new(future::ok((to, meta, conn_meta, flow))),
+            Outcome::Class(layers) => Box::new(
+                run_layers(layers, (to, meta, conn_meta), |l, (to, meta, conn_meta)| {
+                    Box::new(
+                        l.filter_to(to, meta, conn_meta)
+                            .map(|(to, meta, conn_meta, flow)| ((to, meta, conn_meta), flow)),
+                    )
+                })
+                .map(|((to, meta, conn_meta), flow)| (to, meta, conn_meta, flow)),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tokio::runtime::current_thread::Runtime;
+
+    use testutil::{email, map, refusal, TempFile};
+
+    fn open(path: &Path) -> io::Result<AccessMap> {
+        AccessMap::open(path)
+    }
+
+    #[test]
+    fn looks_up_keys() {
+        let (_file, m) = map(
+            "access-keys",
+            "# Comment
+             192.0.2.0/24     REJECT Go away
+             192.0.0.0/8      OK
+             2001:db8::1      DEFER
+
+             Example.com      REJECT
+             ok.example.com   DUNNO
+             john@example.org OK
+             example.org      strict
+             postmaster@      OK
+             <>               DEFER",
+            open,
+        );
+        let ip = |ip: &str| m.lookup_ip(ip.parse().unwrap());
+        let reject = |text: Option<&str>| Some(AccessAction::Reject(text.map(SmtpString::from)));
+        assert_eq!(ip("192.0.2.7"), reject(Some("Go away")));
+        assert_eq!(ip("192.0.3.7"), Some(AccessAction::Ok));
+        assert_eq!(ip("2001:db8::1"), Some(AccessAction::Defer(None)));
+        assert_eq!(ip("2001:db8::2"), None);
+        assert_eq!(ip("198.51.100.1"), None);
+
+        assert_eq!(m.lookup_domain("mx.EXAMPLE.com."), reject(None));
+        assert_eq!(m.lookup_domain("ok.example.com"), Some(AccessAction::Dunno));
+        assert_eq!(m.lookup_domain("example.net"), None);
+
+        let addr = |a: &str| m.lookup_email(Some(&email(a)));
+        let strict = Some(AccessAction::Class("strict".to_owned()));
+        assert_eq!(addr("John@example.org"), Some(AccessAction::Ok));
+        assert_eq!(addr("jane@mail.example.org"), strict);
+        assert_eq!(addr("postmaster@example.org"), strict);
+        assert_eq!(addr("postmaster@example.net"), Some(AccessAction::Ok));
+        assert_eq!(addr("jane@example.net"), None);
+        assert_eq!(m.lookup_email(None), Some(AccessAction::Defer(None)));
+
+        let file = TempFile::new("access-bad");
+        for bad in &[
+            "example.com\n",
+            "192.0.2.0/33 OK\n",
+            "example.com strict now\n",
+            "example.com REJECT Acc\u{e8}s refus\u{e9}\n",
+            "example.com DEFER Try\u{7}later\n",
+        ] {
+            fs::write(file.path(), bad).unwrap();
+            assert!(AccessMap::open(file.path()).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn reloads_changed_files() {
+        let (file, m) = map("access-reload", "example.com REJECT\n", open);
+        assert!(m.lookup_domain("example.com").is_some());
+        fs::write(file.path(), "example.net REJECT Changed\n").unwrap();
+        assert!(m.lookup_domain("example.com").is_none());
+        assert!(m.lookup_domain("example.net").is_some());
+        // Even with the same size, and likely the same modification time
+        fs::write(file.path(), "example.net DEFER  Changed\n").unwrap();
+        assert_eq!(
+            m.lookup_domain("example.net"),
+            Some(AccessAction::Defer(Some("Changed".into())))
+        );
+
+        // The table last read stays when the file is gone
+        fs::remove_file(file.path()).unwrap();
+        assert!(m.lookup_domain("example.net").is_some());
+    }
+
+    struct Strict;
+
+    impl Layer<()> for Strict {
+        fn filter_to(
+            &self,
+            to: Email,
+            meta: MailMetadata,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<Future<Item = (Email, MailMetadata, ConnectionMetadata<()>, Flow), Error = ()>>
+        {
+            let msg = "5.7.1 Strict".into();
+            let r = Refusal {
+                code: ReplyCode::POLICY_REASON,
+                msg,
+            };
+            Box::new(future::ok((
+                to,
+                meta,
+                conn_meta,
+                Flow::Stop(Decision::Reject(r)),
+            )))
+        }
+    }
+
+    #[test]
+    fn applies_actions() {
+        let mut rt = Runtime::new().unwrap();
+        let (_client, client) = map(
+            "access-client",
+            "192.0.2.0/24 REJECT\nbad.example.com DEFER\n",
+            open,
+        );
+        let (_helo, helo) = map(
+            "access-helo",
+            "mx.example.net REJECT You are not me\n",
+            open,
+        );
+        let (_sender, sender) = map("access-sender", "friend@example.org OK\n", open);
+        let (_recipient, recipient) = map(
+            "access-recipient",
+            "abuse@            OK
+             example.org       strict
+             example.net       unknown
+             full@example.com  DEFER 5.2.2 Mailbox full
+             spoof@example.com REJECT 5.7.26 Unauthenticated mail
+             old@example.com   REJECT 5.1 is gone",
+            open,
+        );
+        let access = Access::new()
+            .client(client)
+            .helo(helo)
+            .sender(sender)
+            .recipient(recipient)
+            .class("strict", Strict);
+
+        let connect = |rt: &mut Runtime, ip: &str, name: Option<&str>| {
+            let mut conn_meta = ConnectionMetadata::new(());
+            conn_meta.peer_addr = Some(format!("{}:25", ip).parse().unwrap());
+            conn_meta.peer_name = name.map(SmtpString::from);
+            let (conn_meta, flow) = rt.block_on(access.on_connect(conn_meta)).unwrap();
+            (conn_meta, refusal(&flow))
+        };
+        let (_, refused) = connect(&mut rt, "192.0.2.1", None);
+        assert_eq!(refused, Some((554, "5.7.1 Access denied".to_owned())));
+        let (_, refused) = connect(&mut rt, "198.51.100.1", Some("bad.example.com"));
+        assert_eq!(refused, Some((421, "4.7.1 Try again later".to_owned())));
+        let (mut conn_meta, refused) = connect(&mut rt, "198.51.100.1", None);
+        assert_eq!(refused, None);
+
+        conn_meta.helo = Some("mx.example.net".into());
+        let from = Some(email("friend@example.org"));
+        let (from, mut conn_meta, flow) = rt.block_on(access.filter_from(from, conn_meta)).unwrap();
+        assert_eq!(
+            refusal(&flow),
+            Some((550, "5.7.1 You are not me".to_owned()))
+        );
+        conn_meta.helo = Some("client.example.net".into());
+        let (_, conn_meta, flow) = rt.block_on(access.filter_from(from, conn_meta)).unwrap();
+        assert_eq!(refusal(&flow), Some((250, String::new())));
+
+        let mut conn_meta = conn_meta;
+        let mut rcpt = |to: &str| {
+            let meta = MailMetadata {
+                from: None,
+                to:   Vec::new(),
+            };
+            let c = std::mem::replace(&mut conn_meta, ConnectionMetadata::new(()));
+            let (_, _, c, flow) = rt.block_on(access.filter_to(email(to), meta, c)).unwrap();
+            conn_meta = c;
+            refusal(&flow)
+        };
+        assert_eq!(rcpt("abuse@example.com"), Some((250, String::new())));
+        assert_eq!(
+            rcpt("john@example.org"),
+            Some((550, "5.7.1 Strict".to_owned()))
+        );
+        assert_eq!(
+            rcpt("john@example.net"),
+            Some((451, "4.3.5 Server configuration error".to_owned()))
+        );
+        assert_eq!(rcpt("john@example.com"), None);
+        assert_eq!(
+            rcpt("full@example.com"),
+            Some((450, "4.2.2 Mailbox full".to_owned()))
+        );
+        assert_eq!(
+            rcpt("spoof@example.com"),
+            Some((550, "5.7.26 Unauthenticated mail".to_owned()))
+        );
+        assert_eq!(
+            rcpt("old@example.com"),
+            Some((550, "5.7.1 5.1 is gone".to_owned()))
+        );
+        assert_eq!(
+            rcpt("abuse@example.org"),
+            Some((550, "5.7.1 Strict".to_owned()))
+        );
+    }
+}
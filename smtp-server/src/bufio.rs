@@ -0,0 +1,707 @@
+use bytes::{BufMut, BytesMut};
+use smtp_message::{ReplyCode, ReplyLine, SmtpString};
+use std::{cell::RefCell, io, rc::Rc};
+use tokio::prelude::*;
+
+use config::Config;
+use interact::session;
+use metadata::ConnectionMetadata;
+use sendreply::send_reply;
+use shutdown::Shutdown;
+
+// Minimum amount of free space in the read buffer before each read
+const MIN_READ_SPACE: usize = 512;
+// Amount of memory reserved whenever the read buffer runs out of space
+const READ_BUFFER_SIZE: usize = 8192;
+
+// The session itself only deals with `()` errors, so the I/O adapters stash
+// their error here for `interact_io` to hand it back to the caller.
+#[derive(Clone)]
+struct ErrorSlot(Rc<RefCell<Option<io::Error>>>);
+
+impl ErrorSlot {
+    fn new() -> ErrorSlot {
+        ErrorSlot(Rc::new(RefCell::new(None)))
+    }
+
+    fn set(&self, e: io::Error) {
+        let mut slot = self.0.borrow_mut();
+        if slot.is_none() {
+            *slot = Some(e);
+        }
+    }
+
+    fn take(&self) -> Option<io::Error> {
+        self.0.borrow_mut().take()
+    }
+}
+
+// Stream of the bytes read from `io`, using a single read buffer.
+//
+// Each read is appended to the buffer and split out of it, so that once the
+// consumer has dropped the yielded chunk the memory gets reused for the next
+// reads.
+struct BufferedReader<R: AsyncRead> {
+    io:     R,
+    buf:    BytesMut,
+    errors: ErrorSlot,
+}
+
+impl<R: AsyncRead> Stream for BufferedReader<R> {
+    type Item = BytesMut;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<BytesMut>, ()> {
+        if self.buf.remaining_mut() < MIN_READ_SPACE {
+            self.buf.reserve(READ_BUFFER_SIZE);
+        }
+        match self.io.read_buf(&mut self.buf) {
+            Ok(Async::Ready(0)) => Ok(Async::Ready(None)),
+            Ok(Async::Ready(_)) => Ok(Async::Ready(Some(self.buf.take()))),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => {
+                self.errors.set(e);
+                Err(())
+            }
+        }
+    }
+}
+
+// Sink serializing reply lines into a single write buffer.
+//
+// Nothing is written to `io` before `poll_complete` is called, which means all
+// the replies queued in-between are sent in as few writes as possible.
+struct BufferedWriter<W: AsyncWrite> {
+    io:     W,
+    buf:    BytesMut,
+    errors: ErrorSlot,
+}
+
+impl<W: AsyncWrite> BufferedWriter<W> {
+    fn fail(&self, e: io::Error) {
+        self.errors.set(e);
+    }
+}
+
+impl<W: AsyncWrite> Sink for BufferedWriter<W> {
+    type SinkItem = ReplyLine;
+    type SinkError = ();
+
+    fn start_send(&mut self, line: ReplyLine) -> StartSend<ReplyLine, ()> {
+        self.buf.reserve(line.byte_len());
+        // By design of BytesMut::writer, this cannot fail as we just reserved
+        // enough capacity
+        line.send_to(&mut (&mut self.buf).writer()).unwrap();
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), ()> {
+        while !self.buf.is_empty() {
+            match self.io.poll_write(&self.buf) {
+                Ok(Async::Ready(0)) => {
+                    self.fail(io::ErrorKind::WriteZero.into());
+                    return Err(());
+                }
+                Ok(Async::Ready(n)) => self.buf.advance(n),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    self.fail(e);
+                    return Err(());
+                }
+            }
+        }
+        self.io.poll_flush().map_err(|e| self.fail(e))
+    }
+}
+
+// Runs an SMTP session directly over a connection (`TcpStream`,
+// `UnixStream`, TLS stream, etc.).
+//
+// Contrary to `interact`, the replies are serialized into one reusable write
+// buffer, the replies to pipelined commands are sent in a single write, and
+// the I/O errors are returned to the caller.
+pub fn interact_io<'a, IO, U, Cfg>(
+    io: IO,
+    metadata: U,
+    cfg: Cfg,
+) -> impl Future<Item = (), Error = io::Error> + 'a
+where
+    IO: 'a + AsyncRead + AsyncWrite,
+    U: 'static,
+    Cfg: Config<U>,
+{
+    session_io(io, ConnectionMetadata::new(metadata), cfg, None)
+}
+
+// Same as `interact_io`, for callers that know more about the connection
+pub fn session_io<'a, IO, U, Cfg>(
+    io: IO,
+    conn_meta: ConnectionMetadata<U>,
+    cfg: Cfg,
+    shutdown: Option<Shutdown>,
+) -> impl Future<Item = (), Error = io::Error> + 'a
+where
+    IO: 'a + AsyncRead + AsyncWrite,
+    U: 'static,
+    Cfg: Config<U>,
+{
+    let errors = ErrorSlot::new();
+    let (read, write) = io.split();
+    let reader = BufferedReader {
+        io:     read,
+        buf:    BytesMut::with_capacity(READ_BUFFER_SIZE),
+        errors: errors.clone(),
+    };
+    let writer = BufferedWriter {
+        io:     write,
+        buf:    BytesMut::new(),
+        errors: errors.clone(),
+    };
+    session(reader, writer, conn_meta, cfg, shutdown).map_err(move |()| {
+        errors
+            .take()
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, "SMTP session aborted"))
+    })
+}
+
+// Sends `reply` in place of a session, and drops the connection
+pub fn refuse_io<'a, IO>(
+    io: IO,
+    reply: (ReplyCode, SmtpString),
+) -> impl Future<Item = (), Error = io::Error> + 'a
+where
+    IO: 'a + AsyncWrite,
+{
+    let errors = ErrorSlot::new();
+    let writer = BufferedWriter {
+        io,
+        buf: BytesMut::new(),
+        errors: errors.clone(),
+    };
+    send_reply(writer, reply)
+        .and_then(|writer| writer.flush())
+        .map(|_writer| ())
+        .map_err(move |()| {
+            errors
+                .take()
+                .unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, "could not send reply"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smtp_message::{DataStream, Email, Prependable};
+    use std::{
+        cell::Cell,
+        cmp,
+        collections::VecDeque,
+        time::{Duration, Instant},
+    };
+    use tokio::runtime::current_thread::Runtime;
+
+    use decision::{Decision, Refusal};
+    use disconnect::DisconnectReason;
+    use metadata::MailMetadata;
+
+    // Records whether the client had been caught pipelining illegally or
+    // talking early by the time it sent MAIL FROM
+    struct TestConfig {
+        illegal_pipelining: Rc<Cell<bool>>,
+        early_talker: Rc<Cell<bool>>,
+        // Whether to wait before the end of the banner, and to refuse the
+        // clients that do not
+        pregreet: bool,
+        reject_early_talkers: bool,
+        // Soft and hard limits
+        error_limits: Option<(usize, usize)>,
+        command_limits: Option<(usize, usize)>,
+        refuse_connections: bool,
+        // Why the session ended, and whether a mail was left unfinished
+        disconnected: Rc<Cell<Option<(DisconnectReason, bool)>>>,
+    }
+
+    fn cfg() -> TestConfig {
+        TestConfig {
+            illegal_pipelining: Rc::new(Cell::new(false)),
+            early_talker: Rc::new(Cell::new(false)),
+            pregreet: false,
+            reject_early_talkers: false,
+            error_limits: None,
+            command_limits: None,
+            refuse_connections: false,
+            disconnected: Rc::new(Cell::new(None)),
+        }
+    }
+
+    fn run<F: Future>(f: F) -> Result<F::Item, F::Error> {
+        Runtime::new().unwrap().block_on(f)
+    }
+
+    impl Config<()> for TestConfig {
+        fn hostname(&self) -> SmtpString {
+            SmtpString::from_static(b"test.example.org")
+        }
+
+        fn on_connect(
+            self,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<Future<Item = (Self, ConnectionMetadata<()>, Decision), Error = ()>> {
+            let decision = if self.refuse_connections {
+                Decision::RejectAndClose(Refusal {
+                    code: ReplyCode::TRANSACTION_FAILED,
+                    msg:  "Go away".into(),
+                })
+            } else {
+                Decision::Accept
+            };
+            Box::new(future::ok((self, conn_meta, decision)))
+        }
+
+        fn on_disconnect(
+            self,
+            reason: DisconnectReason,
+            mail: Option<MailMetadata>,
+            _conn_meta: ConnectionMetadata<()>,
+        ) -> Box<Future<Item = (), Error = ()>> {
+            self.disconnected.set(Some((reason, mail.is_some())));
+            Box::new(future::ok(()))
+        }
+
+        fn pregreet_delay(&self) -> Option<Duration> {
+            if self.pregreet {
+                Some(Duration::from_millis(10))
+            } else {
+                None
+            }
+        }
+
+        fn reject_early_talkers(&self) -> bool {
+            self.reject_early_talkers
+        }
+
+        fn soft_error_limit(&self) -> Option<usize> {
+            self.error_limits.map(|l| l.0)
+        }
+
+        fn hard_error_limit(&self) -> Option<usize> {
+            self.error_limits.map(|l| l.1)
+        }
+
+        fn soft_command_limit(&self) -> Option<usize> {
+            self.command_limits.map(|l| l.0)
+        }
+
+        fn hard_command_limit(&self) -> Option<usize> {
+            self.command_limits.map(|l| l.1)
+        }
+
+        fn error_delay(&self) -> Duration {
+            Duration::from_millis(20)
+        }
+
+        fn filter_from(
+            self,
+            addr: Option<Email>,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<Future<Item = (Self, Option<Email>, ConnectionMetadata<()>, Decision), Error = ()>>
+        {
+            self.illegal_pipelining.set(conn_meta.illegal_pipelining);
+            self.early_talker.set(conn_meta.early_talker);
+            Box::new(future::ok((self, addr, conn_meta, Decision::Accept)))
+        }
+
+        fn filter_to(
+            self,
+            email: Email,
+            meta: MailMetadata,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<
+            Future<
+                Item = (Self, Email, MailMetadata, ConnectionMetadata<()>, Decision),
+                Error = (),
+            >,
+        > {
+            Box::new(future::ok((self, email, meta, conn_meta, Decision::Accept)))
+        }
+
+        fn handle_mail<'a, S: 'a + Stream<Item = BytesMut, Error = ()>>(
+            self,
+            reader: DataStream<S>,
+            _meta: MailMetadata,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<
+            'a
+                + Future<
+                    Item = (
+                        Self,
+                        Option<Prependable<S>>,
+                        ConnectionMetadata<()>,
+                        Decision,
+                    ),
+                    Error = (),
+                >,
+        > {
+            Box::new(
+                reader
+                    .concat_and_recover()
+                    .map_err(|_| ())
+                    .map(move |(_, reader)| {
+                        (self, Some(reader.into_inner()), conn_meta, Decision::Accept)
+                    }),
+            )
+        }
+    }
+
+    // Connection that receives `input` chunk by chunk, and records each write
+    // separately. An empty chunk stands for the client waiting for the replies
+    // before sending more.
+    struct MockConn {
+        input:  VecDeque<&'static [u8]>,
+        writes: Vec<Vec<u8>>,
+        fail:   bool,
+    }
+
+    impl io::Read for MockConn {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let chunk = match self.input.pop_front() {
+                None => return Ok(0),
+                Some(chunk) => chunk,
+            };
+            if chunk.is_empty() {
+                // Make sure the reading task gets polled again
+                task::current().notify();
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = cmp::min(buf.len(), chunk.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            if n < chunk.len() {
+                self.input.push_front(&chunk[n..]);
+            }
+            Ok(n)
+        }
+    }
+
+    impl io::Write for MockConn {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.fail {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "test failure"));
+            }
+            self.writes.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for MockConn {}
+
+    impl AsyncWrite for MockConn {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn mock(input: &[&'static [u8]]) -> MockConn {
+        MockConn {
+            input:  input.iter().cloned().collect(),
+            writes: Vec::new(),
+            fail:   false,
+        }
+    }
+
+    #[test]
+    fn coalesces_pipelined_replies() {
+        let mut conn = mock(&[
+            b"",
+            b"MAIL FROM:<foo@example.org>\r\n\
+              RCPT TO:<bar@example.org>\r\n\
+              RCPT TO:<baz@example.org>\r\n",
+        ]);
+        run(interact_io(&mut conn, (), cfg())).unwrap();
+        assert_eq!(
+            conn.writes,
+            vec![
+                b"220 test.example.org Service ready\r\n".to_vec(),
+                b"250 Okay\r\n250 Okay\r\n250 Okay\r\n".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn flushes_before_waiting_for_data() {
+        let mut conn = mock(&[
+            b"",
+            b"MAIL FROM:<foo@example.org>\r\n",
+            b"",
+            b"RCPT TO:<bar@example.org>\r\n",
+            b"",
+            b"DATA\r\n",
+            b"",
+            b"Hello world\r\n.\r\n",
+        ]);
+        run(interact_io(&mut conn, (), cfg())).unwrap();
+        assert_eq!(
+            conn.writes,
+            vec![
+                b"220 test.example.org Service ready\r\n".to_vec(),
+                b"250 Okay\r\n".to_vec(),
+                b"250 Okay\r\n".to_vec(),
+                b"354 Start mail input; end with <CRLF>.<CRLF>\r\n".to_vec(),
+                b"250 Okay\r\n".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn flushes_after_synchronizing_commands() {
+        let mut conn = mock(&[
+            b"",
+            b"EHLO client.example.org\r\n\
+              MAIL FROM:<foo@example.org>\r\n\
+              RCPT TO:<bar@example.org>\r\n\
+              NOOP\r\n\
+              QUIT\r\n",
+        ]);
+        let cfg = cfg();
+        let illegal_pipelining = cfg.illegal_pipelining.clone();
+        run(interact_io(&mut conn, (), cfg)).unwrap();
+        assert_eq!(
+            conn.writes,
+            vec![
+                b"220 test.example.org Service ready\r\n".to_vec(),
+                b"250-test.example.org\r\n250 PIPELINING\r\n".to_vec(),
+                b"250 Okay\r\n250 Okay\r\n250 Okay\r\n".to_vec(),
+                b"221 test.example.org Service closing transmission channel\r\n".to_vec(),
+            ]
+        );
+        // The client did not wait for the reply to EHLO
+        assert!(illegal_pipelining.get());
+    }
+
+    #[test]
+    fn detects_illegal_pipelining() {
+        let tests: &[(&[&'static [u8]], bool)] = &[
+            // Commands sent one at a time after EHLO
+            (
+                &[
+                    b"",
+                    b"EHLO client.example.org\r\n",
+                    b"",
+                    b"MAIL FROM:<foo@example.org>\r\n",
+                ],
+                false,
+            ),
+            // Pipelined commands after EHLO
+            (
+                &[
+                    b"",
+                    b"EHLO client.example.org\r\n",
+                    b"",
+                    b"MAIL FROM:<foo@example.org>\r\nRCPT TO:<bar@example.org>\r\n",
+                ],
+                false,
+            ),
+            // Commands sent before the greeting
+            (
+                &[
+                    b"EHLO client.example.org\r\n",
+                    b"",
+                    b"MAIL FROM:<foo@example.org>\r\n",
+                ],
+                true,
+            ),
+            // Pipelining without having negotiated it
+            (
+                &[
+                    b"",
+                    b"HELO client.example.org\r\n",
+                    b"",
+                    b"MAIL FROM:<foo@example.org>\r\nRCPT TO:<bar@example.org>\r\n",
+                ],
+                true,
+            ),
+        ];
+        for &(input, illegal) in tests {
+            let mut conn = mock(input);
+            let cfg = cfg();
+            let illegal_pipelining = cfg.illegal_pipelining.clone();
+            run(interact_io(&mut conn, (), cfg)).unwrap();
+            assert_eq!(illegal_pipelining.get(), illegal);
+        }
+    }
+
+    #[test]
+    fn detects_early_talkers() {
+        let tests: &[(&[&'static [u8]], bool, bool, &[&[u8]])] = &[
+            // Waiting for the end of the banner
+            (
+                &[b"", b"", b"MAIL FROM:<foo@example.org>\r\n"],
+                true,
+                false,
+                &[
+                    b"220-test.example.org Service ready\r\n",
+                    b"220 test.example.org Service ready\r\n",
+                    b"250 Okay\r\n",
+                ],
+            ),
+            // Talking during the pregreet delay
+            (
+                &[b"", b"MAIL FROM:<foo@example.org>\r\n"],
+                false,
+                true,
+                &[
+                    b"220-test.example.org Service ready\r\n",
+                    b"220 test.example.org Service ready\r\n",
+                    b"250 Okay\r\n",
+                ],
+            ),
+            // Same, with early talkers refused
+            (
+                &[b"", b"MAIL FROM:<foo@example.org>\r\n"],
+                true,
+                false,
+                &[
+                    b"220-test.example.org Service ready\r\n",
+                    b"554 test.example.org Protocol error, talked before the greeting\r\n",
+                ],
+            ),
+        ];
+        for &(input, reject, early, writes) in tests {
+            let mut conn = mock(input);
+            let mut cfg = cfg();
+            cfg.pregreet = true;
+            cfg.reject_early_talkers = reject;
+            let early_talker = cfg.early_talker.clone();
+            run(interact_io(&mut conn, (), cfg)).unwrap();
+            assert_eq!(conn.writes, writes);
+            assert_eq!(early_talker.get(), early);
+        }
+    }
+
+    #[test]
+    fn limits_errors_and_commands() {
+        let input: &[&'static [u8]] = &[
+            b"",
+            b"FOO\r\n",
+            b"",
+            b"NOOP\r\n",
+            b"",
+            b"BAR\r\n",
+            b"",
+            b"BAZ\r\n",
+            b"",
+            b"NOOP\r\n",
+        ];
+        let tests: &[(
+            Option<(usize, usize)>,
+            Option<(usize, usize)>,
+            &[&[u8]],
+            Duration,
+        )] = &[
+            (
+                Some((1, 3)),
+                None,
+                &[
+                    b"220 test.example.org Service ready\r\n",
+                    b"500 Command not recognized\r\n",
+                    b"250 Okay\r\n",
+                    b"500 Command not recognized\r\n",
+                    b"500 Command not recognized\r\n\
+                      421 test.example.org Too many errors, closing connection\r\n",
+                ],
+                // Only the second error is past the soft limit
+                Duration::from_millis(20),
+            ),
+            (
+                None,
+                Some((2, 4)),
+                &[
+                    b"220 test.example.org Service ready\r\n",
+                    b"500 Command not recognized\r\n",
+                    b"250 Okay\r\n",
+                    b"500 Command not recognized\r\n",
+                    b"500 Command not recognized\r\n\
+                      421 test.example.org Too many commands, closing connection\r\n",
+                ],
+                Duration::from_millis(20),
+            ),
+        ];
+        for &(error_limits, command_limits, writes, delay) in tests {
+            let mut conn = mock(input);
+            let mut cfg = cfg();
+            cfg.error_limits = error_limits;
+            cfg.command_limits = command_limits;
+            let start = Instant::now();
+            run(interact_io(&mut conn, (), cfg)).unwrap();
+            assert!(start.elapsed() >= delay);
+            assert_eq!(conn.writes, writes);
+        }
+    }
+
+    #[test]
+    fn returns_io_errors() {
+        let mut conn = mock(&[]);
+        conn.fail = true;
+        let err = run(interact_io(&mut conn, (), cfg())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn reports_connection_lifecycle() {
+        let tests: &[(&[&'static [u8]], bool, &[&[u8]], (DisconnectReason, bool))] = &[
+            (
+                &[b"MAIL FROM:<foo@example.org>\r\n"],
+                true,
+                &[b"554 Go away\r\n"],
+                (DisconnectReason::Policy, false),
+            ),
+            (
+                &[b"", b"MAIL FROM:<foo@example.org>\r\n"],
+                false,
+                &[b"220 test.example.org Service ready\r\n", b"250 Okay\r\n"],
+                (DisconnectReason::Eof, true),
+            ),
+            (
+                &[
+                    b"",
+                    b"MAIL FROM:<foo@example.org>\r\n",
+                    b"",
+                    b"RCPT TO:<bar@example.org>\r\n",
+                    b"",
+                    b"DATA\r\n",
+                    b"",
+                    b"Hello",
+                ],
+                false,
+                &[
+                    b"220 test.example.org Service ready\r\n",
+                    b"250 Okay\r\n",
+                    b"250 Okay\r\n",
+                    b"354 Start mail input; end with <CRLF>.<CRLF>\r\n",
+                ],
+                (DisconnectReason::Eof, true),
+            ),
+            (
+                &[b"", b"QUIT\r\n"],
+                false,
+                &[
+                    b"220 test.example.org Service ready\r\n",
+                    b"221 test.example.org Service closing transmission channel\r\n",
+                ],
+                (DisconnectReason::Quit, false),
+            ),
+        ];
+        for &(input, refuse, writes, disconnected) in tests {
+            let mut conn = mock(input);
+            let mut cfg = cfg();
+            cfg.refuse_connections = refuse;
+            let reported = cfg.disconnected.clone();
+            run(interact_io(&mut conn, (), cfg)).unwrap();
+            assert_eq!(conn.writes, writes);
+            assert_eq!(reported.get(), Some(disconnected));
+        }
+    }
+}
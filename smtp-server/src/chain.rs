@@ -0,0 +1,775 @@
+use bytes::BytesMut;
+use smtp_message::{DataStream, Email, Prependable, ReplyCode, SmtpString};
+use std::{rc::Rc, time::Duration};
+use tokio::prelude::{
+    future::{self, Either, Loop},
+    *,
+};
+
+use config::Config;
+use crlflines::LineEndings;
+use decision::{Decision, Refusal};
+use disconnect::DisconnectReason;
+use metadata::{ConnectionMetadata, MailMetadata};
+
+// What a layer tells its `Chain` to do
+pub enum Flow {
+    // Pass on to the next layer, and eventually to the handler
+    Next,
+    // Stop there, with this decision, without asking the next layers nor the
+    // handler
+    Stop(Decision),
+}
+
+// Policy (eg. a DNSBL, greylisting, or an access table) checking the events of
+// a session before the handler of a `Chain`. What the layers find out, for
+// themselves or for the next ones, goes in `ConnectionMetadata::state` and
+// `ConnectionMetadata::mail_state`.
+pub trait Layer<U: 'static>: 'static {
+    fn on_connect(
+        &self,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (ConnectionMetadata<U>, Flow), Error = ()>> {
+        Box::new(future::ok((conn_meta, Flow::Next)))
+    }
+
+    fn filter_from(
+        &self,
+        from: Option<Email>,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Option<Email>, ConnectionMetadata<U>, Flow), Error = ()>> {
+        Box::new(future::ok((from, conn_meta, Flow::Next)))
+    }
+
+    fn filter_to(
+        &self,
+        to: Email,
+        meta: MailMetadata,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Email, MailMetadata, ConnectionMetadata<U>, Flow), Error = ()>> {
+        Box::new(future::ok((to, meta, conn_meta, Flow::Next)))
+    }
+
+    fn filter_data(
+        &self,
+        meta: MailMetadata,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (MailMetadata, ConnectionMetadata<U>, Flow), Error = ()>> {
+        Box::new(future::ok((meta, conn_meta, Flow::Next)))
+    }
+}
+
+// Stack of layers in front of a handler, that is a `Config` itself. Each event
+// goes through the layers in the order they were added, then to the handler,
+// unless a layer stops it. All the rest of the configuration is the handler's.
+pub struct Chain<U: 'static, H> {
+    layers:  Vec<Rc<Layer<U>>>,
+    handler: H,
+}
+
+impl<U: 'static, H: Config<U>> Chain<U, H> {
+    pub fn new(handler: H) -> Chain<U, H> {
+        Chain {
+            layers: Vec::new(),
+            handler,
+        }
+    }
+
+    pub fn layer<L: Layer<U>>(mut self, layer: L) -> Chain<U, H> {
+        self.layers.push(Rc::new(layer));
+        self
+    }
+}
+
+// As `Server` builds a `Config` for each connection, a chain is meant to be
+// cloned, the layers being shared
+impl<U: 'static, H: Clone> Clone for Chain<U, H> {
+    fn clone(&self) -> Chain<U, H> {
+        Chain {
+            layers:  self.layers.clone(),
+            handler: self.handler.clone(),
+        }
+    }
+}
+
+// Runs `args` through the layers, until one of them stops
+pub fn run_layers<U, T, F>(
+    layers: Vec<Rc<Layer<U>>>,
+    args: T,
+    call: F,
+) -> impl Future<Item = (T, Flow), Error = ()>
+where
+    U: 'static,
+    F: FnMut(&Layer<U>, T) -> Box<Future<Item = (T, Flow), Error = ()>>,
+{
+    future::loop_fn(
+        (layers.into_iter(), args, call),
+        |(mut layers, args, mut call)| match layers.next() {
+            None => Either::A(future::ok(Loop::Break((args, Flow::Next)))),
+            Some(layer) => Either::B(call(&*layer, args).map(move |(args, flow)| match flow {
+                Flow::Next => Loop::Continue((layers, args, call)),
+                flow => Loop::Break((args, flow)),
+            })),
+        },
+    )
+}
+
+impl<U: 'static, H: Config<U>> Config<U> for Chain<U, H> {
+    fn new_mail(self) -> Box<Future<Item = Self, Error = ()>> {
+        let layers = self.layers;
+        Box::new(
+            self.handler
+                .new_mail()
+                .map(|handler| Chain { layers, handler }),
+        )
+    }
+
+    fn filter_from(
+        self,
+        from: Option<Email>,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Self, Option<Email>, ConnectionMetadata<U>, Decision), Error = ()>>
+    {
+        let Chain { layers, handler } = self;
+        let ran = run_layers(layers.clone(), (from, conn_meta), |l, (from, conn_meta)| {
+            Box::new(
+                l.filter_from(from, conn_meta)
+                    .map(|(from, conn_meta, flow)| ((from, conn_meta), flow)),
+            )
+        });
+        Box::new(ran.and_then(|((from, conn_meta), flow)| match flow {
+            Flow::Stop(decision) => Either::A(future::ok((
+                Chain { layers, handler },
+                from,
+                conn_meta,
+                decision,
+            ))),
+            Flow::Next => Either::B(handler.filter_from(from, conn_meta).map(
+                |(handler, from, conn_meta, decision)| {
+                    (Chain { layers, handler }, from, conn_meta, decision)
+                },
+            )),
+        }))
+    }
+
+    fn filter_to(
+        self,
+        to: Email,
+        meta: MailMetadata,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Self, Email, MailMetadata, ConnectionMetadata<U>, Decision), Error = ()>>
+    {
+        let Chain { layers, handler } = self;
+        let ran = run_layers(
+            layers.clone(),
+            (to, meta, conn_meta),
+            |l, (to, meta, conn_meta)| {
+                Box::new(
+                    l.filter_to(to, meta, conn_meta)
+                        .map(|(to, meta, conn_meta, flow)| ((to, meta, conn_meta), flow)),
+                )
+            },
+        );
+        Box::new(ran.and_then(|((to, meta, conn_meta), flow)| match flow {
+            Flow::Stop(decision) => Either::A(future::ok((
+                Chain { layers, handler },
+                to,
+                meta,
+                conn_meta,
+                decision,
+            ))),
+            Flow::Next => Either::B(handler.filter_to(to, meta, conn_meta).map(
+                |(handler, to, meta, conn_meta, decision)| {
+                    (Chain { layers, handler }, to, meta, conn_meta, decision)
+                },
+            )),
+        }))
+    }
+
+    fn filter_data(
+        self,
+        meta: MailMetadata,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Self, MailMetadata, ConnectionMetadata<U>, Decision), Error = ()>> {
+        let Chain { layers, handler } = self;
+        let ran = run_layers(layers.clone(), (meta, conn_meta), |l, (meta, conn_meta)| {
+            Box::new(
+                l.filter_data(meta, conn_meta)
+                    .map(|(meta, conn_meta, flow)| ((meta, conn_meta), flow)),
+            )
+        });
+        Box::new(ran.and_then(|((meta, conn_meta), flow)| match flow {
+            Flow::Stop(decision) => Either::A(future::ok((
+                Chain { layers, handler },
+                meta,
+                conn_meta,
+                decision,
+            ))),
+            Flow::Next => Either::B(handler.filter_data(meta, conn_meta).map(
+                |(handler, meta, conn_meta, decision)| {
+                    (Chain { layers, handler }, meta, conn_meta, decision)
+                },
+            )),
+        }))
+    }
+
+    fn handle_mail<'a, S: 'a + Stream<Item = BytesMut, Error = ()>>(
+        self,
+        stream: DataStream<S>,
+        meta: MailMetadata,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<
+        'a
+            + Future<
+                Item = (
+                    Self,
+                    Option<Prependable<S>>,
+                    ConnectionMetadata<U>,
+                    Decision,
+                ),
+                Error = (),
+            >,
+    > {
+        let layers = self.layers;
+        Box::new(self.handler.handle_mail(stream, meta, conn_meta).map(
+            |(handler, reader, conn_meta, decision)| {
+                (Chain { layers, handler }, reader, conn_meta, decision)
+            },
+        ))
+    }
+
+    fn handle_mail_rcpts<'a, S: 'a + Stream<Item = BytesMut, Error = ()>>(
+        self,
+        stream: DataStream<S>,
+        meta: MailMetadata,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<
+        'a
+            + Future<
+                Item = (
+                    Self,
+                    Option<Prependable<S>>,
+                    ConnectionMetadata<U>,
+                    Vec<Decision>,
+                ),
+                Error = (),
+            >,
+    > {
+        let layers = self.layers;
+        Box::new(self.handler.handle_mail_rcpts(stream, meta, conn_meta).map(
+            |(handler, reader, conn_meta, decisions)| {
+                (Chain { layers, handler }, reader, conn_meta, decisions)
+            },
+        ))
+    }
+
+    fn rcpts_failed(
+        self,
+        failed: Vec<(Email, Refusal)>,
+        meta: MailMetadata,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Self, ConnectionMetadata<U>), Error = ()>> {
+        let layers = self.layers;
+        Box::new(
+            self.handler
+                .rcpts_failed(failed, meta, conn_meta)
+                .map(|(handler, conn_meta)| (Chain { layers, handler }, conn_meta)),
+        )
+    }
+
+    fn on_connect(
+        self,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Self, ConnectionMetadata<U>, Decision), Error = ()>> {
+        let Chain { layers, handler } = self;
+        let ran = run_layers(layers.clone(), conn_meta, |l, conn_meta| {
+            l.on_connect(conn_meta)
+        });
+        Box::new(ran.and_then(|(conn_meta, flow)| match flow {
+            Flow::Stop(decision) => {
+                Either::A(future::ok((Chain { layers, handler }, conn_meta, decision)))
+            }
+            Flow::Next => Either::B(handler.on_connect(conn_meta).map(
+                |(handler, conn_meta, decision)| (Chain { layers, handler }, conn_meta, decision),
+            )),
+        }))
+    }
+
+    fn on_disconnect(
+        self,
+        reason: DisconnectReason,
+        mail: Option<MailMetadata>,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (), Error = ()>> {
+        self.handler.on_disconnect(reason, mail, conn_meta)
+    }
+
+    fn hostname(&self) -> SmtpString {
+        self.handler.hostname()
+    }
+
+    fn xclient_trusted(&self, conn_meta: &ConnectionMetadata<U>) -> bool {
+        self.handler.xclient_trusted(conn_meta)
+    }
+
+    fn xforward_trusted(&self, conn_meta: &ConnectionMetadata<U>) -> bool {
+        self.handler.xforward_trusted(conn_meta)
+    }
+
+    fn lmtp(&self) -> bool {
+        self.handler.lmtp()
+    }
+
+    fn max_command_line_len(&self) -> usize {
+        self.handler.max_command_line_len()
+    }
+
+    fn max_text_line_len(&self) -> usize {
+        self.handler.max_text_line_len()
+    }
+
+    fn max_mail_size(&self) -> Option<usize> {
+        self.handler.max_mail_size()
+    }
+
+    fn command_line_endings(&self) -> LineEndings {
+        self.handler.command_line_endings()
+    }
+
+    fn data_line_endings(&self) -> LineEndings {
+        self.handler.data_line_endings()
+    }
+
+    fn max_rcpts(&self) -> Option<usize> {
+        self.handler.max_rcpts()
+    }
+
+    fn max_buffered_input(&self) -> usize {
+        self.handler.max_buffered_input()
+    }
+
+    fn soft_error_limit(&self) -> Option<usize> {
+        self.handler.soft_error_limit()
+    }
+
+    fn hard_error_limit(&self) -> Option<usize> {
+        self.handler.hard_error_limit()
+    }
+
+    fn soft_command_limit(&self) -> Option<usize> {
+        self.handler.soft_command_limit()
+    }
+
+    fn hard_command_limit(&self) -> Option<usize> {
+        self.handler.hard_command_limit()
+    }
+
+    fn error_delay(&self) -> Duration {
+        self.handler.error_delay()
+    }
+
+    fn pregreet_delay(&self) -> Option<Duration> {
+        self.handler.pregreet_delay()
+    }
+
+    fn reject_early_talkers(&self) -> bool {
+        self.handler.reject_early_talkers()
+    }
+
+    fn greeting_timeout(&self) -> Duration {
+        self.handler.greeting_timeout()
+    }
+
+    fn mail_timeout(&self) -> Duration {
+        self.handler.mail_timeout()
+    }
+
+    fn rcpt_timeout(&self) -> Duration {
+        self.handler.rcpt_timeout()
+    }
+
+    fn data_init_timeout(&self) -> Duration {
+        self.handler.data_init_timeout()
+    }
+
+    fn data_block_timeout(&self) -> Duration {
+        self.handler.data_block_timeout()
+    }
+
+    fn data_termination_timeout(&self) -> Duration {
+        self.handler.data_termination_timeout()
+    }
+
+    fn session_timeout(&self) -> Option<Duration> {
+        self.handler.session_timeout()
+    }
+
+    fn banner(&self) -> SmtpString {
+        self.handler.banner()
+    }
+
+    fn welcome_banner(&self) -> (ReplyCode, SmtpString) {
+        self.handler.welcome_banner()
+    }
+
+    fn okay(&self) -> (ReplyCode, SmtpString) {
+        self.handler.okay()
+    }
+
+    fn helo_okay(&self) -> (ReplyCode, SmtpString) {
+        self.handler.helo_okay()
+    }
+
+    fn ehlo_okay(&self) -> (ReplyCode, SmtpString) {
+        self.handler.ehlo_okay()
+    }
+
+    fn lhlo_okay(&self) -> (ReplyCode, SmtpString) {
+        self.handler.lhlo_okay()
+    }
+
+    fn rset_okay(&self) -> (ReplyCode, SmtpString) {
+        self.handler.rset_okay()
+    }
+
+    fn noop_okay(&self) -> (ReplyCode, SmtpString) {
+        self.handler.noop_okay()
+    }
+
+    fn quit_okay(&self) -> (ReplyCode, SmtpString) {
+        self.handler.quit_okay()
+    }
+
+    fn mail_okay(&self) -> (ReplyCode, SmtpString) {
+        self.handler.mail_okay()
+    }
+
+    fn rcpt_okay(&self) -> (ReplyCode, SmtpString) {
+        self.handler.rcpt_okay()
+    }
+
+    fn data_okay(&self) -> (ReplyCode, SmtpString) {
+        self.handler.data_okay()
+    }
+
+    fn mail_accepted(&self) -> (ReplyCode, SmtpString) {
+        self.handler.mail_accepted()
+    }
+
+    fn bad_sequence(&self) -> (ReplyCode, SmtpString) {
+        self.handler.bad_sequence()
+    }
+
+    fn already_in_mail(&self) -> (ReplyCode, SmtpString) {
+        self.handler.already_in_mail()
+    }
+
+    fn mail_before_lhlo(&self) -> (ReplyCode, SmtpString) {
+        self.handler.mail_before_lhlo()
+    }
+
+    fn rcpt_before_mail(&self) -> (ReplyCode, SmtpString) {
+        self.handler.rcpt_before_mail()
+    }
+
+    fn too_many_rcpts(&self) -> (ReplyCode, SmtpString) {
+        self.handler.too_many_rcpts()
+    }
+
+    fn data_before_rcpt(&self) -> (ReplyCode, SmtpString) {
+        self.handler.data_before_rcpt()
+    }
+
+    fn data_before_mail(&self) -> (ReplyCode, SmtpString) {
+        self.handler.data_before_mail()
+    }
+
+    fn command_unimplemented(&self) -> (ReplyCode, SmtpString) {
+        self.handler.command_unimplemented()
+    }
+
+    fn command_unrecognized(&self) -> (ReplyCode, SmtpString) {
+        self.handler.command_unrecognized()
+    }
+
+    fn timed_out(&self) -> (ReplyCode, SmtpString) {
+        self.handler.timed_out()
+    }
+
+    fn shutting_down(&self) -> (ReplyCode, SmtpString) {
+        self.handler.shutting_down()
+    }
+
+    fn early_talker(&self) -> (ReplyCode, SmtpString) {
+        self.handler.early_talker()
+    }
+
+    fn too_many_errors(&self) -> (ReplyCode, SmtpString) {
+        self.handler.too_many_errors()
+    }
+
+    fn too_many_commands(&self) -> (ReplyCode, SmtpString) {
+        self.handler.too_many_commands()
+    }
+
+    fn too_many_connections(&self) -> (ReplyCode, SmtpString) {
+        self.handler.too_many_connections()
+    }
+
+    fn xclient_unauthorized(&self) -> (ReplyCode, SmtpString) {
+        self.handler.xclient_unauthorized()
+    }
+
+    fn bad_xclient_attribute(&self) -> (ReplyCode, SmtpString) {
+        self.handler.bad_xclient_attribute()
+    }
+
+    fn command_line_too_long(&self) -> (ReplyCode, SmtpString) {
+        self.handler.command_line_too_long()
+    }
+
+    fn bare_line_ending(&self) -> (ReplyCode, SmtpString) {
+        self.handler.bare_line_ending()
+    }
+
+    fn invalid_size(&self) -> (ReplyCode, SmtpString) {
+        self.handler.invalid_size()
+    }
+
+    fn mail_too_big(&self) -> (ReplyCode, SmtpString) {
+        self.handler.mail_too_big()
+    }
+
+    fn text_line_too_long(&self) -> (ReplyCode, SmtpString) {
+        self.handler.text_line_too_long()
+    }
+
+    fn data_bare_line_ending(&self) -> (ReplyCode, SmtpString) {
+        self.handler.data_bare_line_ending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use tokio::runtime::current_thread::Runtime;
+
+    use testutil::email;
+
+    // Number of times the mail went through a `Counter`
+    struct Seen(usize);
+
+    struct Counter;
+
+    fn count(conn_meta: &mut ConnectionMetadata<()>) {
+        let seen = conn_meta.mail_state.remove::<Seen>().map(|s| s.0);
+        conn_meta.mail_state.insert(Seen(seen.unwrap_or(0) + 1));
+    }
+
+    impl Layer<()> for Counter {
+        fn filter_from(
+            &self,
+            from: Option<Email>,
+            mut conn_meta: ConnectionMetadata<()>,
+        ) -> Box<Future<Item = (Option<Email>, ConnectionMetadata<()>, Flow), Error = ()>> {
+            count(&mut conn_meta);
+            Box::new(future::ok((from, conn_meta, Flow::Next)))
+        }
+
+        fn filter_to(
+            &self,
+            to: Email,
+            meta: MailMetadata,
+            mut conn_meta: ConnectionMetadata<()>,
+        ) -> Box<Future<Item = (Email, MailMetadata, ConnectionMetadata<()>, Flow), Error = ()>>
+        {
+            count(&mut conn_meta);
+            Box::new(future::ok((to, meta, conn_meta, Flow::Next)))
+        }
+    }
+
+    // Refuses the senders and recipients with this local part
+    struct Blocklist(&'static str);
+
+    impl Blocklist {
+        fn flow(&self, email: &Email) -> Flow {
+            if email.localpart() == SmtpString::from(self.0) {
+                Flow::Stop(Decision::Reject(Refusal {
+                    code: ReplyCode::POLICY_REASON,
+                    msg:  SmtpString::from_static(b"5.7.1 Blocked"),
+                }))
+            } else {
+                Flow::Next
+            }
+        }
+    }
+
+    impl Layer<()> for Blocklist {
+        fn filter_from(
+            &self,
+            from: Option<Email>,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<Future<Item = (Option<Email>, ConnectionMetadata<()>, Flow), Error = ()>> {
+            let flow = from.as_ref().map(|f| self.flow(f)).unwrap_or(Flow::Next);
+            Box::new(future::ok((from, conn_meta, flow)))
+        }
+
+        fn filter_to(
+            &self,
+            to: Email,
+            meta: MailMetadata,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<Future<Item = (Email, MailMetadata, ConnectionMetadata<()>, Flow), Error = ()>>
+        {
+            let flow = self.flow(&to);
+            Box::new(future::ok((to, meta, conn_meta, flow)))
+        }
+    }
+
+    // Counts the calls to its filters, and tells how many times the
+    // recipients were seen by the layers
+    #[derive(Clone)]
+    struct Handler {
+        calls: Rc<Cell<usize>>,
+    }
+
+    impl Config<()> for Handler {
+        fn hostname(&self) -> SmtpString {
+            SmtpString::from_static(b"test.example.org")
+        }
+
+        fn max_rcpts(&self) -> Option<usize> {
+            Some(5)
+        }
+
+        fn filter_from(
+            self,
+            from: Option<Email>,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<Future<Item = (Self, Option<Email>, ConnectionMetadata<()>, Decision), Error = ()>>
+        {
+            self.calls.set(self.calls.get() + 1);
+            Box::new(future::ok((self, from, conn_meta, Decision::Accept)))
+        }
+
+        fn filter_to(
+            self,
+            to: Email,
+            meta: MailMetadata,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<
+            Future<
+                Item = (Self, Email, MailMetadata, ConnectionMetadata<()>, Decision),
+                Error = (),
+            >,
+        > {
+            self.calls.set(self.calls.get() + 1);
+            let seen = conn_meta.mail_state.get::<Seen>().map(|s| s.0).unwrap_or(0);
+            let decision = Decision::AcceptWith(format!("Seen {} times", seen).as_str().into());
+            Box::new(future::ok((self, to, meta, conn_meta, decision)))
+        }
+
+        fn handle_mail<'a, S: 'a + Stream<Item = BytesMut, Error = ()>>(
+            self,
+            reader: DataStream<S>,
+            _meta: MailMetadata,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<
+            'a
+                + Future<
+                    Item = (
+                        Self,
+                        Option<Prependable<S>>,
+                        ConnectionMetadata<()>,
+                        Decision,
+                    ),
+                    Error = (),
+                >,
+        > {
+            Box::new(
+                reader
+                    .concat_and_recover()
+                    .map_err(|_| ())
+                    .map(move |(_, reader)| {
+                        (self, Some(reader.into_inner()), conn_meta, Decision::Accept)
+                    }),
+            )
+        }
+    }
+
+    fn text(s: &SmtpString) -> String {
+        String::from_utf8_lossy(&s.bytes()[..]).into_owned()
+    }
+
+    fn describe(decision: &Decision) -> String {
+        match decision {
+            Decision::Accept => "accept".to_owned(),
+            Decision::AcceptWith(msg) => format!("accept: {}", text(msg)),
+            Decision::Reject(r) => format!("reject {}: {}", r.code.code(), text(&r.msg)),
+            Decision::RejectAndClose(r) => format!("close {}: {}", r.code.code(), text(&r.msg)),
+            Decision::Delay(..) => "delay".to_owned(),
+        }
+    }
+
+    #[test]
+    fn runs_layers_then_handler() {
+        let calls = Rc::new(Cell::new(0));
+        let chain = Chain::new(Handler {
+            calls: calls.clone(),
+        })
+        .layer(Counter)
+        .layer(Blocklist("spam"))
+        .layer(Counter);
+        assert_eq!(chain.hostname(), SmtpString::from("test.example.org"));
+        assert_eq!(chain.max_rcpts(), Some(5));
+
+        let mut rt = Runtime::new().unwrap();
+        let conn_meta = ConnectionMetadata::new(());
+        let (chain, _, conn_meta, decision) = rt
+            .block_on(chain.filter_from(Some(email("foo@example.org")), conn_meta))
+            .unwrap();
+        assert_eq!(describe(&decision), "accept");
+        let meta = MailMetadata {
+            from: Some(email("foo@example.org")),
+            to:   Vec::new(),
+        };
+        let (chain, _, meta, conn_meta, decision) = rt
+            .block_on(chain.filter_to(email("bar@example.org"), meta, conn_meta))
+            .unwrap();
+        assert_eq!(describe(&decision), "accept: Seen 4 times");
+        assert_eq!(calls.get(), 2);
+
+        // The second counter is not reached
+        let (_, _, _, conn_meta, decision) = rt
+            .block_on(chain.filter_to(email("spam@example.org"), meta, conn_meta))
+            .unwrap();
+        assert_eq!(describe(&decision), "reject 550: 5.7.1 Blocked");
+        assert_eq!(conn_meta.mail_state.get::<Seen>().map(|s| s.0), Some(5));
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn stops_at_the_first_deciding_layer() {
+        let calls = Rc::new(Cell::new(0));
+        let chain = Chain::new(Handler {
+            calls: calls.clone(),
+        })
+        .layer(Blocklist("spam"))
+        .layer(Counter);
+
+        let mut rt = Runtime::new().unwrap();
+        let conn_meta = ConnectionMetadata::new(());
+        let (chain, _, conn_meta, decision) = rt
+            .block_on(chain.filter_from(Some(email("spam@example.org")), conn_meta))
+            .unwrap();
+        assert_eq!(describe(&decision), "reject 550: 5.7.1 Blocked");
+        assert!(conn_meta.mail_state.get::<Seen>().is_none());
+        assert_eq!(calls.get(), 0);
+
+        // Layers without an opinion on an event let it through
+        let (_, conn_meta, decision) = rt.block_on(chain.on_connect(conn_meta)).unwrap();
+        assert_eq!(describe(&decision), "accept");
+        assert!(conn_meta.state.get::<Seen>().is_none());
+    }
+}
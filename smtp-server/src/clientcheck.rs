@@ -0,0 +1,470 @@
+use smtp_message::{Email, ReplyCode, SmtpString};
+use std::{net::IpAddr, rc::Rc, str};
+use tokio::prelude::{future::Either, *};
+
+use chain::{Flow, Layer};
+use decision::{Decision, Refusal};
+use dns::Resolver;
+use metadata::ConnectionMetadata;
+use xclient::parse_ip;
+
+// At most this many names of the client are resolved back, as for
+// `Resolver::lookup_ptr` answers of any size
+const MAX_PTR_NAMES: usize = 10;
+
+// Outcome of the forward-confirmed reverse DNS lookup of the client
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ReverseDns {
+    // Not done, eg. for connections without a client address
+    Unchecked,
+    // A lookup failed, and none of the names found resolves to the client
+    TempFail,
+    // The client has no name resolving back to its address
+    Unconfirmed,
+    // First name of the client that resolves back to its address
+    Confirmed(SmtpString),
+}
+
+// Outcome of the check of the name the client gave with HELO, EHLO or LHLO
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HeloCheck {
+    // The client did not greet
+    Missing,
+    // Neither a fully-qualified domain name nor an address literal
+    Invalid,
+    // Our own hostname, or an address literal of the server
+    Forged,
+    // The lookup of the name failed, and could succeed later
+    TempFail,
+    // The name has no address
+    Unresolvable,
+    // Whether the name is (or resolves to) the address of the client
+    Valid { matches_client: bool },
+}
+
+// Reasons `ClientCheck` can be told to refuse senders for
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClientRule {
+    HeloMissing,
+    HeloInvalid,
+    // Covers lookups of the name that failed, with a temporary refusal
+    HeloUnresolvable,
+    HeloForged,
+    // Covers lookups that failed as well as unconfirmed names
+    NoReverseDns,
+}
+
+// Result of the HELO check for the name it was done on, as the client may
+// greet again
+struct CheckedHelo(Option<SmtpString>, HeloCheck);
+
+// Client checking policy, that does the forward-confirmed reverse DNS lookup
+// of the client at connection time, and checks its HELO name on MAIL FROM,
+// storing the outcomes in `ConnectionMetadata::reverse_dns` and
+// `ConnectionMetadata::helo_check`. A confirmed name also fills in
+// `ConnectionMetadata::peer_name`, unless a trusted proxy already told it.
+//
+// Senders are then refused according to the rules given, the first one that
+// applies deciding: refusing at MAIL FROM rather than at connection or HELO
+// time lets clients be told why, and authenticated users be let through by
+// layers running before this one.
+#[derive(Clone)]
+pub struct ClientCheck {
+    resolver: Rc<Resolver>,
+    hostname: SmtpString,
+    rules:    Vec<ClientRule>,
+}
+
+impl ClientCheck {
+    // `hostname` is the name of the server, as given by `Config::hostname`
+    pub fn new(resolver: Rc<Resolver>, hostname: SmtpString) -> ClientCheck {
+        ClientCheck {
+            resolver,
+            hostname,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn reject(mut self, rule: ClientRule) -> ClientCheck {
+        self.rules.push(rule);
+        self
+    }
+
+    // Resolves the names of `ip`, keeping the first one that resolves back to
+    // it
+    fn reverse_dns(&self, ip: IpAddr) -> impl Future<Item = ReverseDns, Error = ()> {
+        let resolver = self.resolver.clone();
+        self.resolver.lookup_ptr(ip).then(move |names| {
+            let names = match names {
+                Ok(names) => names,
+                Err(_) => return Either::A(future::ok(ReverseDns::TempFail)),
+            };
+            let lookups = names
+                .into_iter()
+                .take(MAX_PTR_NAMES)
+                .map(|name| {
+                    let name = name.trim_end_matches('.').to_owned();
+                    resolver
+                        .lookup_ip(&name)
+                        .then(move |ips| Ok::<_, ()>((name, ips.map(|ips| ips.contains(&ip)))))
+                })
+                .collect::<Vec<_>>();
+            Either::B(future::join_all(lookups).map(|res| {
+                let mut failed = false;
+                for (name, confirmed) in res {
+                    match confirmed {
+                        Ok(true) => return ReverseDns::Confirmed(SmtpString::from(&name[..])),
+                        Ok(false) => (),
+                        Err(_) => failed = true,
+                    }
+                }
+                if failed {
+                    ReverseDns::TempFail
+                } else {
+                    ReverseDns::Unconfirmed
+                }
+            }))
+        })
+    }
+
+    fn helo_check<U>(
+        &self,
+        conn_meta: &ConnectionMetadata<U>,
+    ) -> impl Future<Item = HeloCheck, Error = ()> {
+        let helo = match conn_meta.helo {
+            Some(ref helo) => helo,
+            None => return Either::A(future::ok(HeloCheck::Missing)),
+        };
+        let peer = conn_meta.peer_addr.map(|a| a.ip());
+        let local = conn_meta.local_addr.map(|a| a.ip());
+        let name = match str::from_utf8(&helo.bytes()[..]) {
+            Ok(name) => name,
+            Err(_) => return Either::A(future::ok(HeloCheck::Invalid)),
+        };
+        if name.starts_with('[') && name.ends_with(']') && name.len() > 2 {
+            let check = match parse_ip(&SmtpString::from(&name[1..name.len() - 1])) {
+                Err(()) => HeloCheck::Invalid,
+                Ok(ip) if Some(ip) == local => HeloCheck::Forged,
+                Ok(ip) => HeloCheck::Valid {
+                    matches_client: Some(ip) == peer,
+                },
+            };
+            return Either::A(future::ok(check));
+        }
+        if !is_fqdn(name) {
+            return Either::A(future::ok(HeloCheck::Invalid));
+        }
+        let name = name.trim_end_matches('.');
+        let ours = str::from_utf8(&self.hostname.bytes()[..]).unwrap_or("");
+        if name.eq_ignore_ascii_case(ours.trim_end_matches('.')) {
+            return Either::A(future::ok(HeloCheck::Forged));
+        }
+        Either::B(self.resolver.lookup_ip(name).then(move |ips| {
+            Ok(match ips {
+                Err(_) => HeloCheck::TempFail,
+                Ok(ref ips) if ips.is_empty() => HeloCheck::Unresolvable,
+                Ok(ips) => HeloCheck::Valid {
+                    matches_client: peer.map(|p| ips.contains(&p)).unwrap_or(false),
+                },
+            })
+        }))
+    }
+
+    // Refusal for the first rule that applies to the client, if any
+    fn judge<U>(&self, conn_meta: &ConnectionMetadata<U>) -> Option<Refusal> {
+        let helo = conn_meta.helo_check.as_ref();
+        let refuse = |code, msg: String| {
+            Some(Refusal {
+                code,
+                msg: SmtpString::from(msg.into_bytes()),
+            })
+        };
+        for rule in self.rules.iter() {
+            match (rule, helo, &conn_meta.reverse_dns) {
+                (ClientRule::HeloMissing, Some(HeloCheck::Missing), _) => {
+                    return refuse(
+                        ReplyCode::POLICY_REASON,
+                        "5.5.1 Helo command rejected: send HELO or EHLO first".to_owned(),
+                    );
+                }
+                (ClientRule::HeloInvalid, Some(HeloCheck::Invalid), _) => {
+                    return refuse(
+                        ReplyCode::SYNTAX_ERROR,
+                        "5.5.2 Helo command rejected: Invalid name".to_owned(),
+                    );
+                }
+                (ClientRule::HeloForged, Some(HeloCheck::Forged), _) => {
+                    return refuse(
+                        ReplyCode::POLICY_REASON,
+                        "5.7.1 Helo command rejected: You are not me".to_owned(),
+                    );
+                }
+                (ClientRule::HeloUnresolvable, Some(HeloCheck::Unresolvable), _)
+                | (ClientRule::HeloUnresolvable, Some(HeloCheck::TempFail), _) => {
+                    return refuse(
+                        ReplyCode::MAILBOX_TEMPORARILY_UNAVAILABLE,
+                        "4.7.1 Helo command rejected: Host not found".to_owned(),
+                    );
+                }
+                (ClientRule::NoReverseDns, _, ReverseDns::Unconfirmed)
+                | (ClientRule::NoReverseDns, _, ReverseDns::TempFail) => {
+                    let ip = conn_meta.peer_addr.map(|a| a.ip().to_string());
+                    return refuse(
+                        ReplyCode::MAILBOX_TEMPORARILY_UNAVAILABLE,
+                        format!(
+                            "4.7.1 Client host rejected: cannot find your hostname, [{}]",
+                            ip.unwrap_or_else(String::new)
+                        ),
+                    );
+                }
+                _ => (),
+            }
+        }
+        None
+    }
+}
+
+impl<U: 'static> Layer<U> for ClientCheck {
+    fn on_connect(
+        &self,
+        mut conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (ConnectionMetadata<U>, Flow), Error = ()>> {
+        let ip = match conn_meta.peer_addr {
+            Some(addr) => addr.ip(),
+            None => return Box::new(future::ok((conn_meta, Flow::Next))),
+        };
+        Box::new(self.reverse_dns(ip).map(move |rdns| {
+            if let ReverseDns::Confirmed(ref name) = rdns {
+                if conn_meta.peer_name.is_none() {
+                    conn_meta.peer_name = Some(name.clone());
+                }
+            }
+            conn_meta.reverse_dns = rdns;
+            (conn_meta, Flow::Next)
+        }))
+    }
+
+    fn filter_from(
+        &self,
+        from: Option<Email>,
+        mut conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Option<Email>, ConnectionMetadata<U>, Flow), Error = ()>> {
+        let cached = match conn_meta.state.get::<CheckedHelo>() {
+            Some(&CheckedHelo(ref helo, ref check)) if *helo == conn_meta.helo => {
+                Some(check.clone())
+            }
+            _ => None,
+        };
+        let check = match cached {
+            Some(check) => Either::A(future::ok(check)),
+            None => Either::B(self.helo_check(&conn_meta)),
+        };
+        let this = self.clone();
+        Box::new(check.map(move |check| {
+            let helo = conn_meta.helo.clone();
+            conn_meta.state.insert(CheckedHelo(helo, check.clone()));
+            conn_meta.helo_check = Some(check);
+            let flow = match this.judge(&conn_meta) {
+                Some(r) => Flow::Stop(Decision::Reject(r)),
+                None => Flow::Next,
+            };
+            (from, conn_meta, flow)
+        }))
+    }
+}
+
+// Whether `name` is a fully-qualified domain name, made of at least two labels
+// of letters, digits and inner hyphens, and not ending with a numeric one
+fn is_fqdn(name: &str) -> bool {
+    let name = if name.ends_with('.') {
+        &name[..name.len() - 1]
+    } else {
+        name
+    };
+    let labels = name.split('.').collect::<Vec<_>>();
+    name.len() <= 253
+        && labels.len() >= 2
+        && labels.iter().all(|l| {
+            !l.is_empty()
+                && l.len() <= 63
+                && !l.starts_with('-')
+                && !l.ends_with('-')
+                && l.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+        && !labels[labels.len() - 1].bytes().all(|b| b.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::current_thread::Runtime;
+
+    use dns::tests::FakeResolver;
+    use testutil::refusal;
+
+    fn check() -> ClientCheck {
+        let mut resolver = FakeResolver::default();
+        let client = "192.0.2.1".parse().unwrap();
+        resolver
+            .ptrs
+            .insert(client, vec!["other.example.org.", "client.example.org."]);
+        resolver
+            .ptrs
+            .insert("192.0.2.2".parse().unwrap(), vec!["forged.example.org."]);
+        resolver
+            .ptrs
+            .insert("192.0.2.3".parse().unwrap(), vec!["failing.example.org."]);
+        resolver
+            .ips
+            .insert("other.example.org", vec!["192.0.2.9".parse().unwrap()]);
+        resolver.ips.insert("client.example.org", vec![client]);
+        resolver
+            .ips
+            .insert("forged.example.org", vec!["192.0.2.9".parse().unwrap()]);
+        resolver.failing.push("failing.example.org");
+        resolver.failing.push("192.0.2.4");
+        ClientCheck::new(Rc::new(resolver), "mx.example.net".into())
+    }
+
+    fn connect(rt: &mut Runtime, ip: &str) -> ConnectionMetadata<()> {
+        let mut conn_meta = ConnectionMetadata::new(());
+        conn_meta.peer_addr = Some(format!("{}:25", ip).parse().unwrap());
+        conn_meta.local_addr = Some("198.51.100.1:25".parse().unwrap());
+        let (conn_meta, flow) = rt.block_on(check().on_connect(conn_meta)).unwrap();
+        assert!(refusal(&flow).is_none());
+        conn_meta
+    }
+
+    fn helo(
+        rt: &mut Runtime,
+        check: &ClientCheck,
+        mut conn_meta: ConnectionMetadata<()>,
+        helo: Option<&str>,
+    ) -> (HeloCheck, Option<(u16, String)>) {
+        conn_meta.helo = helo.map(SmtpString::from);
+        let (_, conn_meta, flow) = rt.block_on(check.filter_from(None, conn_meta)).unwrap();
+        (conn_meta.helo_check.unwrap(), refusal(&flow))
+    }
+
+    #[test]
+    fn confirms_reverse_dns() {
+        let mut rt = Runtime::new().unwrap();
+        let conn_meta = connect(&mut rt, "192.0.2.1");
+        let name = SmtpString::from("client.example.org");
+        assert_eq!(conn_meta.reverse_dns, ReverseDns::Confirmed(name.clone()));
+        assert_eq!(conn_meta.peer_name, Some(name));
+
+        let conn_meta = connect(&mut rt, "192.0.2.2");
+        assert_eq!(conn_meta.reverse_dns, ReverseDns::Unconfirmed);
+        assert_eq!(conn_meta.peer_name, None);
+        let conn_meta = connect(&mut rt, "192.0.2.5");
+        assert_eq!(conn_meta.reverse_dns, ReverseDns::Unconfirmed);
+        let conn_meta = connect(&mut rt, "192.0.2.3");
+        assert_eq!(conn_meta.reverse_dns, ReverseDns::TempFail);
+        let conn_meta = connect(&mut rt, "192.0.2.4");
+        assert_eq!(conn_meta.reverse_dns, ReverseDns::TempFail);
+
+        let conn_meta = rt
+            .block_on(check().on_connect(ConnectionMetadata::new(())))
+            .unwrap()
+            .0;
+        assert_eq!(conn_meta.reverse_dns, ReverseDns::Unchecked);
+    }
+
+    #[test]
+    fn checks_helo_names() {
+        let mut rt = Runtime::new().unwrap();
+        let check = check();
+        let mut outcome = |name| {
+            let conn_meta = connect(&mut rt, "192.0.2.1");
+            helo(&mut rt, &check, conn_meta, name).0
+        };
+        let valid = |matches_client| HeloCheck::Valid { matches_client };
+        assert_eq!(outcome(None), HeloCheck::Missing);
+        assert_eq!(outcome(Some("Client.example.org")), valid(true));
+        assert_eq!(outcome(Some("other.example.org.")), valid(false));
+        assert_eq!(outcome(Some("[192.0.2.1]")), valid(true));
+        assert_eq!(outcome(Some("[IPv6:2001:db8::1]")), valid(false));
+        assert_eq!(
+            outcome(Some("unknown.example.org")),
+            HeloCheck::Unresolvable
+        );
+        assert_eq!(outcome(Some("failing.example.org")), HeloCheck::TempFail);
+        assert_eq!(outcome(Some("MX.example.net")), HeloCheck::Forged);
+        assert_eq!(outcome(Some("[198.51.100.1]")), HeloCheck::Forged);
+        for name in &[
+            "localhost",
+            "192.0.2.1",
+            "[192.0.2]",
+            "-a.example.org",
+            "a_b.example.org",
+        ] {
+            assert_eq!(outcome(Some(*name)), HeloCheck::Invalid, "{}", name);
+        }
+    }
+
+    #[test]
+    fn refuses_by_rule() {
+        let mut rt = Runtime::new().unwrap();
+        let strict = check()
+            .reject(ClientRule::HeloMissing)
+            .reject(ClientRule::HeloInvalid)
+            .reject(ClientRule::HeloForged)
+            .reject(ClientRule::HeloUnresolvable)
+            .reject(ClientRule::NoReverseDns);
+        let mut refused = |check: &ClientCheck, ip, name| {
+            let conn_meta = connect(&mut rt, ip);
+            helo(&mut rt, check, conn_meta, name).1
+        };
+        let code = |r: Option<(u16, String)>| r.map(|r| r.0);
+        let client = "192.0.2.1";
+        assert_eq!(refused(&strict, client, Some("client.example.org")), None);
+        assert_eq!(code(refused(&strict, client, None)), Some(550));
+        assert_eq!(code(refused(&strict, client, Some("localhost"))), Some(501));
+        assert_eq!(
+            code(refused(&strict, client, Some("unknown.example.org"))),
+            Some(450)
+        );
+        assert_eq!(
+            refused(&strict, client, Some("mx.example.net")),
+            Some((
+                550,
+                "5.7.1 Helo command rejected: You are not me".to_owned()
+            ))
+        );
+        assert_eq!(
+            refused(&strict, "192.0.2.2", Some("[192.0.2.2]")),
+            Some((
+                450,
+                "4.7.1 Client host rejected: cannot find your hostname, [192.0.2.2]".to_owned()
+            ))
+        );
+
+        // Only the rules given apply
+        let forged = check().reject(ClientRule::HeloForged);
+        assert_eq!(refused(&forged, "192.0.2.2", None), None);
+        assert_eq!(
+            code(refused(&forged, client, Some("[198.51.100.1]"))),
+            Some(550)
+        );
+    }
+
+    #[test]
+    fn caches_helo_checks() {
+        let mut rt = Runtime::new().unwrap();
+        let check = check().reject(ClientRule::HeloUnresolvable);
+        let mut conn_meta = connect(&mut rt, "192.0.2.1");
+        conn_meta.helo = Some("client.example.org".into());
+        let (_, conn_meta, _) = rt.block_on(check.filter_from(None, conn_meta)).unwrap();
+
+        // Lookups are not done again for the same name, but are for a new one
+        let forgetful = ClientCheck::new(Rc::new(FakeResolver::default()), "mx.example.net".into())
+            .reject(ClientRule::HeloUnresolvable);
+        let (_, mut conn_meta, flow) = rt.block_on(forgetful.filter_from(None, conn_meta)).unwrap();
+        assert!(refusal(&flow).is_none());
+        conn_meta.helo = Some("other.example.org".into());
+        let (_, conn_meta, flow) = rt.block_on(forgetful.filter_from(None, conn_meta)).unwrap();
+        assert_eq!(conn_meta.helo_check, Some(HeloCheck::Unresolvable));
+        assert_eq!(refusal(&flow).map(|r| r.0), Some(450));
+    }
+}
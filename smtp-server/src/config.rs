@@ -1,8 +1,11 @@
 use bytes::BytesMut;
 use smtp_message::{DataStream, Email, Prependable, ReplyCode, SmtpString};
+use std::time::Duration;
 use tokio::prelude::*;
 
-use decision::Decision;
+use crlflines::LineEndings;
+use decision::{Decision, Refusal};
+use disconnect::DisconnectReason;
 use metadata::{ConnectionMetadata, MailMetadata};
 
 // TODO: (B) replace all these Box by impl Trait syntax hide:impl-trait-in-trait
@@ -19,6 +22,8 @@ pub trait Config<U: 'static>: Sized + 'static {
         conn_meta: ConnectionMetadata<U>,
     ) -> Box<Future<Item = (Self, Option<Email>, ConnectionMetadata<U>, Decision), Error = ()>>;
 
+    // The recipient returned is the one added to the mail, which allows
+    // rewriting it
     fn filter_to(
         self,
         to: Email,
@@ -34,6 +39,10 @@ pub trait Config<U: 'static>: Sized + 'static {
         Box::new(future::ok((self, meta, conn_meta, Decision::Accept)))
     }
 
+    // The stream ends early, making `DataStream::into_inner` fail, when the
+    // connection is closed or when the data breaks a limit. In the latter case
+    // the session goes on and the returned decision is ignored, so the future
+    // should not fail. The returned reader is not used any longer.
     fn handle_mail<'a, S: 'a + Stream<Item = BytesMut, Error = ()>>(
         self,
         stream: DataStream<S>,
@@ -52,8 +61,214 @@ pub trait Config<U: 'static>: Sized + 'static {
             >,
     >;
 
+    // Like `handle_mail`, but with a decision for each recipient, in the order
+    // of `meta.to`. Defaults to applying the decision of `handle_mail` to all
+    // the recipients. Recipients without a decision get a 451 reply, and
+    // extra decisions are ignored.
+    fn handle_mail_rcpts<'a, S: 'a + Stream<Item = BytesMut, Error = ()>>(
+        self,
+        stream: DataStream<S>,
+        meta: MailMetadata,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<
+        'a
+            + Future<
+                Item = (
+                    Self,
+                    Option<Prependable<S>>,
+                    ConnectionMetadata<U>,
+                    Vec<Decision>,
+                ),
+                Error = (),
+            >,
+    > {
+        let rcpts = meta.to.len();
+        Box::new(self.handle_mail(stream, meta, conn_meta).map(
+            move |(cfg, reader, conn_meta, decision)| {
+                (cfg, reader, conn_meta, vec![decision; rcpts])
+            },
+        ))
+    }
+
+    // Called when the decisions of `handle_mail_rcpts` refuse only some of the
+    // recipients. The mail is then accepted, so the client will not tell the
+    // sender about the failures: this should queue bounces for them.
+    fn rcpts_failed(
+        self,
+        _failed: Vec<(Email, Refusal)>,
+        _meta: MailMetadata,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Self, ConnectionMetadata<U>), Error = ()>> {
+        Box::new(future::ok((self, conn_meta)))
+    }
+
     fn hostname(&self) -> SmtpString;
 
+    // Called before sending the banner, and again after XCLIENT told of
+    // another client, with `ConnectionMetadata::state` and the checks of the
+    // former one dropped. A refusal is sent in place of the banner, and the
+    // connection closed: RFC 5321 § 3.1 calls for a 554 code.
+    fn on_connect(
+        self,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Self, ConnectionMetadata<U>, Decision), Error = ()>> {
+        Box::new(future::ok((self, conn_meta, Decision::Accept)))
+    }
+
+    // Time to wait before ending the banner, the client being caught talking
+    // early if it sends anything in the meantime (like postscreen's pregreet
+    // test). Only the first line of the banner is sent during the delay, as
+    // bots tend to wait for any reply rather than for its last line.
+    fn pregreet_delay(&self) -> Option<Duration> {
+        None
+    }
+
+    // Whether to refuse the clients that talked before the end of the banner,
+    // instead of only setting `ConnectionMetadata::early_talker`
+    fn reject_early_talkers(&self) -> bool {
+        false
+    }
+
+    // Called once the session is over, unless it was aborted by an error from
+    // the configuration or while writing to the client. `mail` is the mail
+    // transaction that was left unfinished, if any.
+    fn on_disconnect(
+        self,
+        _reason: DisconnectReason,
+        _mail: Option<MailMetadata>,
+        _conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (), Error = ()>> {
+        Box::new(future::ok(()))
+    }
+
+    // Whether the client is a trusted proxy, allowed to override its
+    // attributes in `ConnectionMetadata` with XCLIENT
+    fn xclient_trusted(&self, _conn_meta: &ConnectionMetadata<U>) -> bool {
+        false
+    }
+
+    // Whether the client is a trusted content filter, allowed to forward the
+    // attributes of the original client of a mail with XFORWARD
+    fn xforward_trusted(&self, _conn_meta: &ConnectionMetadata<U>) -> bool {
+        false
+    }
+
+    // Whether to speak LMTP (RFC 2033) in place of SMTP: the client then has
+    // to greet with LHLO, and gets a reply for each recipient after the data
+    fn lmtp(&self) -> bool {
+        false
+    }
+
+    // Maximum length of a command line, CRLF included (RFC 5321 § 4.5.3.1.4)
+    fn max_command_line_len(&self) -> usize {
+        512
+    }
+
+    // Maximum length of a line of the mail data, CRLF included (RFC 5321
+    // § 4.5.3.1.6)
+    fn max_text_line_len(&self) -> usize {
+        1000
+    }
+
+    // Maximum size of the mail data, advertised with the SIZE extension (RFC
+    // 1870). Past it the rest of the data is discarded, and the mail refused.
+    fn max_mail_size(&self) -> Option<usize> {
+        None
+    }
+
+    // How to handle a CR or LF that is not part of a CRLF in a command line
+    fn command_line_endings(&self) -> LineEndings {
+        LineEndings::Reject
+    }
+
+    // Same as `command_line_endings`, for the mail data. Anything but
+    // `Reject` lets through mails that other servers may split differently
+    // when they relay them.
+    fn data_line_endings(&self) -> LineEndings {
+        LineEndings::Reject
+    }
+
+    // Maximum number of recipients of a mail, the next ones being refused with
+    // a temporary failure so that the client sends them in another mail. RFC
+    // 5321 § 4.5.3.1.8 requires accepting at least 100.
+    fn max_rcpts(&self) -> Option<usize> {
+        Some(100)
+    }
+
+    // Amount of received data past which the server stops reading commands
+    // ahead of handling them
+    fn max_buffered_input(&self) -> usize {
+        64 * 1024
+    }
+
+    // Numbers of errors (invalid or unknown commands, and refused recipients)
+    // past which each reply is delayed by `error_delay` more than the previous
+    // one, and at which the connection is closed with a 421 reply
+    fn soft_error_limit(&self) -> Option<usize> {
+        None
+    }
+
+    fn hard_error_limit(&self) -> Option<usize> {
+        None
+    }
+
+    // Same as the error limits, for the number of commands in the session
+    fn soft_command_limit(&self) -> Option<usize> {
+        None
+    }
+
+    fn hard_command_limit(&self) -> Option<usize> {
+        None
+    }
+
+    fn error_delay(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    // The following timeouts default to the ones RFC 5321 § 4.5.3.2 sets for
+    // the client waiting on the server, the server having to be at least as
+    // patient
+
+    // Time given to the client for sending its first command
+    fn greeting_timeout(&self) -> Duration {
+        Duration::from_secs(5 * 60)
+    }
+
+    // Time given to the client for sending a command outside of a mail
+    // transaction
+    fn mail_timeout(&self) -> Duration {
+        Duration::from_secs(5 * 60)
+    }
+
+    // Time given to the client for sending a command during a mail
+    // transaction
+    fn rcpt_timeout(&self) -> Duration {
+        Duration::from_secs(5 * 60)
+    }
+
+    // Time given to the client for starting to send the data after the 354
+    fn data_init_timeout(&self) -> Duration {
+        Duration::from_secs(2 * 60)
+    }
+
+    // Time given to the client for sending each line of the data
+    fn data_block_timeout(&self) -> Duration {
+        Duration::from_secs(3 * 60)
+    }
+
+    // Time given to `handle_mail` for deciding after the end of the data.
+    // When it expires the client gets a 421 reply, but the session still waits
+    // for `handle_mail` before ending.
+    fn data_termination_timeout(&self) -> Duration {
+        Duration::from_secs(10 * 60)
+    }
+
+    // Time after which the session is ended the next time the server waits for
+    // the client
+    fn session_timeout(&self) -> Option<Duration> {
+        None
+    }
+
     fn banner(&self) -> SmtpString {
         SmtpString::from_static(b"Service ready")
     }
@@ -72,6 +287,35 @@ pub trait Config<U: 'static>: Sized + 'static {
         (ReplyCode::OKAY, SmtpString::from_static(b"Okay"))
     }
 
+    fn helo_okay(&self) -> (ReplyCode, SmtpString) {
+        (ReplyCode::OKAY, self.hostname())
+    }
+
+    // First line of the reply to EHLO, the supported extensions are listed
+    // after it
+    fn ehlo_okay(&self) -> (ReplyCode, SmtpString) {
+        self.helo_okay()
+    }
+
+    fn lhlo_okay(&self) -> (ReplyCode, SmtpString) {
+        self.ehlo_okay()
+    }
+
+    fn rset_okay(&self) -> (ReplyCode, SmtpString) {
+        self.okay()
+    }
+
+    fn noop_okay(&self) -> (ReplyCode, SmtpString) {
+        self.okay()
+    }
+
+    fn quit_okay(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::CLOSING_CHANNEL,
+            self.hostname() + SmtpString::from_static(b" Service closing transmission channel"),
+        )
+    }
+
     fn mail_okay(&self) -> (ReplyCode, SmtpString) {
         self.okay()
     }
@@ -102,10 +346,21 @@ pub trait Config<U: 'static>: Sized + 'static {
         self.bad_sequence()
     }
 
+    fn mail_before_lhlo(&self) -> (ReplyCode, SmtpString) {
+        self.bad_sequence()
+    }
+
     fn rcpt_before_mail(&self) -> (ReplyCode, SmtpString) {
         self.bad_sequence()
     }
 
+    fn too_many_rcpts(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::INSUFFICIENT_STORAGE,
+            SmtpString::from_static(b"4.5.3 Too many recipients"),
+        )
+    }
+
     fn data_before_rcpt(&self) -> (ReplyCode, SmtpString) {
         self.bad_sequence()
     }
@@ -127,4 +382,113 @@ pub trait Config<U: 'static>: Sized + 'static {
             SmtpString::from_static(b"Command not recognized"),
         )
     }
+
+    fn timed_out(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::SERVICE_NOT_AVAILABLE,
+            self.hostname() + SmtpString::from_static(b" Timeout exceeded, closing connection"),
+        )
+    }
+
+    fn shutting_down(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::SERVICE_NOT_AVAILABLE,
+            self.hostname() + SmtpString::from_static(b" Service shutting down, try again later"),
+        )
+    }
+
+    // Sent in place of the end of the banner to the clients that talked before
+    // it, if they are refused
+    fn early_talker(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::TRANSACTION_FAILED,
+            self.hostname()
+                + SmtpString::from_static(b" Protocol error, talked before the greeting"),
+        )
+    }
+
+    fn too_many_errors(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::SERVICE_NOT_AVAILABLE,
+            self.hostname() + SmtpString::from_static(b" Too many errors, closing connection"),
+        )
+    }
+
+    fn too_many_commands(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::SERVICE_NOT_AVAILABLE,
+            self.hostname() + SmtpString::from_static(b" Too many commands, closing connection"),
+        )
+    }
+
+    // Sent by `Server` in place of the banner to the clients above the
+    // connection limits
+    fn too_many_connections(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::SERVICE_NOT_AVAILABLE,
+            self.hostname() + SmtpString::from_static(b" Too many connections, try again later"),
+        )
+    }
+
+    // Sent in reply to XCLIENT or XFORWARD from untrusted clients
+    fn xclient_unauthorized(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::POLICY_REASON,
+            SmtpString::from_static(b"5.7.0 Insufficient authorization"),
+        )
+    }
+
+    fn bad_xclient_attribute(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::SYNTAX_ERROR,
+            SmtpString::from_static(b"5.5.4 Bad attribute"),
+        )
+    }
+
+    fn command_line_too_long(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::COMMAND_UNRECOGNIZED,
+            SmtpString::from_static(b"Line too long"),
+        )
+    }
+
+    fn bare_line_ending(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::COMMAND_UNRECOGNIZED,
+            SmtpString::from_static(b"Bare CR or LF not allowed"),
+        )
+    }
+
+    fn invalid_size(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::SYNTAX_ERROR,
+            SmtpString::from_static(b"Invalid SIZE parameter"),
+        )
+    }
+
+    // Sent in reply to MAIL FROM when the declared size is too big, or in
+    // place of the reply to the mail data when it turns out to be
+    fn mail_too_big(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::EXCEEDED_STORAGE,
+            SmtpString::from_static(b"Message size exceeds fixed maximum message size"),
+        )
+    }
+
+    // Replaces the reply to the mail data if one of its lines was too long
+    fn text_line_too_long(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::EXCEEDED_STORAGE,
+            SmtpString::from_static(b"Line too long"),
+        )
+    }
+
+    // Replaces the reply to the mail data if it had a bare CR or LF, with
+    // `LineEndings::Reject`
+    fn data_bare_line_ending(&self) -> (ReplyCode, SmtpString) {
+        (
+            ReplyCode::TRANSACTION_FAILED,
+            SmtpString::from_static(b"Bare CR or LF not allowed in the data"),
+        )
+    }
 }
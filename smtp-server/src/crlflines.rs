@@ -1,18 +1,133 @@
 use bytes::BytesMut;
 use smtp_message::Prependable;
+use std::mem;
 use tokio::prelude::*;
 
+// What to do with a CR or LF that is not part of a CRLF. Other mail systems do
+// not all agree on where such lines end, which allows smuggling a mail in the
+// data of another one (eg. with `\n.\n`) when they relay ours. Thus, unless
+// `Lenient` is used, only a `.` line between two actual CRLFs ends the mail
+// data (see `CrlfLines::is_strict_line`).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LineEndings {
+    // Refuse the line
+    Reject,
+    // End the line there, with a CRLF in place of the bare CR or LF
+    Normalize,
+    // Keep the CR or LF as part of the line
+    PassThrough,
+    // For legacy clients: end lines on a bare LF too, replacing it with a
+    // CRLF, and refuse bare CRs as with `Reject`. The mail data may then end
+    // with a `.` line ending in a bare LF (eg. `\n.\n`).
+    Lenient,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Line {
+    // A line, including its terminating CRLF
+    Complete(BytesMut),
+    // A line longer than the limit, whose contents have been discarded
+    TooLong,
+    // A line with a bare CR or LF, refused as per `LineEndings::Reject`
+    BareLineEnding,
+}
+
 pub struct CrlfLines<S: Stream<Item = BytesMut>> {
     source: Prependable<S>,
-    buf:    BytesMut,
+    buf: BytesMut,
+    eof: bool,
+    // Maximum length of a line, CRLF included
+    max_line_len: usize,
+    // Amount of buffered data past which `poll_available` stops reading
+    max_buffered: usize,
+    // Whether the beginning of the current line has been discarded for being
+    // too long
+    discarding: bool,
+    line_endings: LineEndings,
+    // Whether a line ended with a bare LF, as accepted by `LineEndings::Lenient`
+    saw_bare_lf: bool,
+    // Whether the last line returned ended with an actual CRLF, and whether
+    // it also started after one
+    after_crlf: bool,
+    strict_line: bool,
 }
 
 impl<S: Stream<Item = BytesMut>> CrlfLines<S> {
     pub fn new(s: Prependable<S>) -> CrlfLines<S> {
         CrlfLines {
             source: s,
-            buf:    BytesMut::new(),
+            buf: BytesMut::new(),
+            eof: false,
+            max_line_len: usize::max_value(),
+            max_buffered: usize::max_value(),
+            discarding: false,
+            line_endings: LineEndings::PassThrough,
+            saw_bare_lf: false,
+            after_crlf: true,
+            strict_line: false,
+        }
+    }
+
+    pub fn max_line_len(&self) -> usize {
+        self.max_line_len
+    }
+
+    pub fn set_max_line_len(&mut self, max: usize) {
+        self.max_line_len = max;
+    }
+
+    pub fn line_endings(&self) -> LineEndings {
+        self.line_endings
+    }
+
+    pub fn set_line_endings(&mut self, line_endings: LineEndings) {
+        self.line_endings = line_endings;
+    }
+
+    pub fn saw_bare_lf(&self) -> bool {
+        self.saw_bare_lf
+    }
+
+    // Whether the last line returned was between two actual CRLFs, rather
+    // than started or ended by a bare CR or LF. Normalized lines are not.
+    pub fn is_strict_line(&self) -> bool {
+        self.strict_line
+    }
+
+    pub fn set_max_buffered(&mut self, max: usize) {
+        self.max_buffered = max;
+    }
+
+    // Whether a complete line is already buffered, ie. whether the next `poll`
+    // is guaranteed to return a line without reading from the network
+    pub fn has_buffered_line(&self) -> bool {
+        self.line_end().is_some()
+    }
+
+    // Position of the end of the first buffered line, terminator included
+    fn line_end(&self) -> Option<usize> {
+        match self.line_endings {
+            LineEndings::Lenient => self.buf.iter().position(|&c| c == b'\n').map(|p| p + 1),
+            _ => self
+                .buf
+                .windows(2)
+                .position(|x| x == b"\r\n")
+                .map(|p| p + 2),
+        }
+    }
+
+    // Reads everything that is immediately available from the source, without
+    // waiting for more, and returns whether there is any data buffered.
+    // As it polls the source, this must be called from within a task.
+    pub fn poll_available(&mut self) -> Result<bool, S::Error> {
+        while !self.eof && self.buf.len() < self.max_buffered {
+            match self.source.poll()? {
+                Async::NotReady => break,
+                Async::Ready(None) => self.eof = true,
+                Async::Ready(Some(b)) => self.buf.unsplit(b),
+            }
         }
+        Ok(!self.buf.is_empty())
     }
 
     pub fn into_inner(mut self) -> Prependable<S> {
@@ -30,34 +145,73 @@ impl<S: Stream<Item = BytesMut>> CrlfLines<S> {
 }
 
 impl<S: Stream<Item = BytesMut>> Stream for CrlfLines<S> {
-    type Item = BytesMut;
+    type Item = Line;
     type Error = S::Error;
 
     fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
         use self::Async::*;
 
-        // First, empty the current buffer
-        if let Some(pos) = self.buf.windows(2).position(|x| x == b"\r\n") {
-            return Ok(Ready(Some(self.buf.split_to(pos + 2))));
-        }
-
-        // Then ask for more until a complete line is found
         loop {
+            // First, empty the current buffer
+            if let Some(end) = self.line_end() {
+                let mut line = self.buf.split_to(end);
+                let started_after_crlf = self.after_crlf;
+                self.after_crlf = line.ends_with(b"\r\n");
+                self.strict_line = false;
+                if self.discarding || line.len() > self.max_line_len {
+                    self.discarding = false;
+                    return Ok(Ready(Some(Line::TooLong)));
+                }
+                if !self.after_crlf {
+                    self.saw_bare_lf = true;
+                    line.truncate(end - 1);
+                    line.extend_from_slice(b"\r\n");
+                }
+                let pos = line.len() - 2;
+                let bare = line[..pos].iter().position(|&c| c == b'\r' || c == b'\n');
+                return Ok(Ready(Some(match (bare, self.line_endings) {
+                    (None, _) | (_, LineEndings::PassThrough) => {
+                        self.strict_line = started_after_crlf && self.after_crlf;
+                        Line::Complete(line)
+                    }
+                    (Some(_), LineEndings::Reject) | (Some(_), LineEndings::Lenient) => {
+                        Line::BareLineEnding
+                    }
+                    (Some(i), LineEndings::Normalize) => {
+                        // What follows the bare CR or LF is left for the next
+                        // line
+                        let mut rest = line.split_off(i + 1);
+                        rest.unsplit(mem::replace(&mut self.buf, BytesMut::new()));
+                        self.buf = rest;
+                        self.after_crlf = false;
+                        line.truncate(i);
+                        line.extend_from_slice(b"\r\n");
+                        Line::Complete(line)
+                    }
+                })));
+            }
+
+            // Past the limit, drop what has been received of the line, except for
+            // a trailing CR that may be the beginning of its CRLF
+            if self.buf.len() > self.max_line_len {
+                let keep = if self.buf.ends_with(b"\r") { 1 } else { 0 };
+                let len = self.buf.len();
+                self.buf.split_to(len - keep);
+                self.discarding = true;
+            }
+
+            // Then ask for more until a complete line is found
+            if self.eof {
+                return Ok(Ready(None)); // Drop self.buf
+            }
             match self.source.poll()? {
                 NotReady => return Ok(NotReady),
-                Ready(None) => return Ok(Ready(None)), // Drop self.buf
-                Ready(Some(b)) => {
-                    // TODO: (B) implement line length limits id:line-length-limit
-                    // TODO: (B) optimize searching for crlf p:line-length-limit
-                    // This can be done with much fewer allocations and searches through the buffer
-                    // Technique : do not extending the buffers straightaway but store them in a
-                    // vec until the CRLF is found, and then extending with the right size)
-                    // Other idea: just extend at line length limit
-                    self.buf.unsplit(b);
-                    if let Some(pos) = self.buf.windows(2).position(|x| x == b"\r\n") {
-                        return Ok(Ready(Some(self.buf.split_to(pos + 2))));
-                    }
-                }
+                Ready(None) => self.eof = true,
+                // TODO: (B) optimize searching for crlf p:line-length-limit
+                // This can be done with much fewer allocations and searches through the buffer
+                // Technique : do not extending the buffers straightaway but store them in a
+                // vec until the CRLF is found, and then extending with the right size)
+                Ready(Some(b)) => self.buf.unsplit(b),
             }
         }
     }
@@ -80,23 +234,157 @@ mod tests {
                     b"Hello World\r\n",
                     b".\r\n",
                     b"QUIT\r\n",
-                ].into_iter()
-                    .map(BytesMut::from),
-            ).map_err(|()| ())
-                .prependable(),
+                ]
+                .into_iter()
+                .map(BytesMut::from),
+            )
+            .map_err(|()| ())
+            .prependable(),
         );
 
         assert_eq!(
             stream.collect().wait().unwrap(),
             vec![
-                b"MAIL FROM:<foo@bar.example.org>\r\n".to_vec(),
-                b"RCPT TO:<baz@quux.example.org>\r\n".to_vec(),
-                b"RCPT TO:<foo2@bar.example.org>\r\n".to_vec(),
-                b"DATA\r\n".to_vec(),
-                b"Hello World\r\n".to_vec(),
-                b".\r\n".to_vec(),
-                b"QUIT\r\n".to_vec(),
+                &b"MAIL FROM:<foo@bar.example.org>\r\n"[..],
+                b"RCPT TO:<baz@quux.example.org>\r\n",
+                b"RCPT TO:<foo2@bar.example.org>\r\n",
+                b"DATA\r\n",
+                b"Hello World\r\n",
+                b".\r\n",
+                b"QUIT\r\n",
             ]
+            .into_iter()
+            .map(|l| Line::Complete(BytesMut::from(l)))
+            .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn crlflines_discards_long_lines() {
+        let tests: &[(&[&[u8]], &[Option<&[u8]>])] = &[
+            (&[b"0123456789\r\nfoo\r\n"], &[None, Some(b"foo\r\n")]),
+            (
+                &[b"01234567\r\nfoo\r\n"],
+                &[Some(b"01234567\r\n"), Some(b"foo\r\n")],
+            ),
+            (
+                &[b"0123", b"4567", b"89", b"0123", b"\r", b"\nfoo\r\n"],
+                &[None, Some(b"foo\r\n")],
+            ),
+            (&[b"0123456789", b"0123456789"], &[]),
+        ];
+        for &(inp, out) in tests {
+            let mut lines = CrlfLines::new(
+                stream::iter_ok(inp.iter().map(|x| BytesMut::from(*x)))
+                    .map_err(|()| ())
+                    .prependable(),
+            );
+            lines.set_max_line_len(10);
+            assert_eq!(
+                lines.collect().wait().unwrap(),
+                out.iter()
+                    .map(|l| match l {
+                        Some(l) => Line::Complete(BytesMut::from(*l)),
+                        None => Line::TooLong,
+                    })
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn crlflines_handles_bare_line_endings() {
+        let inp: &[u8] = b"foo\nbar\r\nbaz\rquux\r\r\n.\n.\r\nok\r\n";
+        let tests: &[(LineEndings, &[Option<&[u8]>])] = &[
+            (LineEndings::Reject, &[None, None, None, Some(b"ok\r\n")]),
+            (
+                LineEndings::Normalize,
+                &[
+                    Some(b"foo\r\n"),
+                    Some(b"bar\r\n"),
+                    Some(b"baz\r\n"),
+                    Some(b"quux\r\n"),
+                    Some(b"\r\n"),
+                    Some(b".\r\n"),
+                    Some(b".\r\n"),
+                    Some(b"ok\r\n"),
+                ],
+            ),
+            (
+                LineEndings::PassThrough,
+                &[
+                    Some(b"foo\nbar\r\n"),
+                    Some(b"baz\rquux\r\r\n"),
+                    Some(b".\n.\r\n"),
+                    Some(b"ok\r\n"),
+                ],
+            ),
+            (
+                LineEndings::Lenient,
+                &[
+                    Some(b"foo\r\n"),
+                    Some(b"bar\r\n"),
+                    None,
+                    Some(b".\r\n"),
+                    Some(b".\r\n"),
+                    Some(b"ok\r\n"),
+                ],
+            ),
+        ];
+        for &(line_endings, out) in tests {
+            let mut lines = CrlfLines::new(
+                stream::iter_ok(vec![BytesMut::from(inp)])
+                    .map_err(|()| ())
+                    .prependable(),
+            );
+            lines.set_line_endings(line_endings);
+            assert_eq!(
+                lines.by_ref().collect().wait().unwrap(),
+                out.iter()
+                    .map(|l| match l {
+                        Some(l) => Line::Complete(BytesMut::from(*l)),
+                        None => Line::BareLineEnding,
+                    })
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(lines.saw_bare_lf(), line_endings == LineEndings::Lenient);
+        }
+    }
+
+    #[test]
+    fn tells_strict_lines() {
+        let inp: &[u8] = b".\r\n.\n.\r\n.\r\na\r.\r\n";
+        let mut lines = CrlfLines::new(
+            stream::iter_ok(vec![BytesMut::from(inp)])
+                .map_err(|()| ())
+                .prependable(),
+        );
+        lines.set_line_endings(LineEndings::Normalize);
+        let strict = future::poll_fn(|| {
+            let mut strict = Vec::new();
+            while let Async::Ready(Some(_)) = lines.poll()? {
+                strict.push(lines.is_strict_line());
+            }
+            Ok::<_, ()>(Async::Ready(strict))
+        })
+        .wait()
+        .unwrap();
+        assert_eq!(strict, vec![true, false, false, true, false, false]);
+    }
+
+    #[test]
+    fn poll_available_stops_at_limit() {
+        let mut lines = CrlfLines::new(
+            stream::iter_ok(
+                vec![&b"NOOP\r\n"[..], b"NOOP\r\n", b"NOOP\r\n"]
+                    .into_iter()
+                    .map(BytesMut::from),
+            )
+            .map_err(|()| ())
+            .prependable(),
         );
+        lines.set_max_buffered(8);
+        future::lazy(|| lines.poll_available()).wait().unwrap();
+        assert_eq!(lines.buf.len(), 12);
     }
 }
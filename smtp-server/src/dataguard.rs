@@ -0,0 +1,455 @@
+use bytes::BytesMut;
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+use tokio::{prelude::*, timer::Delay};
+
+use crlflines::{CrlfLines, Line, LineEndings};
+use disconnect::DisconnectReason;
+use timeout::{deadline, Timeout};
+
+// Reasons for which the mail data could not be handed over in full to
+// `Config::handle_mail`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DataError {
+    LineTooLong,
+    TooBig,
+    // A line had a CR or LF outside of a CRLF, with `LineEndings::Reject`
+    BareLineEnding,
+    // The input is left in the middle of the data, so the session must end
+    Timeout(Timeout),
+}
+
+struct State<S: Stream<Item = BytesMut>> {
+    lines: Option<CrlfLines<S>>,
+    // Whether the final ".\r\n" line has been read
+    finished: bool,
+    error: Option<DataError>,
+    // Bytes of data read so far, and limit past which the data is discarded
+    size: usize,
+    max_size: usize,
+    delay: Delay,
+    timeout: Timeout,
+    block_time: Duration,
+    session_end: Option<Instant>,
+}
+
+// Stream of the lines of the mail data, up to and including the final ".\r\n"
+// line, that is handed over to `DataStream`. Only a "." line between two
+// actual CRLFs ends the data, except with `LineEndings::Lenient`, where lines
+// all start after a line ending and any "." line does.
+//
+// The input stays shared with the session, so that it can be recovered even
+// though the data has been cut short for breaking a limit. In this case, the
+// rest of the data is discarded up to the final ".\r\n", and the stream ends
+// without it, so that `DataStream` reports the data as incomplete.
+//
+// The first line is waited for until the deadline given to `new`, and each
+// next one for `block_time` after the previous one.
+pub struct DataGuard<S: Stream<Item = BytesMut>>(Rc<RefCell<State<S>>>);
+
+impl<S: Stream<Item = BytesMut>> Clone for DataGuard<S> {
+    fn clone(&self) -> DataGuard<S> {
+        DataGuard(self.0.clone())
+    }
+}
+
+impl<S: Stream<Item = BytesMut>> DataGuard<S> {
+    pub fn new(
+        lines: CrlfLines<S>,
+        (end, timeout): (Instant, Timeout),
+        block_time: Duration,
+        session_end: Option<Instant>,
+    ) -> DataGuard<S> {
+        DataGuard(Rc::new(RefCell::new(State {
+            lines: Some(lines),
+            finished: false,
+            error: None,
+            size: 0,
+            max_size: usize::max_value(),
+            delay: Delay::new(end),
+            timeout,
+            block_time,
+            session_end,
+        })))
+    }
+
+    pub fn set_max_size(&self, max: usize) {
+        self.0.borrow_mut().max_size = max;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.0.borrow().finished
+    }
+
+    // Resolves once `timeout` has elapsed after the end of the data
+    pub fn termination(&self, timeout: Duration) -> impl Future<Item = (), Error = ()> {
+        let guard = self.clone();
+        let mut delay = None;
+        future::poll_fn(move || {
+            if delay.is_none() {
+                if !guard.is_finished() {
+                    // The guard can only finish while the data is being read,
+                    // which happens in the same task
+                    return Ok(Async::NotReady);
+                }
+                delay = Some(Delay::new(Instant::now() + timeout));
+            }
+            delay.as_mut().unwrap().poll().map_err(|_| ())
+        })
+    }
+
+    // Discards whatever was not consumed of the data, and gives back the input
+    // along with the limit that was broken, if any. Fails with the reason for
+    // ending the session if the input ended or failed before the end of the
+    // data, unless it timed out.
+    pub fn finish(
+        self,
+    ) -> impl Future<Item = (CrlfLines<S>, Option<DataError>), Error = DisconnectReason> {
+        self.clone()
+            .for_each(|_| Ok(()))
+            .map_err(|_| DisconnectReason::IoError)
+            .and_then(move |()| {
+                let mut state = self.0.borrow_mut();
+                let lines = state.lines.take().expect("finished a DataGuard twice");
+                let timed_out = match state.error {
+                    Some(DataError::Timeout(_)) => true,
+                    _ => false,
+                };
+                if state.finished || timed_out {
+                    Ok((lines, state.error))
+                } else {
+                    Err(DisconnectReason::Eof)
+                }
+            })
+    }
+}
+
+impl<S: Stream<Item = BytesMut>> Stream for DataGuard<S> {
+    type Item = BytesMut;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<BytesMut>, S::Error> {
+        let mut state = self.0.borrow_mut();
+        let state = &mut *state;
+        if state.finished {
+            return Ok(Async::Ready(None));
+        }
+        if let Some(DataError::Timeout(_)) = state.error {
+            return Ok(Async::Ready(None));
+        }
+        let lines = state.lines.as_mut().expect("polled a finished DataGuard");
+        loop {
+            let line = match lines.poll()? {
+                Async::NotReady => {
+                    // Timer errors, eg. for lack of a running timer, count as
+                    // timeouts
+                    if let Ok(Async::NotReady) = state.delay.poll() {
+                        return Ok(Async::NotReady);
+                    }
+                    state.error = Some(DataError::Timeout(state.timeout));
+                    return Ok(Async::Ready(None));
+                }
+                Async::Ready(line) => line,
+            };
+            let (end, timeout) = deadline(state.block_time, Timeout::DataBlock, state.session_end);
+            state.delay.reset(end);
+            state.timeout = timeout;
+            match line {
+                None => return Ok(Async::Ready(None)),
+                Some(Line::TooLong) => {
+                    state.error = state.error.or(Some(DataError::LineTooLong));
+                }
+                Some(Line::BareLineEnding) => {
+                    state.error = state.error.or(Some(DataError::BareLineEnding));
+                }
+                Some(Line::Complete(mut line)) => {
+                    let lenient = lines.line_endings() == LineEndings::Lenient;
+                    if &line[..] == b".\r\n" && !lines.is_strict_line() && !lenient {
+                        // Unless lenient, a `.` line started or ended by a
+                        // bare CR or LF is part of the data, and is passed on
+                        // dot-stuffed so that `DataStream` does not end there
+                        // either
+                        line = BytesMut::from(&b"..\r\n"[..]);
+                    } else if &line[..] == b".\r\n" {
+                        state.finished = true;
+                        if state.error.is_some() {
+                            return Ok(Async::Ready(None));
+                        }
+                        return Ok(Async::Ready(Some(line)));
+                    }
+                    state.size += line.len();
+                    if state.size > state.max_size {
+                        state.error = state.error.or(Some(DataError::TooBig));
+                    }
+                    if state.error.is_none() {
+                        return Ok(Async::Ready(Some(line)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smtp_message::StreamExt;
+
+    use tokio::runtime::current_thread::Runtime;
+
+    fn guard<S: Stream<Item = BytesMut, Error = ()>>(source: S, max: usize) -> DataGuard<S> {
+        let mut lines = CrlfLines::new(source.prependable());
+        lines.set_max_line_len(max);
+        let minute = Duration::from_secs(60);
+        let end = Instant::now() + minute;
+        DataGuard::new(lines, (end, Timeout::DataInit), minute, None)
+    }
+
+    fn chunks(inp: &'static [&'static [u8]]) -> impl Stream<Item = BytesMut, Error = ()> {
+        stream::iter_ok(inp.iter().map(|x| BytesMut::from(*x)))
+    }
+
+    #[test]
+    fn stops_at_end_of_data() {
+        let g = guard(chunks(&[b"Hello\r\n", b"..\r\n.\r\nQUIT\r\n"]), 10);
+        assert_eq!(
+            g.clone().collect().wait().unwrap(),
+            vec![&b"Hello\r\n"[..], b"..\r\n", b".\r\n"]
+        );
+        let (lines, error) = g.finish().wait().unwrap();
+        assert_eq!(error, None);
+        assert_eq!(
+            lines.collect().wait().unwrap(),
+            vec![Line::Complete(BytesMut::from(&b"QUIT\r\n"[..]))]
+        );
+    }
+
+    #[test]
+    fn discards_data_after_long_line() {
+        let g = guard(
+            chunks(&[b"Hello\r\n0123456789\r\nWorld\r\n.\r\nQUIT\r\n"]),
+            10,
+        );
+        assert_eq!(g.clone().collect().wait().unwrap(), vec![&b"Hello\r\n"[..]]);
+        let (lines, error) = g.finish().wait().unwrap();
+        assert_eq!(error, Some(DataError::LineTooLong));
+        assert_eq!(
+            lines.collect().wait().unwrap(),
+            vec![Line::Complete(BytesMut::from(&b"QUIT\r\n"[..]))]
+        );
+    }
+
+    #[test]
+    fn discards_data_past_max_size() {
+        let g = guard(chunks(&[b"Hello\r\nWorld\r\nFoo\r\n.\r\nQUIT\r\n"]), 10);
+        g.set_max_size(14);
+        assert_eq!(
+            g.clone().collect().wait().unwrap(),
+            vec![&b"Hello\r\n"[..], b"World\r\n"]
+        );
+        let (lines, error) = g.finish().wait().unwrap();
+        assert_eq!(error, Some(DataError::TooBig));
+        assert_eq!(lines.collect().wait().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn discards_data_with_bare_line_endings() {
+        let source = chunks(&[b"Hello\r\nfoo\n.\nbar\r\n.\r\nQUIT\r\n"]);
+        let mut lines = CrlfLines::new(source.prependable());
+        lines.set_line_endings(LineEndings::Reject);
+        let end = Instant::now() + Duration::from_secs(60);
+        let g = DataGuard::new(
+            lines,
+            (end, Timeout::DataInit),
+            Duration::from_secs(60),
+            None,
+        );
+        assert_eq!(g.clone().collect().wait().unwrap(), vec![&b"Hello\r\n"[..]]);
+        let (lines, error) = g.finish().wait().unwrap();
+        assert_eq!(error, Some(DataError::BareLineEnding));
+        assert_eq!(
+            lines.collect().wait().unwrap(),
+            vec![Line::Complete(BytesMut::from(&b"QUIT\r\n"[..]))]
+        );
+    }
+
+    // Splits `inp` into the data and what follows it at the first CRLF.CRLF,
+    // the data itself starting at the beginning of a line
+    fn crlf_framing(inp: &[u8]) -> (&[u8], &[u8]) {
+        let mut framed = b"\r\n".to_vec();
+        framed.extend_from_slice(inp);
+        let end = framed
+            .windows(5)
+            .position(|x| x == b"\r\n.\r\n")
+            .expect("no end of data")
+            + 3;
+        (&inp[..end], &inp[end..])
+    }
+
+    // Splits `inp` at the first `.` line ending in a LF, whether or not the LF
+    // is part of a CRLF
+    fn lenient_framing(inp: &[u8]) -> (&[u8], &[u8]) {
+        let end = (0..inp.len())
+            .filter(|&i| i == 0 || inp[i - 1] == b'\n')
+            .filter_map(|i| {
+                let line = &inp[i..];
+                if line.starts_with(b".\n") {
+                    Some(i + 2)
+                } else if line.starts_with(b".\r\n") {
+                    Some(i + 3)
+                } else {
+                    None
+                }
+            })
+            .next()
+            .expect("no end of data");
+        (&inp[..end], &inp[end..])
+    }
+
+    // Frames `inp` as the mail data followed by commands, and returns the
+    // data read with `line_endings` along with its error and the lines after it
+    fn framing(inp: &[u8], line_endings: LineEndings) -> (Vec<u8>, Option<DataError>, Vec<u8>) {
+        let source = stream::iter_ok(vec![BytesMut::from(inp)]);
+        let mut lines = CrlfLines::new(source.prependable());
+        lines.set_line_endings(line_endings);
+        let end = Instant::now() + Duration::from_secs(60);
+        let g = DataGuard::new(
+            lines,
+            (end, Timeout::DataInit),
+            Duration::from_secs(60),
+            None,
+        );
+        let data = g.clone().concat2().wait().unwrap();
+        let (mut lines, error) = g.finish().wait().unwrap();
+        lines.set_line_endings(LineEndings::PassThrough);
+        let rest = lines
+            .map(|l| match l {
+                Line::Complete(l) => l,
+                _ => unreachable!(),
+            })
+            .concat2()
+            .wait()
+            .unwrap();
+        (data.to_vec(), error, rest.to_vec())
+    }
+
+    // Builds a terminated mail data out of the characters that matter for
+    // framing
+    fn mail_data(v: Vec<u8>) -> Vec<u8> {
+        let mut res = v
+            .into_iter()
+            .map(|c| b"\r\n.a"[c as usize % 4])
+            .collect::<Vec<u8>>();
+        res.extend_from_slice(b"\r\n.\r\n");
+        res
+    }
+
+    fn has_bare_line_ending(data: &[u8]) -> bool {
+        data.iter().enumerate().any(|(i, &c)| match c {
+            b'\r' => data.get(i + 1) != Some(&b'\n'),
+            b'\n' => i == 0 || data[i - 1] != b'\r',
+            _ => false,
+        })
+    }
+
+    quickcheck! {
+        fn frames_at_crlf_dot_crlf_when_rejecting(v: Vec<u8>) -> bool {
+            let inp = mail_data(v);
+            let (data, rest) = crlf_framing(&inp);
+            let (our_data, error, our_rest) = framing(&inp, LineEndings::Reject);
+            our_rest == rest && if has_bare_line_ending(data) {
+                error == Some(DataError::BareLineEnding)
+            } else {
+                error.is_none() && our_data == data
+            }
+        }
+
+        fn frames_at_crlf_dot_crlf_when_passing_through(v: Vec<u8>) -> bool {
+            let inp = mail_data(v);
+            let (data, _) = crlf_framing(&inp);
+            framing(&inp, LineEndings::PassThrough).0 == data
+        }
+
+        fn normalizes_to_crlf(v: Vec<u8>) -> bool {
+            let inp = mail_data(v);
+            let (_, rest) = crlf_framing(&inp);
+            let (data, error, our_rest) = framing(&inp, LineEndings::Normalize);
+            error.is_none() && !has_bare_line_ending(&data) && our_rest == rest
+        }
+
+        fn lenient_framing_yields_crlf(v: Vec<u8>) -> bool {
+            let inp = mail_data(v);
+            let (_, rest) = lenient_framing(&inp);
+            let (data, _, our_rest) = framing(&inp, LineEndings::Lenient);
+            !has_bare_line_ending(&data) && our_rest == rest
+        }
+    }
+
+    // Sequences that some servers take as the end of the data, followed by a
+    // smuggled mail
+    #[test]
+    fn ends_only_on_crlf_dot_crlf() {
+        let tests: &[(&[u8], &[u8])] = &[
+            (b"\n.\n", b"MAIL FROM:<>\r\n.\r\nQUIT\r\n"),
+            (b"\r.\r", b"QUIT\r\n"),
+            (b"\r\n.\n", b"MAIL FROM:<>\r\n.\r\nQUIT\r\n"),
+            (b"\n.\r\n", b"MAIL FROM:<>\r\n.\r\nQUIT\r\n"),
+            (b"\r.\r\n", b"QUIT\r\n"),
+        ];
+        for &(end, lenient_rest) in tests {
+            let mut inp = b"Hello".to_vec();
+            inp.extend_from_slice(end);
+            inp.extend_from_slice(b"MAIL FROM:<>\r\n.\r\nQUIT\r\n");
+            for &line_endings in &[
+                LineEndings::Reject,
+                LineEndings::Normalize,
+                LineEndings::PassThrough,
+            ] {
+                let (_, _, rest) = framing(&inp, line_endings);
+                assert_eq!(&rest[..], b"QUIT\r\n", "{:?} {:?}", end, line_endings);
+            }
+            let (_, _, rest) = framing(&inp, LineEndings::Lenient);
+            assert_eq!(&rest[..], lenient_rest, "{:?}", end);
+        }
+    }
+
+    #[test]
+    fn finish_drains_unconsumed_data() {
+        let g = guard(chunks(&[b"Hello\r\nWorld\r\n.\r\nQUIT\r\n"]), 10);
+        let (lines, error) = g.finish().wait().unwrap();
+        assert_eq!(error, None);
+        assert_eq!(lines.collect().wait().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn finish_fails_on_early_eof() {
+        assert_eq!(
+            guard(chunks(&[b"Hello\r\n"]), 10).finish().wait().err(),
+            Some(DisconnectReason::Eof)
+        );
+    }
+
+    #[test]
+    fn times_out() {
+        let source = chunks(&[b"Hello\r\n"]).chain(stream::poll_fn(|| Ok(Async::NotReady)));
+        let mut lines = CrlfLines::new(source.prependable());
+        lines.set_max_line_len(10);
+        let end = Instant::now() + Duration::from_secs(60);
+        let g = DataGuard::new(
+            lines,
+            (end, Timeout::DataInit),
+            Duration::from_millis(10),
+            None,
+        );
+        let mut rt = Runtime::new().unwrap();
+        assert_eq!(
+            rt.block_on(g.clone().collect()).unwrap(),
+            vec![&b"Hello\r\n"[..]]
+        );
+        let (_, error) = rt.block_on(g.finish()).unwrap();
+        assert_eq!(error, Some(DataError::Timeout(Timeout::DataBlock)));
+    }
+}
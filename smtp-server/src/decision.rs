@@ -1,12 +1,83 @@
 use smtp_message::{ReplyCode, SmtpString};
+use std::time::{Duration, Instant};
+use tokio::{
+    prelude::{future::Either, *},
+    timer::Delay,
+};
 
-// TODO: (B) merge into Decision<T> id:J6HX
+use disconnect::DisconnectReason;
+
+// A refusal with a 4xx code is a temporary failure, that the client will retry
+// later, while one with a 5xx code is permanent. The message must be fit for a
+// reply (see `is_reply_text`).
+#[derive(Clone)]
 pub struct Refusal {
     pub code: ReplyCode,
     pub msg:  SmtpString,
 }
 
+// Whether `text` can be sent in a reply, that only allows tabs and printable
+// ASCII characters
+pub fn is_reply_text(text: &[u8]) -> bool {
+    text.iter().all(|&c| c == b'\t' || (c >= b' ' && c <= b'~'))
+}
+
+#[derive(Clone)]
 pub enum Decision {
     Accept,
+    // Accepts, with this text in place of the default one in the reply (eg. a
+    // queue ID)
+    AcceptWith(SmtpString),
     Reject(Refusal),
+    // Rejects, then closes the connection. The code should be 421 or 554.
+    RejectAndClose(Refusal),
+    // Applies the decision only after some time, to slow down the client
+    Delay(Duration, Box<Decision>),
+}
+
+// What `interact` does after having sent the reply to a decision
+pub enum Outcome {
+    Accepted,
+    Rejected,
+    Closed(DisconnectReason),
+}
+
+// Waits as long as `decision` asks for, and resolves to the decision to apply
+// then, which is not a `Decision::Delay`
+pub fn wait_decision(decision: Decision) -> impl Future<Item = Decision, Error = ()> {
+    let mut wait = Duration::from_secs(0);
+    let mut decision = decision;
+    let decision = loop {
+        decision = match decision {
+            Decision::Delay(d, next) => {
+                wait += d;
+                *next
+            }
+            d => break d,
+        };
+    };
+    if wait == Duration::from_secs(0) {
+        Either::A(future::ok(decision))
+    } else {
+        Either::B(
+            Delay::new(Instant::now() + wait)
+                .map_err(|_| ())
+                .map(move |()| decision),
+        )
+    }
+}
+
+// Returns the reply to send for `decision`, given the default reply for when
+// it accepts
+pub fn decision_reply(
+    decision: Decision,
+    (code, text): (ReplyCode, SmtpString),
+) -> ((ReplyCode, SmtpString), Outcome) {
+    match decision {
+        Decision::Accept => ((code, text), Outcome::Accepted),
+        Decision::AcceptWith(text) => ((code, text), Outcome::Accepted),
+        Decision::Reject(r) => ((r.code, r.msg), Outcome::Rejected),
+        Decision::RejectAndClose(r) => ((r.code, r.msg), Outcome::Closed(DisconnectReason::Policy)),
+        Decision::Delay(_, d) => decision_reply(*d, (code, text)),
+    }
 }
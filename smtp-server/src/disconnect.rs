@@ -0,0 +1,19 @@
+use timeout::Timeout;
+
+// Why a session ended, as told to `Config::on_disconnect`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DisconnectReason {
+    // The client sent QUIT
+    Quit,
+    // The client closed the connection
+    Eof,
+    // Reading from the client failed
+    IoError,
+    // The client was too slow, and got a 421 reply
+    Timeout(Timeout),
+    // The server is shutting down, and the client got a 421 reply
+    Shutdown,
+    // The configuration decided to close the connection after refusing a
+    // command
+    Policy,
+}
@@ -0,0 +1,75 @@
+use smtp_message::SmtpString;
+use std::{io, net::IpAddr};
+use tokio::prelude::*;
+
+// DNS resolver used by the policies that look names up, to be provided by the
+// user. A name that does not exist resolves to no record, failures (eg.
+// timeouts) being for the other errors.
+pub trait Resolver: 'static {
+    fn lookup_ip(&self, name: &str) -> Box<Future<Item = Vec<IpAddr>, Error = io::Error>>;
+
+    fn lookup_txt(&self, name: &str) -> Box<Future<Item = Vec<SmtpString>, Error = io::Error>>;
+
+    // Names the address resolves to with PTR records
+    fn lookup_ptr(&self, ip: IpAddr) -> Box<Future<Item = Vec<String>, Error = io::Error>>;
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // Resolver answering with fixed records, and failing for the names (or
+    // addresses, for PTR lookups) in `failing`
+    #[derive(Default)]
+    pub struct FakeResolver {
+        pub ips:     HashMap<&'static str, Vec<IpAddr>>,
+        pub txts:    HashMap<&'static str, Vec<SmtpString>>,
+        pub ptrs:    HashMap<IpAddr, Vec<&'static str>>,
+        pub failing: Vec<&'static str>,
+    }
+
+    impl FakeResolver {
+        fn fail(&self, name: &str) -> bool {
+            self.failing.iter().any(|f| name.eq_ignore_ascii_case(f))
+        }
+    }
+
+    impl Resolver for FakeResolver {
+        fn lookup_ip(&self, name: &str) -> Box<Future<Item = Vec<IpAddr>, Error = io::Error>> {
+            if self.fail(name) {
+                return Box::new(future::err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "SERVFAIL",
+                )));
+            }
+            let ips = self.ips.get(&name.to_ascii_lowercase()[..]).cloned();
+            Box::new(future::ok(ips.unwrap_or_else(Vec::new)))
+        }
+
+        fn lookup_txt(&self, name: &str) -> Box<Future<Item = Vec<SmtpString>, Error = io::Error>> {
+            if self.fail(name) {
+                return Box::new(future::err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "SERVFAIL",
+                )));
+            }
+            let txts = self.txts.get(&name.to_ascii_lowercase()[..]).cloned();
+            Box::new(future::ok(txts.unwrap_or_else(Vec::new)))
+        }
+
+        fn lookup_ptr(&self, ip: IpAddr) -> Box<Future<Item = Vec<String>, Error = io::Error>> {
+            if self.fail(&ip.to_string()) {
+                return Box::new(future::err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "SERVFAIL",
+                )));
+            }
+            let names = self
+                .ptrs
+                .get(&ip)
+                .map(|n| n.iter().map(|n| n.to_string()).collect());
+            Box::new(future::ok(names.unwrap_or_else(Vec::new)))
+        }
+    }
+}
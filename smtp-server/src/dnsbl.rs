@@ -0,0 +1,354 @@
+use smtp_message::{Email, ReplyCode, SmtpString};
+use std::{net::IpAddr, rc::Rc, str};
+use tokio::prelude::{future::Either, *};
+
+use chain::{Flow, Layer};
+use decision::{is_reply_text, Decision, Refusal};
+use dns::Resolver;
+use metadata::ConnectionMetadata;
+
+#[derive(Clone)]
+struct List {
+    zone:   String,
+    weight: i32,
+}
+
+// Heaviest of the lists that matched a lookup, and the name looked up in it
+struct Listing {
+    name: String,
+    zone: String,
+}
+
+// Score of the client alone, kept for adding the sender's to it
+struct ClientScore(i32);
+
+// Longest part of a TXT record quoted in a refusal
+const MAX_TXT_LEN: usize = 256;
+
+// DNS blocklist policy, that looks the client address up in the DNSBLs at
+// connection time and the domain of the sender in the RHSBLs on MAIL FROM,
+// all the lists of a kind being queried at once. The weights of the lists
+// that match add up into `ConnectionMetadata::dnsbl_score`, and the client or
+// sender is refused once the score reaches the threshold, with the
+// explanation published by the heaviest list matching.
+//
+// Lookups that fail count as not matching.
+#[derive(Clone)]
+pub struct Dnsbl {
+    resolver:  Rc<Resolver>,
+    threshold: i32,
+    dnsbls:    Vec<List>,
+    rhsbls:    Vec<List>,
+}
+
+impl Dnsbl {
+    pub fn new(resolver: Rc<Resolver>, threshold: i32) -> Dnsbl {
+        Dnsbl {
+            resolver,
+            threshold,
+            dnsbls: Vec::new(),
+            rhsbls: Vec::new(),
+        }
+    }
+
+    // Adds a list of client addresses, eg. `zen.spamhaus.org`
+    pub fn dnsbl(mut self, zone: &str, weight: i32) -> Dnsbl {
+        self.dnsbls.push(List {
+            zone: zone.to_owned(),
+            weight,
+        });
+        self
+    }
+
+    // Adds a list of domains, eg. `dbl.spamhaus.org`
+    pub fn rhsbl(mut self, zone: &str, weight: i32) -> Dnsbl {
+        self.rhsbls.push(List {
+            zone: zone.to_owned(),
+            weight,
+        });
+        self
+    }
+
+    // Looks `name` up in all the `lists`, resolving to the sum of the weights
+    // of the ones that matched, and to the heaviest of them (the first one
+    // added, for equal weights)
+    fn lookup(
+        &self,
+        lists: &[List],
+        name: &str,
+    ) -> impl Future<Item = (i32, Option<Listing>), Error = ()> {
+        let lookups = lists
+            .iter()
+            .map(|l| {
+                let name = format!("{}.{}", name, l.zone);
+                let (zone, weight) = (l.zone.clone(), l.weight);
+                self.resolver.lookup_ip(&name).then(move |res| {
+                    let listed = res.map(|ips| ips.iter().any(is_listing)).unwrap_or(false);
+                    Ok::<_, ()>(if listed {
+                        Some((weight, Listing { name, zone }))
+                    } else {
+                        None
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        future::join_all(lookups).map(|hits| {
+            let hits = hits.into_iter().filter_map(|h| h).collect::<Vec<_>>();
+            let score = hits.iter().map(|(w, _)| w).sum();
+            let heaviest = hits.into_iter().rev().max_by_key(|(w, _)| *w);
+            (score, heaviest.map(|(_, l)| l))
+        })
+    }
+
+    // Refuses if the score reaches the threshold, quoting the first TXT record
+    // the list has for the name. As records can hold any byte, those that
+    // cannot be sent in a reply are replaced with `?`.
+    fn judge(
+        &self,
+        score: i32,
+        listing: Option<Listing>,
+        code: ReplyCode,
+        what: String,
+    ) -> impl Future<Item = Flow, Error = ()> {
+        let listing = match listing {
+            Some(listing) if score >= self.threshold => listing,
+            _ => return Either::A(future::ok(Flow::Next)),
+        };
+        Either::B(self.resolver.lookup_txt(&listing.name).then(move |txts| {
+            let mut msg = format!("5.7.1 {} blocked using {}", what, listing.zone).into_bytes();
+            if let Some(txt) = txts.ok().and_then(|t| t.into_iter().next()) {
+                msg.extend_from_slice(b"; ");
+                msg.extend(txt.bytes().iter().take(MAX_TXT_LEN).map(|&c| {
+                    if is_reply_text(&[c]) {
+                        c
+                    } else {
+                        b'?'
+                    }
+                }));
+            }
+            let msg = SmtpString::from(msg);
+            Ok(Flow::Stop(Decision::Reject(Refusal { code, msg })))
+        }))
+    }
+}
+
+impl<U: 'static> Layer<U> for Dnsbl {
+    fn on_connect(
+        &self,
+        mut conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (ConnectionMetadata<U>, Flow), Error = ()>> {
+        let ip = match conn_meta.peer_addr {
+            Some(addr) if !self.dnsbls.is_empty() => addr.ip(),
+            _ => return Box::new(future::ok((conn_meta, Flow::Next))),
+        };
+        let this = self.clone();
+        Box::new(
+            self.lookup(&self.dnsbls, &reverse_name(ip))
+                .and_then(move |(score, listing)| {
+                    conn_meta.dnsbl_score = score;
+                    conn_meta.state.insert(ClientScore(score));
+                    // RFC 5321 § 3.1 calls for a 554 in place of the banner
+                    let what = format!("Service unavailable; client [{}]", ip);
+                    this.judge(score, listing, ReplyCode::TRANSACTION_FAILED, what)
+                        .map(move |flow| (conn_meta, flow))
+                }),
+        )
+    }
+
+    fn filter_from(
+        &self,
+        from: Option<Email>,
+        mut conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Option<Email>, ConnectionMetadata<U>, Flow), Error = ()>> {
+        let client = conn_meta.state.get::<ClientScore>().map(|s| s.0);
+        conn_meta.dnsbl_score = client.unwrap_or(0);
+        let domain = match from.as_ref().and_then(sender_domain) {
+            Some(domain) if !self.rhsbls.is_empty() => domain,
+            _ => return Box::new(future::ok((from, conn_meta, Flow::Next))),
+        };
+        let this = self.clone();
+        Box::new(
+            self.lookup(&self.rhsbls, &domain)
+                .and_then(move |(score, listing)| {
+                    let score = client.unwrap_or(0) + score;
+                    conn_meta.dnsbl_score = score;
+                    let what = format!("Sender address rejected; domain {}", domain);
+                    this.judge(score, listing, ReplyCode::POLICY_REASON, what)
+                        .map(move |flow| (from, conn_meta, flow))
+                }),
+        )
+    }
+}
+
+// Name of the address in the lists: the octets of IPv4 addresses and the
+// nibbles of IPv6 ones, in reverse order
+fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            format!("{}.{}.{}.{}", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .rev()
+            .map(|b| format!("{:x}.{:x}", b & 0xf, b >> 4))
+            .collect::<Vec<_>>()
+            .join("."),
+    }
+}
+
+// Lists answer with addresses in 127.0.0.0/8, but for 127.255.255.0/24 that
+// some use for telling about errors (eg. for queries from public resolvers)
+fn is_listing(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            o[0] == 127 && !(o[1] == 255 && o[2] == 255)
+        }
+        IpAddr::V6(_) => false,
+    }
+}
+
+// Domain of the sender, unless it is an address literal
+fn sender_domain(from: &Email) -> Option<String> {
+    let domain = SmtpString::from_sendable(from.hostname().as_ref()?).ok()?;
+    let domain = str::from_utf8(&domain.bytes()[..]).ok()?;
+    if domain.starts_with('[') {
+        None
+    } else {
+        Some(domain.to_ascii_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::current_thread::Runtime;
+
+    use dns::tests::FakeResolver;
+    use testutil::refusal;
+
+    fn dnsbl() -> Dnsbl {
+        let mut resolver = FakeResolver::default();
+        let listed = vec!["127.0.0.2".parse().unwrap()];
+        resolver
+            .ips
+            .insert("1.2.0.192.bl.example.org", listed.clone());
+        resolver
+            .ips
+            .insert("1.2.0.192.weak.example.org", listed.clone());
+        resolver
+            .ips
+            .insert("2.2.0.192.weak.example.org", listed.clone());
+        resolver.ips.insert(
+            "3.2.0.192.bl.example.org",
+            vec!["127.255.255.254".parse().unwrap()],
+        );
+        resolver.ips.insert(
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.bl.example.org",
+            listed.clone(),
+        );
+        resolver
+            .ips
+            .insert("4.2.0.192.bl.example.org", listed.clone());
+        resolver
+            .ips
+            .insert("4.2.0.192.weak.example.org", listed.clone());
+        resolver
+            .ips
+            .insert("spam.example.com.dbl.example.org", listed);
+        resolver.txts.insert(
+            "1.2.0.192.bl.example.org",
+            vec!["See https://bl.example.org/192.0.2.1".into()],
+        );
+        let mut txt = b"Listed\r\n250 ok caf\xc3\xa9\0".to_vec();
+        txt.extend_from_slice(&[b'x'; 300][..]);
+        resolver
+            .txts
+            .insert("4.2.0.192.bl.example.org", vec![SmtpString::from(txt)]);
+        resolver.failing.push("1.2.0.192.failing.example.org");
+        Dnsbl::new(Rc::new(resolver), 3)
+            .dnsbl("weak.example.org", 1)
+            .dnsbl("bl.example.org", 2)
+            .dnsbl("failing.example.org", 5)
+            .rhsbl("dbl.example.org", 2)
+    }
+
+    fn connect(rt: &mut Runtime, ip: &str) -> (ConnectionMetadata<()>, Option<(u16, String)>) {
+        let mut conn_meta = ConnectionMetadata::new(());
+        conn_meta.peer_addr = Some(format!("{}:25", ip).parse().unwrap());
+        let (conn_meta, flow) = rt.block_on(dnsbl().on_connect(conn_meta)).unwrap();
+        (conn_meta, refusal(&flow))
+    }
+
+    #[test]
+    fn reverses_addresses() {
+        assert_eq!(reverse_name("192.0.2.1".parse().unwrap()), "1.2.0.192");
+        assert_eq!(
+            reverse_name("2001:db8::1".parse().unwrap()),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2"
+        );
+    }
+
+    #[test]
+    fn scores_clients() {
+        let mut rt = Runtime::new().unwrap();
+        let (conn_meta, refused) = connect(&mut rt, "192.0.2.1");
+        assert_eq!(conn_meta.dnsbl_score, 3);
+        assert_eq!(
+            refused,
+            Some((
+                554,
+                "5.7.1 Service unavailable; client [192.0.2.1] blocked using bl.example.org; \
+                 See https://bl.example.org/192.0.2.1"
+                    .to_owned()
+            ))
+        );
+
+        let (conn_meta, refused) = connect(&mut rt, "192.0.2.2");
+        assert_eq!((conn_meta.dnsbl_score, refused), (1, None));
+        let (conn_meta, refused) = connect(&mut rt, "192.0.2.3");
+        assert_eq!((conn_meta.dnsbl_score, refused), (0, None));
+
+        let (conn_meta, refused) = connect(&mut rt, "[2001:db8::1]");
+        assert_eq!(conn_meta.dnsbl_score, 2);
+        assert!(refused.is_none());
+    }
+
+    #[test]
+    fn quotes_only_printable_txt() {
+        let mut rt = Runtime::new().unwrap();
+        let (_, refused) = connect(&mut rt, "192.0.2.4");
+        let mut expected = "5.7.1 Service unavailable; client [192.0.2.4] blocked using \
+                            bl.example.org; Listed??250 ok caf???"
+            .to_owned();
+        expected.push_str(&"x".repeat(MAX_TXT_LEN - 21));
+        assert_eq!(refused, Some((554, expected)));
+    }
+
+    #[test]
+    fn adds_up_sender_domains() {
+        let mut rt = Runtime::new().unwrap();
+        let (conn_meta, _) = connect(&mut rt, "192.0.2.2");
+        let from = Some(Email::parse_slice(b"foo@example.org").unwrap());
+        let (_, conn_meta, flow) = rt.block_on(dnsbl().filter_from(from, conn_meta)).unwrap();
+        assert_eq!((conn_meta.dnsbl_score, refusal(&flow)), (1, None));
+
+        let from = Some(Email::parse_slice(b"foo@Spam.example.com").unwrap());
+        let (_, conn_meta, flow) = rt.block_on(dnsbl().filter_from(from, conn_meta)).unwrap();
+        assert_eq!(conn_meta.dnsbl_score, 3);
+        assert_eq!(
+            refusal(&flow),
+            Some((
+                550,
+                "5.7.1 Sender address rejected; domain spam.example.com blocked using \
+                 dbl.example.org"
+                    .to_owned()
+            ))
+        );
+
+        let (_, conn_meta, flow) = rt.block_on(dnsbl().filter_from(None, conn_meta)).unwrap();
+        assert_eq!((conn_meta.dnsbl_score, refusal(&flow)), (1, None));
+    }
+}
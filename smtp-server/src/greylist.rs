@@ -0,0 +1,491 @@
+use smtp_message::{Email, ReplyCode, SmtpString};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    rc::Rc,
+    str,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::prelude::*;
+
+use chain::{Flow, Layer};
+use decision::{Decision, Refusal};
+use metadata::{ConnectionMetadata, MailMetadata};
+
+// Times are in seconds since the epoch, as stored in the file
+struct Triplet {
+    first:  u64,
+    // Last time the triplet was let through, once the client retried
+    passed: Option<u64>,
+}
+
+struct Client {
+    passed: usize,
+    last:   u64,
+}
+
+#[derive(Default)]
+struct Store {
+    // Keyed on the client network, the sender and the recipient
+    triplets: HashMap<(Vec<u8>, Vec<u8>, Vec<u8>), Triplet>,
+    clients:  HashMap<Vec<u8>, Client>,
+    path:     Option<PathBuf>,
+    // Whether some changes are not in the file yet, and when it was last
+    // written
+    unsaved:  bool,
+    saved:    u64,
+}
+
+// Minimum seconds between two writes of the file, as it is written in full
+// from the event loop. Losing the changes since the last one only makes a few
+// clients retry again, or entries expire a little early.
+const SAVE_INTERVAL: u64 = 60;
+
+// Greylisting policy (see RFC 6647), that temporarily refuses the first mail
+// from a client network, sender and recipient, and lets it through once the
+// client retried after `delay` (but before `retry_window` is over). The
+// triplets that passed, and the clients that passed `auto_whitelist` of them,
+// are then let through without delay as long as they are seen again within
+// `lifetime`.
+//
+// Clones share the same store, so that a single `Greylist` can be used by all
+// the connections, as a `Layer` or from `Config::filter_to` with `check`.
+#[derive(Clone)]
+pub struct Greylist {
+    store: Rc<RefCell<Store>>,
+    delay: Duration,
+    retry_window: Duration,
+    lifetime: Duration,
+    auto_whitelist: Option<usize>,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+}
+
+impl Greylist {
+    // Greylist kept in memory only, with the defaults of postgrey
+    pub fn new() -> Greylist {
+        Greylist {
+            store: Rc::new(RefCell::new(Store::default())),
+            delay: Duration::from_secs(5 * 60),
+            retry_window: Duration::from_secs(2 * 24 * 3600),
+            lifetime: Duration::from_secs(35 * 24 * 3600),
+            auto_whitelist: Some(5),
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+        }
+    }
+
+    // Greylist stored in the file at `path`, that is read if it exists, and
+    // rewritten at most once a minute after the checks that change it, and
+    // when the last clone is dropped
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Greylist> {
+        let path = path.as_ref().to_owned();
+        let mut store = match fs::read(&path) {
+            Ok(content) => Store::parse(&content)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Store::default(),
+            Err(e) => return Err(e),
+        };
+        store.path = Some(path);
+        Ok(Greylist {
+            store: Rc::new(RefCell::new(store)),
+            ..Greylist::new()
+        })
+    }
+
+    // Time the client has to wait before retrying
+    pub fn delay(mut self, delay: Duration) -> Greylist {
+        self.delay = delay;
+        self
+    }
+
+    // Time after the delay during which a retry lets the triplet through,
+    // after which it is greylisted anew
+    pub fn retry_window(mut self, window: Duration) -> Greylist {
+        self.retry_window = window;
+        self
+    }
+
+    // Time after which the triplets and clients that were not seen again are
+    // forgotten
+    pub fn lifetime(mut self, lifetime: Duration) -> Greylist {
+        self.lifetime = lifetime;
+        self
+    }
+
+    // Number of triplets a client network has to pass to be let through
+    // without greylisting, if any
+    pub fn auto_whitelist(mut self, passed: Option<usize>) -> Greylist {
+        self.auto_whitelist = passed;
+        self
+    }
+
+    // Sizes of the networks the clients are grouped in, as big senders retry
+    // from other addresses than the first one
+    pub fn prefixes(mut self, ipv4: u8, ipv6: u8) -> Greylist {
+        assert!(ipv4 <= 32 && ipv6 <= 128);
+        self.ipv4_prefix = ipv4;
+        self.ipv6_prefix = ipv6;
+        self
+    }
+
+    // Either accepts the mail from the client at `ip`, or refuses it with a
+    // 451 reply
+    pub fn check(&self, ip: IpAddr, from: &Option<Email>, to: &Email) -> Decision {
+        self.check_at(SystemTime::now(), ip, from, to)
+    }
+
+    fn check_at(&self, now: SystemTime, ip: IpAddr, from: &Option<Email>, to: &Email) -> Decision {
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let net = network(ip, self.ipv4_prefix, self.ipv6_prefix).into_bytes();
+        let addr = |e: &Option<Email>| {
+            let e = SmtpString::from_sendable(e).unwrap();
+            let mut res = vec![b'<'];
+            res.extend_from_slice(&e.bytes()[..]);
+            res.push(b'>');
+            res
+        };
+        let key = (net.clone(), addr(from), addr(&Some(to.clone())));
+        let mut store = self.store.borrow_mut();
+        let window = self
+            .delay
+            .as_secs()
+            .saturating_add(self.retry_window.as_secs());
+        let expired = store.expire(now, window, self.lifetime.as_secs());
+        let (passed, changed) = self.check_store(&mut store, now, net, key);
+        store.unsaved |= expired || changed;
+        if store.unsaved && now >= store.saved.saturating_add(SAVE_INTERVAL) {
+            // Failing to save only loses what changed since the last time, the
+            // greylist in memory staying right, and it is tried again after
+            // the same interval
+            store.saved = now;
+            store.unsaved = store.save().is_err();
+        }
+        if passed {
+            Decision::Accept
+        } else {
+            Decision::Reject(Refusal {
+                code: ReplyCode::LOCAL_ERROR,
+                msg:  SmtpString::from_static(b"4.7.1 Greylisted, please try again later"),
+            })
+        }
+    }
+
+    fn check_store(
+        &self,
+        store: &mut Store,
+        now: u64,
+        net: Vec<u8>,
+        key: (Vec<u8>, Vec<u8>, Vec<u8>),
+    ) -> (bool, bool) {
+        let whitelisted = match (store.clients.get_mut(&net), self.auto_whitelist) {
+            (Some(client), Some(min)) if client.passed >= min => {
+                client.last = now;
+                true
+            }
+            _ => false,
+        };
+        if whitelisted {
+            return (true, true);
+        }
+        let mut changed = false;
+        let triplet = store.triplets.entry(key).or_insert_with(|| {
+            changed = true;
+            Triplet {
+                first:  now,
+                passed: None,
+            }
+        });
+        if let Some(ref mut passed) = triplet.passed {
+            *passed = now;
+            return (true, true);
+        }
+        if now < triplet.first.saturating_add(self.delay.as_secs()) {
+            return (false, changed);
+        }
+        triplet.passed = Some(now);
+        let client = store.clients.entry(net).or_insert(Client {
+            passed: 0,
+            last:   now,
+        });
+        client.passed = client.passed.saturating_add(1);
+        client.last = now;
+        (true, true)
+    }
+}
+
+impl Default for Greylist {
+    fn default() -> Greylist {
+        Greylist::new()
+    }
+}
+
+impl<U: 'static> Layer<U> for Greylist {
+    fn filter_to(
+        &self,
+        to: Email,
+        meta: MailMetadata,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Email, MailMetadata, ConnectionMetadata<U>, Flow), Error = ()>> {
+        let flow = match conn_meta.peer_addr {
+            Some(addr) => match self.check(addr.ip(), &meta.from, &to) {
+                Decision::Accept => Flow::Next,
+                decision => Flow::Stop(decision),
+            },
+            // There is nothing to key the local clients on
+            None => Flow::Next,
+        };
+        Box::new(future::ok((to, meta, conn_meta, flow)))
+    }
+}
+
+impl Store {
+    // Forgets the triplets whose retry window is over, and the triplets and
+    // clients not seen for `lifetime`, returning whether there were any. The
+    // times, in seconds, may come from a file edited by hand, and thus be
+    // anything.
+    fn expire(&mut self, now: u64, window: u64, lifetime: u64) -> bool {
+        let entries = self.triplets.len() + self.clients.len();
+        self.triplets.retain(|_, t| match t.passed {
+            Some(passed) => passed.saturating_add(lifetime) >= now,
+            None => t.first.saturating_add(window) >= now,
+        });
+        self.clients
+            .retain(|_, c| c.last.saturating_add(lifetime) >= now);
+        self.triplets.len() + self.clients.len() != entries
+    }
+
+    // One line per entry, with tab-separated fields, that can appear in
+    // neither the addresses nor the networks:
+    //   T <network> <sender> <recipient> <first seen> <last passed or ->
+    //   C <network> <triplets passed> <last seen>
+    fn parse(content: &[u8]) -> io::Result<Store> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid greylist file");
+        let num = |f: &[u8]| -> io::Result<u64> {
+            str::from_utf8(f)
+                .ok()
+                .and_then(|f| f.parse().ok())
+                .ok_or_else(invalid)
+        };
+        let mut store = Store::default();
+        for line in content.split(|&c| c == b'\n').filter(|l| !l.is_empty()) {
+            let fields = line.split(|&c| c == b'\t').collect::<Vec<_>>();
+            match &fields[..] {
+                [b"T", net, from, to, first, passed] => {
+                    let passed = match *passed {
+                        b"-" => None,
+                        passed => Some(num(passed)?),
+                    };
+                    let key = (net.to_vec(), from.to_vec(), to.to_vec());
+                    let first = num(first)?;
+                    store.triplets.insert(key, Triplet { first, passed });
+                }
+                [b"C", net, passed, last] => {
+                    let passed = num(passed)? as usize;
+                    let last = num(last)?;
+                    store.clients.insert(net.to_vec(), Client { passed, last });
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(store)
+    }
+
+    // Writes to a temporary file first, so that the store is never left half
+    // written
+    fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut content = Vec::new();
+        for ((net, from, to), t) in self.triplets.iter() {
+            content.extend_from_slice(b"T\t");
+            for f in &[net, from, to] {
+                content.extend_from_slice(f);
+                content.push(b'\t');
+            }
+            let passed = t.passed.map(|p| p.to_string());
+            let passed = passed.as_ref().map(|p| &p[..]).unwrap_or("-");
+            content.extend_from_slice(format!("{}\t{}\n", t.first, passed).as_bytes());
+        }
+        for (net, c) in self.clients.iter() {
+            content.extend_from_slice(b"C\t");
+            content.extend_from_slice(net);
+            content.extend_from_slice(format!("\t{}\t{}\n", c.passed, c.last).as_bytes());
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        if self.unsaved {
+            let _ = self.save();
+        }
+    }
+}
+
+fn network(ip: IpAddr, ipv4_prefix: u8, ipv6_prefix: u8) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = (!0u32).checked_shl(32 - ipv4_prefix as u32).unwrap_or(0);
+            let net = Ipv4Addr::from(u32::from(ip) & mask);
+            format!("{}/{}", net, ipv4_prefix)
+        }
+        IpAddr::V6(ip) => {
+            let mask = (!0u128).checked_shl(128 - ipv6_prefix as u32).unwrap_or(0);
+            let net = Ipv6Addr::from(u128::from(ip) & mask);
+            format!("{}/{}", net, ipv6_prefix)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use testutil::{email, TempFile};
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_000_000 + secs)
+    }
+
+    // An empty sender stands for the null one
+    fn passes(g: &Greylist, secs: u64, ip: &str, from: &str, to: &str) -> bool {
+        let from = Some(from).filter(|f| !f.is_empty()).map(email);
+        match g.check_at(at(secs), ip.parse().unwrap(), &from, &email(to)) {
+            Decision::Accept => true,
+            Decision::Reject(r) => {
+                assert_eq!(r.code.code(), 451);
+                assert_eq!(r.msg, "4.7.1 Greylisted, please try again later".into());
+                false
+            }
+            _ => panic!("unexpected decision"),
+        }
+    }
+
+    fn greylist() -> Greylist {
+        Greylist::new()
+            .delay(Duration::from_secs(60))
+            .retry_window(Duration::from_secs(3600))
+            .lifetime(Duration::from_secs(86400))
+            .auto_whitelist(Some(2))
+    }
+
+    #[test]
+    fn lets_retries_through() {
+        let g = greylist();
+        let (from, to) = ("foo@example.org", "bar@example.com");
+        assert!(!passes(&g, 0, "192.0.2.1", from, to));
+        assert!(!passes(&g, 30, "192.0.2.1", from, to));
+        // Retrying from elsewhere in the same network is a retry all the same
+        assert!(passes(&g, 90, "192.0.2.42", from, to));
+        assert!(passes(&g, 100, "192.0.2.1", from, to));
+        assert!(!passes(&g, 110, "192.0.2.1", from, "baz@example.com"));
+        assert!(!passes(&g, 120, "198.51.100.1", from, to));
+        assert!(passes(&g, 100 + 86400, "192.0.2.1", from, to));
+        // Forgotten after a lifetime without being seen
+        assert!(!passes(&g, 100 + 2 * 86400 + 1, "192.0.2.1", from, to));
+    }
+
+    #[test]
+    fn greylists_late_retries_anew() {
+        let g = greylist();
+        let (from, to) = ("foo@example.org", "bar@example.com");
+        assert!(!passes(&g, 0, "2001:db8::1", from, to));
+        assert!(!passes(&g, 60 + 3600 + 1, "2001:db8::1", from, to));
+        assert!(passes(&g, 60 + 3600 + 61, "2001:db8::2", from, to));
+    }
+
+    #[test]
+    fn whitelists_clients_that_retried() {
+        let g = greylist();
+        let from = "foo@example.org";
+        for (i, to) in ["a@example.com", "b@example.com"].iter().enumerate() {
+            let t = 1000 * i as u64;
+            assert!(!passes(&g, t, "192.0.2.1", from, to));
+            assert!(passes(&g, t + 60, "192.0.2.1", from, to));
+        }
+        assert!(passes(
+            &g,
+            2000,
+            "192.0.2.1",
+            "bar@example.org",
+            "c@example.com"
+        ));
+        assert!(!passes(&g, 2000, "198.51.100.1", from, "c@example.com"));
+
+        let g = greylist().auto_whitelist(None);
+        assert!(!passes(&g, 0, "192.0.2.1", from, "a@example.com"));
+        assert!(passes(&g, 60, "192.0.2.1", from, "a@example.com"));
+        assert!(!passes(&g, 60, "192.0.2.1", from, "b@example.com"));
+    }
+
+    #[test]
+    fn persists_to_a_file() {
+        let file = TempFile::new("greylist");
+        let path = file.path();
+        let (from, to) = ("foo@example.org", "bar@example.com");
+        let g = Greylist::open(path).unwrap().delay(Duration::from_secs(60));
+        assert!(!passes(&g, 0, "192.0.2.1", from, to));
+        assert!(!passes(&g, 10, "192.0.2.1", "", to));
+        drop(g);
+
+        let g = Greylist::open(path).unwrap().delay(Duration::from_secs(60));
+        assert!(passes(&g, 60, "192.0.2.1", from, to));
+        assert!(!passes(&g, 61, "192.0.2.1", from, "baz@example.com"));
+        assert!(passes(&g, 70, "192.0.2.1", "", to));
+        drop(g);
+
+        let g = Greylist::open(path).unwrap().delay(Duration::from_secs(60));
+        assert!(passes(&g, 72, "192.0.2.1", from, to));
+        // Changes are then saved only a minute after the last save, or when
+        // the greylist is dropped
+        fs::remove_file(path).unwrap();
+        assert!(passes(&g, 73, "192.0.2.1", from, to));
+        assert!(!passes(&g, 74, "192.0.2.1", from, "qux@example.com"));
+        assert!(!path.exists());
+        assert!(passes(&g, 132, "192.0.2.1", from, to));
+        assert!(path.exists());
+        assert!(!passes(&g, 133, "192.0.2.1", from, "quux@example.com"));
+        drop(g);
+
+        let g = Greylist::open(path).unwrap().delay(Duration::from_secs(60));
+        assert!(passes(&g, 134, "192.0.2.1", from, "qux@example.com"));
+        assert!(passes(&g, 193, "192.0.2.1", from, "quux@example.com"));
+        drop(g);
+        fs::write(path, b"T\t192.0.2.0/24\t<>\n").unwrap();
+        assert!(Greylist::open(path).is_err());
+    }
+
+    #[test]
+    fn saturates_huge_times() {
+        let file = TempFile::new("greylist-huge");
+        let max = u64::max_value();
+        let content = format!(
+            "T\t192.0.2.0/24\t<foo@example.org>\t<bar@example.com>\t{}\t-\nC\t198.51.100.0/24\t{}\\
+             \
+             t{}\n",
+            max, max, max
+        );
+        fs::write(file.path(), content).unwrap();
+        let g = Greylist::open(file.path())
+            .unwrap()
+            .retry_window(Duration::from_secs(max))
+            .lifetime(Duration::from_secs(max));
+        let (from, to) = ("foo@example.org", "bar@example.com");
+        assert!(!passes(&g, 0, "192.0.2.1", from, to));
+        assert!(passes(&g, 0, "198.51.100.1", from, to));
+        assert!(!passes(&g, 0, "203.0.113.1", from, to));
+        assert!(passes(&g, 60 * 5, "203.0.113.1", from, to));
+    }
+}
@@ -1,15 +1,29 @@
 use bytes::{BufMut, Bytes, BytesMut};
 use smtp_message::{
-    Command, DataStream, MailCommand, Prependable, RcptCommand, ReplyLine, StreamExt,
+    Attributes, Command, DataStream, Email, ParseError, RcptCommand, ReplyCode, ReplyLine,
+    SmtpString, StreamExt,
+};
+use std::time::{Duration, Instant};
+use tokio::{
+    prelude::{
+        future::{Either, Loop},
+        *,
+    },
+    timer::Delay,
 };
-use tokio::prelude::{future::Either, *};
 
+use clientcheck::ReverseDns;
 use config::Config;
-use crlflines::CrlfLines;
-use decision::Decision;
-use metadata::{ConnectionMetadata, MailMetadata};
-use sendreply::send_reply;
-use stupidfut::FutIn9;
+use crlflines::{CrlfLines, Line};
+use dataguard::{DataError, DataGuard};
+use decision::{decision_reply, wait_decision, Decision, Outcome, Refusal};
+use disconnect::DisconnectReason;
+use metadata::{ConnectionMetadata, MailMetadata, State};
+use sendreply::{send_reply, send_reply_lines, send_reply_start};
+use shutdown::Shutdown;
+use stupidfut::FutIn4;
+use timeout::{deadline, next_line, Timeout};
+use xclient::{apply_xclient, apply_xforward, XCLIENT_ATTRIBUTES, XFORWARD_ATTRIBUTES};
 
 // TODO: (B) Allow Reader and Writer to return errors?
 pub fn interact<
@@ -24,7 +38,7 @@ pub fn interact<
     metadata: UserProvidedMetadata,
     cfg: Cfg,
 ) -> impl Future<Item = (), Error = ()> + 'a {
-    let conn_meta = ConnectionMetadata { user: metadata };
+    let conn_meta = ConnectionMetadata::new(metadata);
     let writer = outgoing.with(|c: ReplyLine| {
         let mut w = BytesMut::with_capacity(c.byte_len()).writer();
         // TODO: (B) refactor Sendable to send to a sink instead of to a Write
@@ -34,17 +48,379 @@ pub fn interact<
         // programming error, there's no need to try and handle this cleanly.
         future::ok(w.into_inner().freeze())
     });
-    send_reply(writer, cfg.welcome_banner()).and_then(|writer| {
-        CrlfLines::new(incoming.prependable())
-            .fold_with_stream((cfg, writer, conn_meta, None), move |acc, line, reader| {
-                handle_line(reader.into_inner(), acc, line).and_then(|(reader, acc)| {
-                    future::result(reader.ok_or(()).map(|read| (CrlfLines::new(read), acc)))
-                })
+    session(incoming, writer, conn_meta, cfg, None)
+}
+
+// Runs an SMTP session over a sink that directly accepts reply lines.
+//
+// Replies are only queued into `writer`, which is flushed only once there is no
+// complete command line left to handle in the input buffer, or after a
+// synchronizing command (RFC 2920). This way, the replies to pipelined
+// commands are coalesced into a single write if the sink does buffering.
+//
+// Once `shutdown` is triggered, the session ends the next time it waits for a
+// command.
+pub fn session<
+    'a,
+    Reader: 'a + Stream<Item = BytesMut, Error = ()>,
+    Writer: 'a + Sink<SinkItem = ReplyLine, SinkError = ()>,
+    U: 'static,
+    Cfg: Config<U>,
+>(
+    incoming: Reader,
+    writer: Writer,
+    conn_meta: ConnectionMetadata<U>,
+    cfg: Cfg,
+    shutdown: Option<Shutdown>,
+) -> impl Future<Item = (), Error = ()> + 'a {
+    let session_end = cfg.session_timeout().map(|t| Instant::now() + t);
+    let mut lines = CrlfLines::new(incoming.prependable());
+    lines.set_max_line_len(cfg.max_command_line_len());
+    lines.set_line_endings(cfg.command_line_endings());
+    lines.set_max_buffered(cfg.max_buffered_input());
+    open_session(lines, writer, conn_meta, cfg)
+        .and_then(move |start| {
+            let (lines, acc) = match start {
+                Ok(start) => start,
+                Err(res) => return Either::A(future::ok(res)),
+            };
+            Either::B(future::loop_fn(
+                (lines, acc, true),
+                move |(lines, acc, first)| {
+                    let (cfg, writer, conn_meta, mail_data) = acc;
+                    let wait = if first {
+                        (cfg.greeting_timeout(), Timeout::Greeting)
+                    } else if mail_data.is_some() {
+                        (cfg.rcpt_timeout(), Timeout::Rcpt)
+                    } else {
+                        (cfg.mail_timeout(), Timeout::Mail)
+                    };
+                    let end = deadline(wait.0, wait.1, session_end);
+                    next_line(lines, end, shutdown.clone()).and_then(move |(line, lines)| {
+                        let acc = (cfg, writer, conn_meta, mail_data);
+                        match line {
+                            Ok(None) => {
+                                Either::A(future::ok(Loop::Break((acc, DisconnectReason::Eof))))
+                            }
+                            Ok(Some(line)) => Either::B(Either::A(
+                                handle_line_pipelined(lines, acc, line, session_end).map(
+                                    |l| match l {
+                                        Loop::Continue((lines, acc)) => {
+                                            Loop::Continue((lines, acc, false))
+                                        }
+                                        Loop::Break(res) => Loop::Break(res),
+                                    },
+                                ),
+                            )),
+                            // There is no point in replying to a broken connection
+                            Err(DisconnectReason::IoError) => {
+                                Either::A(future::ok(Loop::Break((acc, DisconnectReason::IoError))))
+                            }
+                            Err(reason) => {
+                                let (cfg, writer, conn_meta, mail_data) = acc;
+                                let reply = match reason {
+                                    DisconnectReason::Shutdown => cfg.shutting_down(),
+                                    _ => cfg.timed_out(),
+                                };
+                                Either::B(Either::B(send_reply(writer, reply).map(move |writer| {
+                                    Loop::Break(((cfg, writer, conn_meta, mail_data), reason))
+                                })))
+                            }
+                        }
+                    })
+                },
+            ))
+        })
+        .and_then(|((cfg, writer, conn_meta, mail_data), reason)| {
+            // The configuration is told about the end of the session even if
+            // the last replies could not be sent, but the caller still gets
+            // the I/O errors
+            writer.flush().then(move |flushed| {
+                cfg.on_disconnect(reason, mail_data, conn_meta)
+                    .and_then(move |()| match (flushed, reason) {
+                        (Err(_), _) | (_, DisconnectReason::IoError) => Err(()),
+                        _ => Ok(()),
+                    })
             })
-            .map(|_acc| ()) // TODO: (B) warn of unfinished commands?
+        })
+}
+
+// Greets the client, unless `Config::on_connect` refuses it or it talks too
+// early, and resolves to the state to start the session with, or to why it is
+// already over
+fn open_session<
+    'a,
+    Reader: 'a + Stream<Item = BytesMut, Error = ()>,
+    Writer: 'a + Sink<SinkItem = ReplyLine, SinkError = ()>,
+    U: 'static,
+    Cfg: Config<U>,
+>(
+    lines: CrlfLines<Reader>,
+    writer: Writer,
+    conn_meta: ConnectionMetadata<U>,
+    cfg: Cfg,
+) -> impl Future<
+    Item = Result<
+        (
+            CrlfLines<Reader>,
+            (Cfg, Writer, ConnectionMetadata<U>, Option<MailMetadata>),
+        ),
+        (
+            (Cfg, Writer, ConnectionMetadata<U>, Option<MailMetadata>),
+            DisconnectReason,
+        ),
+    >,
+    Error = (),
+> + 'a {
+    // Check whether the client talked before being greeted, without waiting
+    // for it to do so
+    poll_talked(lines)
+        .and_then(move |(lines, talked_early)| {
+            check_client(cfg, conn_meta).map(move |checked| ((lines, talked_early), checked))
+        })
+        .and_then(move |((lines, talked_early), (cfg, conn_meta, checked))| {
+            let (banner, outcome) = checked;
+            match outcome {
+                Outcome::Accepted => (),
+                // The refusal is sent in place of the banner
+                _ => {
+                    return Either::A(send_reply(writer, banner).map(move |writer| {
+                        Err(((cfg, writer, conn_meta, None), DisconnectReason::Policy))
+                    }))
+                }
+            }
+            let banner_end = banner.clone();
+            Either::B(
+                match cfg.pregreet_delay() {
+                    None => Either::A(future::ok((lines, writer, talked_early))),
+                    // Like postscreen, send the beginning of the banner and
+                    // wait before ending it, which the client has to do before
+                    // talking
+                    Some(delay) => Either::B(
+                        send_reply_start(writer, banner)
+                            .and_then(|writer| writer.flush())
+                            .and_then(move |writer| {
+                                // Timer errors, eg. for lack of a running
+                                // timer, just cut the delay short
+                                Delay::new(Instant::now() + delay)
+                                    .then(|_| poll_talked(lines))
+                                    .map(move |(lines, talked)| {
+                                        (lines, writer, talked_early || talked)
+                                    })
+                            }),
+                    ),
+                }
+                .and_then(move |(lines, writer, talked_early)| {
+                    let mut conn_meta = conn_meta;
+                    conn_meta.illegal_pipelining = talked_early;
+                    conn_meta.early_talker = talked_early;
+                    if talked_early && cfg.reject_early_talkers() {
+                        return Either::A(send_reply(writer, cfg.early_talker()).map(
+                            move |writer| {
+                                Err(((cfg, writer, conn_meta, None), DisconnectReason::Policy))
+                            },
+                        ));
+                    }
+                    Either::B(
+                        send_reply(writer, banner_end)
+                            .and_then(|writer| writer.flush())
+                            .map(move |writer| Ok((lines, (cfg, writer, conn_meta, None)))),
+                    )
+                }),
+            )
+        })
+}
+
+// Runs `Config::on_connect`, and resolves to the banner along with whether the
+// client is accepted, the banner being the refusal if it is not
+fn check_client<U: 'static, Cfg: Config<U>>(
+    cfg: Cfg,
+    conn_meta: ConnectionMetadata<U>,
+) -> impl Future<
+    Item = (
+        Cfg,
+        ConnectionMetadata<U>,
+        ((ReplyCode, SmtpString), Outcome),
+    ),
+    Error = (),
+> {
+    cfg.on_connect(conn_meta)
+        .and_then(|(cfg, conn_meta, decision)| {
+            wait_decision(decision).map(move |d| (cfg, conn_meta, d))
+        })
+        .map(|(cfg, conn_meta, decision)| {
+            let checked = decision_reply(decision, cfg.welcome_banner());
+            (cfg, conn_meta, checked)
+        })
+}
+
+// Resolves to `lines` along with whether the client has already sent anything,
+// without waiting for it to do so
+fn poll_talked<S: Stream<Item = BytesMut, Error = ()>>(
+    lines: CrlfLines<S>,
+) -> impl Future<Item = (CrlfLines<S>, bool), Error = ()> {
+    let mut lines = Some(lines);
+    future::poll_fn(move || {
+        let talked = lines.as_mut().unwrap().poll_available()?;
+        Ok(Async::Ready((lines.take().unwrap(), talked)))
     })
 }
 
+// Commands after which the client must wait for the reply before sending
+// anything else (RFC 2920 § 3.1), and XCLIENT that acts as a new connection
+// TODO: (B) add BDAT once smtp_message parses it
+fn is_synchronizing(cmd: &Result<Command, ParseError>) -> bool {
+    match cmd {
+        Ok(Command::Data(_)) | Ok(Command::Ehlo(_)) | Ok(Command::Expn(_))
+        | Ok(Command::Helo(_)) | Ok(Command::Lhlo(_)) | Ok(Command::Noop(_))
+        | Ok(Command::Quit(_)) | Ok(Command::Vrfy(_)) => true,
+        Ok(Command::Xclient(_)) => true,
+        _ => false,
+    }
+}
+
+// Handles one line, and decides whether to flush the replies or to keep them
+// buffered until more commands have been handled
+fn handle_line_pipelined<
+    'a,
+    U: 'static,
+    Writer: 'a + Sink<SinkItem = ReplyLine, SinkError = ()>,
+    Reader: 'a + Stream<Item = BytesMut, Error = ()>,
+    Cfg: Config<U>,
+>(
+    reader: CrlfLines<Reader>,
+    (cfg, writer, mut conn_meta, mail_data): (
+        Cfg,
+        Writer,
+        ConnectionMetadata<U>,
+        Option<MailMetadata>,
+    ),
+    line: Line,
+    session_end: Option<Instant>,
+) -> impl Future<
+    Item = Loop<
+        (
+            (Cfg, Writer, ConnectionMetadata<U>, Option<MailMetadata>),
+            DisconnectReason,
+        ),
+        (
+            CrlfLines<Reader>,
+            (Cfg, Writer, ConnectionMetadata<U>, Option<MailMetadata>),
+        ),
+    >,
+    Error = (),
+> + 'a {
+    let cmd = match line {
+        Line::Complete(line) => Ok(Command::parse(line.freeze())),
+        Line::TooLong => Err(cfg.command_line_too_long()),
+        Line::BareLineEnding => Err(cfg.bare_line_ending()),
+    };
+    conn_meta.bare_lf |= reader.saw_bare_lf();
+    conn_meta.commands += 1;
+    let synchronizing = cmd.as_ref().map(is_synchronizing).unwrap_or(false);
+    if reader.has_buffered_line() && (synchronizing || !conn_meta.pipelining) {
+        conn_meta.illegal_pipelining = true;
+    }
+    handle_line(
+        reader,
+        (cfg, writer, conn_meta, mail_data),
+        cmd,
+        session_end,
+    )
+    .and_then(move |(lines, (cfg, writer, conn_meta, mail_data))| {
+        let (lines, limit) = match lines {
+            Ok(lines) => match broken_limit(&cfg, &conn_meta) {
+                Some(reply) => (Err(DisconnectReason::Policy), Some(reply)),
+                None => (Ok(lines), None),
+            },
+            Err(reason) => (Err(reason), None),
+        };
+        // Delaying the reply is done by delaying the flush
+        let delay = match lines {
+            Ok(_) => penalty(&cfg, &conn_meta),
+            Err(_) => Duration::from_secs(0),
+        };
+        let must_flush = match lines {
+            Ok(ref lines) => synchronizing || !lines.has_buffered_line(),
+            Err(_) => true,
+        };
+        match limit {
+            Some(reply) => Either::A(send_reply(writer, reply)),
+            None => Either::B(future::ok(writer)),
+        }
+        .and_then(move |writer| {
+            if delay > Duration::from_secs(0) {
+                Either::A(Delay::new(Instant::now() + delay).then(|_| writer.flush()))
+            } else if must_flush {
+                Either::B(Either::A(writer.flush()))
+            } else {
+                Either::B(Either::B(future::ok(writer)))
+            }
+        })
+        .map(move |writer| {
+            let acc = (cfg, writer, conn_meta, mail_data);
+            match lines {
+                Ok(lines) => Loop::Continue((lines, acc)),
+                Err(reason) => Loop::Break((acc, reason)),
+            }
+        })
+    })
+}
+
+// Returns the reply to send before closing the connection if the client
+// reached a hard limit on errors or commands
+fn broken_limit<U, Cfg: Config<U>>(
+    cfg: &Cfg,
+    conn_meta: &ConnectionMetadata<U>,
+) -> Option<(ReplyCode, SmtpString)> {
+    let reached = |count, limit: Option<usize>| limit.map_or(false, |l| count >= l);
+    if reached(
+        conn_meta.errors + conn_meta.rejected_rcpts,
+        cfg.hard_error_limit(),
+    ) {
+        Some(cfg.too_many_errors())
+    } else if reached(conn_meta.commands, cfg.hard_command_limit()) {
+        Some(cfg.too_many_commands())
+    } else {
+        None
+    }
+}
+
+// Returns how long to wait before replying, which grows with each error or
+// command past the soft limits
+fn penalty<U, Cfg: Config<U>>(cfg: &Cfg, conn_meta: &ConnectionMetadata<U>) -> Duration {
+    let past = |count: usize, limit: Option<usize>| limit.map_or(0, |l| count.saturating_sub(l));
+    let errors = past(
+        conn_meta.errors + conn_meta.rejected_rcpts,
+        cfg.soft_error_limit(),
+    );
+    let commands = past(conn_meta.commands, cfg.soft_command_limit());
+    cfg.error_delay() * (errors + commands) as u32
+}
+
+// Returns the list of extensions to advertise in the reply to EHLO
+fn ehlo_extensions<U, Cfg: Config<U>>(
+    cfg: &Cfg,
+    conn_meta: &ConnectionMetadata<U>,
+) -> Vec<SmtpString> {
+    let mut extensions = vec![SmtpString::from_static(b"PIPELINING")];
+    if let Some(max) = cfg.max_mail_size() {
+        extensions.push(SmtpString::from(format!("SIZE {}", max).into_bytes()));
+    }
+    if cfg.xclient_trusted(conn_meta) {
+        let ext = format!("XCLIENT {}", XCLIENT_ATTRIBUTES.join(" "));
+        extensions.push(SmtpString::from(ext.into_bytes()));
+    }
+    if cfg.xforward_trusted(conn_meta) {
+        let ext = format!("XFORWARD {}", XFORWARD_ATTRIBUTES.join(" "));
+        extensions.push(SmtpString::from(ext.into_bytes()));
+    }
+    extensions
+}
+
+// `cmd` is an error holding the reply to send for a line that could not even
+// be parsed (eg. for being too long). Returns why the connection is to be
+// closed in place of the reader if it is
 // TODO: (B) use async/await here hide:async-await-in-rust-and-tokio
 fn handle_line<
     'a,
@@ -53,173 +429,530 @@ fn handle_line<
     Reader: 'a + Stream<Item = BytesMut, Error = ()>,
     Cfg: Config<U>,
 >(
-    reader: Prependable<Reader>,
-    (cfg, writer, conn_meta, mail_data): (Cfg, Writer, ConnectionMetadata<U>, Option<MailMetadata>),
-    line: BytesMut,
+    reader: CrlfLines<Reader>,
+    (cfg, writer, mut conn_meta, mail_data): (
+        Cfg,
+        Writer,
+        ConnectionMetadata<U>,
+        Option<MailMetadata>,
+    ),
+    cmd: Result<Result<Command, ParseError>, (ReplyCode, SmtpString)>,
+    session_end: Option<Instant>,
 ) -> impl Future<
     Item = (
-        Option<Prependable<Reader>>,
+        Result<CrlfLines<Reader>, DisconnectReason>,
         (Cfg, Writer, ConnectionMetadata<U>, Option<MailMetadata>),
     ),
     Error = (),
->
-         + 'a {
-    let cmd = Command::parse(line.freeze());
-    match cmd {
-        Ok(Command::Mail(MailCommand {
-            from,
-            params: _params,
-        })) => {
+> + 'a {
+    // Name the client greets with, if it does
+    let helo = match cmd {
+        Ok(Ok(Command::Ehlo(ref c))) => SmtpString::from_sendable(c.domain()).ok(),
+        Ok(Ok(Command::Helo(ref c))) => SmtpString::from_sendable(c.domain()).ok(),
+        Ok(Ok(Command::Lhlo(ref c))) => SmtpString::from_sendable(c.domain()).ok(),
+        _ => None,
+    };
+    // The commands that only need a reply fall through to the end of the
+    // function
+    let (reply, mail_data, close) = match cmd {
+        Ok(Ok(Command::Mail(mail))) => {
+            let size = mail.size();
+            let too_big = match (size, cfg.max_mail_size()) {
+                (Ok(Some(size)), Some(max)) => size > max,
+                _ => false,
+            };
             if mail_data.is_some() {
-                FutIn9::Fut1(
-                    send_reply(writer, cfg.already_in_mail()).and_then(|writer| {
-                        future::ok((Some(reader), (cfg, writer, conn_meta, mail_data)))
-                    }),
-                )
+                (one_line(cfg.already_in_mail()), mail_data, None)
+            } else if cfg.lmtp() && !conn_meta.greeted {
+                (one_line(cfg.mail_before_lhlo()), None, None)
+            } else if size.is_err() {
+                (one_line(cfg.invalid_size()), None, None)
+            } else if too_big {
+                (one_line(cfg.mail_too_big()), None, None)
             } else {
-                FutIn9::Fut2(
+                let from = mail.from;
+                conn_meta.mail_state.clear();
+                return FutIn4::Fut1(
                     cfg.new_mail()
                         .and_then(|cfg| cfg.filter_from(from, conn_meta))
-                        .and_then(|(cfg, from, conn_meta, decision)| match decision {
-                            Decision::Accept => {
-                                let to = Vec::new();
-                                Either::A(send_reply(writer, cfg.mail_okay()).and_then(|writer| {
-                                    future::ok((
-                                        Some(reader),
-                                        (cfg, writer, conn_meta, Some(MailMetadata { from, to })),
-                                    ))
-                                }))
-                            }
-                            Decision::Reject(r) => Either::B(
-                                send_reply(writer, (r.code, r.msg.into())).and_then(|writer| {
-                                    future::ok((Some(reader), (cfg, writer, conn_meta, mail_data)))
-                                }),
-                            ),
+                        .and_then(|(cfg, from, conn_meta, decision)| {
+                            wait_decision(decision).map(move |d| (cfg, from, conn_meta, d))
+                        })
+                        .and_then(|(cfg, from, conn_meta, decision)| {
+                            let (reply, outcome) = decision_reply(decision, cfg.mail_okay());
+                            send_reply(writer, reply).map(move |writer| {
+                                let (reader, mail_data) = match outcome {
+                                    Outcome::Accepted => {
+                                        let to = Vec::new();
+                                        (Ok(reader), Some(MailMetadata { from, to }))
+                                    }
+                                    Outcome::Rejected => (Ok(reader), None),
+                                    Outcome::Closed(reason) => (Err(reason), None),
+                                };
+                                (reader, (cfg, writer, conn_meta, mail_data))
+                            })
                         }),
-                )
+                );
             }
         }
-        Ok(Command::Rcpt(RcptCommand {
+        Ok(Ok(Command::Rcpt(RcptCommand {
             to: rcpt_to,
             params: _params,
-        })) => {
-            if let Some(mail_meta) = mail_data {
-                FutIn9::Fut3(cfg.filter_to(rcpt_to, mail_meta, conn_meta).and_then(
-                    |(cfg, rcpt_to, mail_meta, conn_meta, decision)| match decision {
-                        Decision::Accept => {
-                            let MailMetadata { from, mut to } = mail_meta;
-                            to.push(rcpt_to);
-                            Either::A(send_reply(writer, cfg.rcpt_okay()).and_then(|writer| {
-                                future::ok((
-                                    Some(reader),
-                                    (cfg, writer, conn_meta, Some(MailMetadata { from, to })),
-                                ))
-                            }))
-                        }
-                        Decision::Reject(r) => {
-                            Either::B(send_reply(writer, (r.code, r.msg)).and_then(|writer| {
-                                future::ok((
-                                    Some(reader),
-                                    (cfg, writer, conn_meta, Some(mail_meta)),
-                                ))
-                            }))
-                        }
-                    },
-                ))
+        }))) => {
+            let full = match (&mail_data, conn_meta.max_rcpts.or(cfg.max_rcpts())) {
+                (Some(mail_meta), Some(max)) => mail_meta.to.len() >= max,
+                _ => false,
+            };
+            if full {
+                (one_line(cfg.too_many_rcpts()), mail_data, None)
+            } else if let Some(mail_meta) = mail_data {
+                return FutIn4::Fut2(
+                    cfg.filter_to(rcpt_to, mail_meta, conn_meta)
+                        .and_then(|(cfg, rcpt_to, mail_meta, conn_meta, decision)| {
+                            wait_decision(decision)
+                                .map(move |d| (cfg, rcpt_to, mail_meta, conn_meta, d))
+                        })
+                        .and_then(|(cfg, rcpt_to, mut mail_meta, mut conn_meta, decision)| {
+                            let (reply, outcome) = decision_reply(decision, cfg.rcpt_okay());
+                            let reader = match outcome {
+                                Outcome::Accepted => {
+                                    mail_meta.to.push(rcpt_to);
+                                    Ok(reader)
+                                }
+                                Outcome::Rejected => {
+                                    conn_meta.rejected_rcpts += 1;
+                                    Ok(reader)
+                                }
+                                Outcome::Closed(reason) => Err(reason),
+                            };
+                            send_reply(writer, reply).map(move |writer| {
+                                (reader, (cfg, writer, conn_meta, Some(mail_meta)))
+                            })
+                        }),
+                );
             } else {
-                FutIn9::Fut4(
-                    send_reply(writer, cfg.rcpt_before_mail()).and_then(|writer| {
-                        future::ok((Some(reader), (cfg, writer, conn_meta, mail_data)))
-                    }),
-                )
+                (one_line(cfg.rcpt_before_mail()), None, None)
             }
         }
-        Ok(Command::Data(_)) => {
-            if let Some(mail_meta) = mail_data {
-                if !mail_meta.to.is_empty() {
-                    FutIn9::Fut5(cfg.filter_data(mail_meta, conn_meta).and_then(
-                        |(cfg, mail_meta, conn_meta, decision)| match decision {
-                            Decision::Accept => Either::A(
-                                send_reply(writer, cfg.data_okay()).and_then(move |writer| {
-                                    cfg.handle_mail(DataStream::new(reader), mail_meta, conn_meta)
-                                        .and_then(|(cfg, reader, conn_meta, decision)| {
-                                            match decision {
-                                                Decision::Accept => Either::A(
-                                                    send_reply(writer, cfg.mail_accepted())
-                                                        .and_then(|writer| {
-                                                            future::ok((
-                                                                reader,
-                                                                (cfg, writer, conn_meta, None),
-                                                            ))
-                                                        }),
-                                                ),
-                                                Decision::Reject(r) => Either::B(
-                                                    send_reply(writer, (r.code, r.msg.into()))
-                                                        .and_then(|writer| {
-                                                            // Other mail systems (at least
-                                                            // postfix, OpenSMTPD and gmail) appear
-                                                            // to drop the state on an unsuccessful
-                                                            // DATA command (eg. too long).
-                                                            // Couldn't find the RFC reference
-                                                            // anywhere, though.
-                                                            future::ok((
-                                                                reader,
-                                                                (cfg, writer, conn_meta, None),
-                                                            ))
-                                                        }),
-                                                ),
-                                            }
-                                        })
-                                }),
-                            ),
-                            Decision::Reject(r) => Either::B(
-                                send_reply(writer, (r.code, r.msg.into())).and_then(|writer| {
-                                    future::ok((
-                                        Some(reader),
-                                        (cfg, writer, conn_meta, Some(mail_meta)),
-                                    ))
-                                }),
-                            ),
-                        },
-                    ))
+        Ok(Ok(Command::Data(_))) => match mail_data {
+            None => (one_line(cfg.data_before_mail()), None, None),
+            Some(mail_meta) => {
+                if mail_meta.to.is_empty() {
+                    (one_line(cfg.data_before_rcpt()), Some(mail_meta), None)
                 } else {
-                    FutIn9::Fut6(
-                        send_reply(writer, cfg.data_before_rcpt()).and_then(|writer| {
-                            future::ok((Some(reader), (cfg, writer, conn_meta, Some(mail_meta))))
-                        }),
-                    )
+                    return FutIn4::Fut3(
+                        cfg.filter_data(mail_meta, conn_meta)
+                            .and_then(|(cfg, mail_meta, conn_meta, decision)| {
+                                wait_decision(decision).map(move |d| (cfg, mail_meta, conn_meta, d))
+                            })
+                            .and_then(|(cfg, mail_meta, conn_meta, decision)| {
+                                let (reply, outcome) = decision_reply(decision, cfg.data_okay());
+                                let reader = match outcome {
+                                    Outcome::Accepted => {
+                                        return Either::A(receive_data(
+                                            reader,
+                                            (cfg, writer, conn_meta),
+                                            mail_meta,
+                                            reply,
+                                            session_end,
+                                        ))
+                                    }
+                                    Outcome::Rejected => Ok(reader),
+                                    Outcome::Closed(reason) => Err(reason),
+                                };
+                                Either::B(send_reply(writer, reply).map(move |writer| {
+                                    (reader, (cfg, writer, conn_meta, Some(mail_meta)))
+                                }))
+                            }),
+                    );
                 }
+            }
+        },
+        // LMTP replaces HELO and EHLO with LHLO
+        Ok(Ok(Command::Ehlo(_))) | Ok(Ok(Command::Helo(_))) if cfg.lmtp() => {
+            (one_line(cfg.command_unrecognized()), mail_data, None)
+        }
+        Ok(Ok(Command::Lhlo(_))) if !cfg.lmtp() => {
+            (one_line(cfg.command_unrecognized()), mail_data, None)
+        }
+        Ok(Ok(Command::Ehlo(_))) | Ok(Ok(Command::Lhlo(_))) => {
+            // The greetings implicitly abort any ongoing mail transaction
+            conn_meta.greeted = true;
+            conn_meta.pipelining = true;
+            conn_meta.helo = helo;
+            conn_meta.xforward = Attributes::default();
+            let (code, text) = if cfg.lmtp() {
+                cfg.lhlo_okay()
             } else {
-                FutIn9::Fut7(
-                    send_reply(writer, cfg.data_before_mail()).and_then(|writer| {
-                        future::ok((Some(reader), (cfg, writer, conn_meta, mail_data)))
-                    }),
-                )
+                cfg.ehlo_okay()
+            };
+            let mut texts = vec![text];
+            texts.extend(ehlo_extensions(&cfg, &conn_meta));
+            ((code, texts), None, None)
+        }
+        Ok(Ok(Command::Helo(_))) => {
+            conn_meta.greeted = true;
+            conn_meta.pipelining = false;
+            conn_meta.helo = helo;
+            conn_meta.xforward = Attributes::default();
+            (one_line(cfg.helo_okay()), None, None)
+        }
+        Ok(Ok(Command::Rset(_))) => {
+            conn_meta.xforward = Attributes::default();
+            (one_line(cfg.rset_okay()), None, None)
+        }
+        Ok(Ok(Command::Xclient(_))) | Ok(Ok(Command::Xforward(_))) if mail_data.is_some() => {
+            (one_line(cfg.already_in_mail()), mail_data, None)
+        }
+        Ok(Ok(Command::Xclient(xclient))) => {
+            if !cfg.xclient_trusted(&conn_meta) {
+                conn_meta.errors += 1;
+                (one_line(cfg.xclient_unauthorized()), None, None)
+            } else if apply_xclient(&xclient.attrs, &mut conn_meta).is_err() {
+                conn_meta.errors += 1;
+                (one_line(cfg.bad_xclient_attribute()), None, None)
+            } else {
+                // As for a new connection, the proxy then greets on behalf of
+                // the client, which `on_connect` checks again, the verdicts on
+                // the proxy being dropped
+                conn_meta.greeted = false;
+                conn_meta.pipelining = false;
+                conn_meta.xforward = Attributes::default();
+                conn_meta.reverse_dns = ReverseDns::Unchecked;
+                conn_meta.helo_check = None;
+                conn_meta.dnsbl_score = 0;
+                conn_meta.state = State::default();
+                return FutIn4::Fut4(Either::A(check_client(cfg, conn_meta).and_then(
+                    |(cfg, conn_meta, (banner, outcome))| {
+                        let reader = match outcome {
+                            Outcome::Accepted => Ok(reader),
+                            _ => Err(DisconnectReason::Policy),
+                        };
+                        send_reply(writer, banner)
+                            .map(move |writer| (reader, (cfg, writer, conn_meta, None)))
+                    },
+                )));
             }
         }
-        // TODO: (B) implement all the parsed commands and remove this case
-        Ok(_) => FutIn9::Fut8(
-            send_reply(writer, cfg.command_unimplemented())
-                .and_then(|writer| future::ok((Some(reader), (cfg, writer, conn_meta, mail_data)))),
-        ),
-        Err(_) => FutIn9::Fut9(
-            send_reply(writer, cfg.command_unrecognized())
-                .and_then(|writer| future::ok((Some(reader), (cfg, writer, conn_meta, mail_data)))),
+        Ok(Ok(Command::Xforward(xforward))) => {
+            if !cfg.xforward_trusted(&conn_meta) {
+                conn_meta.errors += 1;
+                (one_line(cfg.xclient_unauthorized()), None, None)
+            } else if apply_xforward(&xforward.attrs, &mut conn_meta).is_err() {
+                conn_meta.errors += 1;
+                (one_line(cfg.bad_xclient_attribute()), None, None)
+            } else {
+                (one_line(cfg.okay()), None, None)
+            }
+        }
+        Ok(Ok(Command::Noop(_))) => (one_line(cfg.noop_okay()), mail_data, None),
+        Ok(Ok(Command::Quit(_))) => (
+            one_line(cfg.quit_okay()),
+            mail_data,
+            Some(DisconnectReason::Quit),
         ),
+        // TODO: (B) implement all the parsed commands and remove this case
+        Ok(Ok(_)) => {
+            conn_meta.errors += 1;
+            (one_line(cfg.command_unimplemented()), mail_data, None)
+        }
+        Ok(Err(_)) => {
+            conn_meta.errors += 1;
+            (one_line(cfg.command_unrecognized()), mail_data, None)
+        }
+        Err(reply) => {
+            conn_meta.errors += 1;
+            (one_line(reply), mail_data, None)
+        }
+    };
+    FutIn4::Fut4(Either::B(send_reply_lines(writer, reply).and_then(
+        move |writer| {
+            let reader = match close {
+                None => Ok(reader),
+                Some(reason) => Err(reason),
+            };
+            future::ok((reader, (cfg, writer, conn_meta, mail_data)))
+        },
+    )))
+}
+
+fn one_line((code, text): (ReplyCode, SmtpString)) -> (ReplyCode, Vec<SmtpString>) {
+    (code, vec![text])
+}
+
+// Sends the 354 reply and hands the mail data over to `Config::handle_mail`
+fn receive_data<
+    'a,
+    U: 'static,
+    Writer: 'a + Sink<SinkItem = ReplyLine, SinkError = ()>,
+    Reader: 'a + Stream<Item = BytesMut, Error = ()>,
+    Cfg: Config<U>,
+>(
+    mut reader: CrlfLines<Reader>,
+    (cfg, writer, conn_meta): (Cfg, Writer, ConnectionMetadata<U>),
+    mail_meta: MailMetadata,
+    data_okay: (ReplyCode, SmtpString),
+    session_end: Option<Instant>,
+) -> impl Future<
+    Item = (
+        Result<CrlfLines<Reader>, DisconnectReason>,
+        (Cfg, Writer, ConnectionMetadata<U>, Option<MailMetadata>),
+    ),
+    Error = (),
+> + 'a {
+    reader.set_max_line_len(cfg.max_text_line_len());
+    reader.set_line_endings(cfg.data_line_endings());
+    let guard = DataGuard::new(
+        reader,
+        deadline(cfg.data_init_timeout(), Timeout::DataInit, session_end),
+        cfg.data_block_timeout(),
+        session_end,
+    );
+    if let Some(max) = cfg.max_mail_size() {
+        guard.set_max_size(max);
+    }
+    let termination_timeout = cfg.data_termination_timeout();
+    let timed_out = cfg.timed_out();
+    // The client will not send anything before having received the 354, so
+    // flush now instead of waiting for the input buffer to be drained
+    send_reply(writer, data_okay)
+        .and_then(|writer| writer.flush())
+        .and_then(move |writer| {
+            let stream = DataStream::new(guard.clone().prependable());
+            let mail = mail_meta.clone();
+            cfg.handle_mail_rcpts(stream, mail_meta, conn_meta)
+                .select2(guard.termination(termination_timeout))
+                .map_err(|_| ())
+                .and_then(move |res| match res {
+                    Either::A((handled, _)) => Either::A(future::ok((handled, writer, false))),
+                    // Let the client go right away, but the configuration is
+                    // still needed to end the session
+                    Either::B(((), handling)) => Either::B(
+                        send_reply(writer, timed_out)
+                            .and_then(|writer| writer.flush())
+                            .and_then(|writer| {
+                                handling.map(move |handled| (handled, writer, true))
+                            }),
+                    ),
+                })
+                .and_then(
+                    move |((cfg, _reader, conn_meta, decisions), writer, late)| {
+                        // The input is taken back from the guard, as `handle_mail`
+                        // cannot return it when the data was cut short. This fails
+                        // only after a connection closed in the middle of the data,
+                        // which leaves the mail unfinished.
+                        guard.finish().then(move |res| {
+                            Ok::<_, ()>(match res {
+                                Ok((reader, error)) => {
+                                    Ok(((reader, error, decisions, late), (cfg, writer, conn_meta)))
+                                }
+                                Err(reason) => Err((reason, (cfg, writer, conn_meta))),
+                            })
+                        })
+                    },
+                )
+                .and_then(|finished| {
+                    let ((mut reader, error, decisions, late), (cfg, writer, mut conn_meta)) =
+                        match finished {
+                            Ok(finished) => finished,
+                            Err((reason, acc)) => {
+                                let unfinished = Some(mail);
+                                return Either::A(future::ok((
+                                    Vec::new(),
+                                    Err(reason),
+                                    unfinished,
+                                    acc,
+                                )));
+                            }
+                        };
+                    conn_meta.bare_lf |= reader.saw_bare_lf();
+                    // The forwarded attributes were only for this mail
+                    conn_meta.xforward = Attributes::default();
+                    reader.set_max_line_len(cfg.max_command_line_len());
+                    reader.set_line_endings(cfg.command_line_endings());
+                    // An LMTP client expects a reply per recipient
+                    let rcpts = if cfg.lmtp() { mail.to.len() } else { 1 };
+                    let (replies, reader) = match (late, error) {
+                        (true, _) => (
+                            Vec::new(),
+                            Err(DisconnectReason::Timeout(Timeout::DataTermination)),
+                        ),
+                        (_, Some(DataError::Timeout(t))) => {
+                            (vec![cfg.timed_out()], Err(DisconnectReason::Timeout(t)))
+                        }
+                        (_, Some(DataError::LineTooLong)) => {
+                            (vec![cfg.text_line_too_long(); rcpts], Ok(reader))
+                        }
+                        (_, Some(DataError::TooBig)) => {
+                            (vec![cfg.mail_too_big(); rcpts], Ok(reader))
+                        }
+                        (_, Some(DataError::BareLineEnding)) => {
+                            (vec![cfg.data_bare_line_ending(); rcpts], Ok(reader))
+                        }
+                        // Tarpitting is only worth it for complete mails
+                        (_, None) => {
+                            let decisions = one_per_rcpt(decisions, mail.to.len());
+                            let waits = decisions.into_iter().map(wait_decision);
+                            return Either::B(Either::B(future::join_all(waits).and_then(
+                                move |decisions| {
+                                    let accepted = cfg.mail_accepted();
+                                    let (replies, failed, outcome) = if cfg.lmtp() {
+                                        let (replies, outcome) = lmtp_replies(decisions, accepted);
+                                        (replies, Vec::new(), outcome)
+                                    } else {
+                                        let (reply, failed, outcome) =
+                                            merge_decisions(decisions, &mail.to, accepted);
+                                        (vec![reply], failed, outcome)
+                                    };
+                                    let reader = match outcome {
+                                        Outcome::Closed(reason) => Err(reason),
+                                        _ => Ok(reader),
+                                    };
+                                    if failed.is_empty() {
+                                        Either::A(future::ok((cfg, conn_meta)))
+                                    } else {
+                                        Either::B(cfg.rcpts_failed(failed, mail, conn_meta))
+                                    }
+                                    .map(
+                                        move |(cfg, conn_meta)| {
+                                            (replies, reader, None, (cfg, writer, conn_meta))
+                                        },
+                                    )
+                                },
+                            )));
+                        }
+                    };
+                    Either::B(Either::A(future::ok((
+                        replies,
+                        reader,
+                        None,
+                        (cfg, writer, conn_meta),
+                    ))))
+                })
+                .and_then(|(replies, reader, mail_data, (cfg, writer, conn_meta))| {
+                    stream::iter_ok::<_, ()>(replies)
+                        .fold(writer, send_reply)
+                        .map(|writer| {
+                            // Other mail systems (at least postfix, OpenSMTPD and gmail)
+                            // appear to drop the state on an unsuccessful DATA command
+                            // (eg. too long). Couldn't find the RFC reference anywhere,
+                            // though.
+                            (reader, (cfg, writer, conn_meta, mail_data))
+                        })
+                })
+        })
+}
+
+// Fits the decisions of `Config::handle_mail_rcpts` to the recipients, should
+// it return a wrong number of them: the extra ones are dropped, and the
+// missing ones are temporary failures
+fn one_per_rcpt(mut decisions: Vec<Decision>, rcpts: usize) -> Vec<Decision> {
+    decisions.truncate(rcpts);
+    while decisions.len() < rcpts {
+        decisions.push(Decision::Reject(Refusal {
+            code: ReplyCode::LOCAL_ERROR,
+            msg:  SmtpString::from_static(b"4.3.0 Local error in processing"),
+        }));
+    }
+    decisions
+}
+
+// Returns the replies to send to an LMTP client for the decisions on each
+// recipient, the client handling the failures itself
+fn lmtp_replies(
+    decisions: Vec<Decision>,
+    accepted: (ReplyCode, SmtpString),
+) -> (Vec<(ReplyCode, SmtpString)>, Outcome) {
+    let mut close = None;
+    let replies = decisions
+        .into_iter()
+        .map(|decision| {
+            let (reply, outcome) = decision_reply(decision, accepted.clone());
+            if let Outcome::Closed(reason) = outcome {
+                close = Some(reason);
+            }
+            reply
+        })
+        .collect();
+    let outcome = close.map(Outcome::Closed).unwrap_or(Outcome::Accepted);
+    (replies, outcome)
+}
+
+// Sums up the decisions on each of `rcpts` into the single reply SMTP allows
+// after the data. The mail is accepted if any recipient is, and the other
+// recipients are then returned along with their refusals.
+fn merge_decisions(
+    decisions: Vec<Decision>,
+    rcpts: &[Email],
+    accepted: (ReplyCode, SmtpString),
+) -> ((ReplyCode, SmtpString), Vec<(Email, Refusal)>, Outcome) {
+    let mut accept_reply = None;
+    let mut refused = Vec::new();
+    let mut close = None;
+    let mut closing = None;
+    for (decision, rcpt) in decisions.into_iter().zip(rcpts) {
+        let ((code, msg), outcome) = decision_reply(decision, accepted.clone());
+        match outcome {
+            Outcome::Accepted => {
+                accept_reply.get_or_insert((code, msg));
+            }
+            Outcome::Rejected => refused.push((rcpt.clone(), Refusal { code, msg })),
+            Outcome::Closed(reason) => {
+                close = Some(reason);
+                closing.get_or_insert(refused.len());
+                refused.push((rcpt.clone(), Refusal { code, msg }));
+            }
+        }
+    }
+    match (accept_reply, close) {
+        (Some(reply), None) => (reply, refused, Outcome::Accepted),
+        (Some(reply), Some(reason)) => (reply, refused, Outcome::Closed(reason)),
+        (None, close) => {
+            // The refusal closing the connection goes first, then temporary
+            // ones, so that no recipient that could be retried gets bounced.
+            // There is at least one recipient to a mail.
+            let temporary = refused.iter().position(|(_, r)| r.code.code() < 500);
+            let r = refused.swap_remove(closing.or(temporary).unwrap_or(0)).1;
+            let outcome = close.map(Outcome::Closed).unwrap_or(Outcome::Rejected);
+            ((r.code, r.msg), Vec::new(), outcome)
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crlflines::LineEndings;
     use itertools::Itertools;
-    use smtp_message::{Email, ReplyCode, SmtpString};
-    use std::{self, cell::RefCell, rc::Rc};
-
-    use decision::Refusal;
+    use smtp_message::Prependable;
+    use std::{
+        self,
+        cell::{Cell, RefCell},
+        net::IpAddr,
+        rc::Rc,
+    };
+    use tokio::runtime::current_thread::Runtime;
 
     struct TestConfig {
         mails: Rc<RefCell<Vec<(Option<Email>, Vec<Email>, BytesMut)>>>,
+        disconnected: Rc<Cell<Option<DisconnectReason>>>,
+        lmtp: bool,
+        max_size: Option<usize>,
+        lenient: bool,
+    }
+
+    fn cfg() -> TestConfig {
+        TestConfig {
+            mails: Rc::new(RefCell::new(Vec::new())),
+            disconnected: Rc::new(Cell::new(None)),
+            lmtp: false,
+            max_size: None,
+            lenient: false,
+        }
+    }
+
+    fn run<F: Future>(f: F) -> Result<F::Item, F::Error> {
+        Runtime::new().unwrap().block_on(f)
     }
 
     impl Config<()> for TestConfig {
@@ -227,6 +960,72 @@ mod tests {
             SmtpString::from_static(b"test.example.org")
         }
 
+        fn on_connect(
+            self,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<Future<Item = (Self, ConnectionMetadata<()>, Decision), Error = ()>> {
+            let listed = "192.0.2.66".parse::<IpAddr>().ok();
+            let decision = if conn_meta.peer_addr.map(|a| a.ip()) == listed {
+                Decision::Reject(Refusal {
+                    code: ReplyCode::TRANSACTION_FAILED,
+                    msg:  "5.7.1 Client host rejected: Access denied".into(),
+                })
+            } else {
+                Decision::Accept
+            };
+            Box::new(future::ok((self, conn_meta, decision)))
+        }
+
+        fn on_disconnect(
+            self,
+            reason: DisconnectReason,
+            _mail: Option<MailMetadata>,
+            _conn_meta: ConnectionMetadata<()>,
+        ) -> Box<Future<Item = (), Error = ()>> {
+            self.disconnected.set(Some(reason));
+            Box::new(future::ok(()))
+        }
+
+        fn lmtp(&self) -> bool {
+            self.lmtp
+        }
+
+        fn xclient_trusted(&self, conn_meta: &ConnectionMetadata<()>) -> bool {
+            conn_meta.helo == Some(SmtpString::from_static(b"proxy.example.org"))
+        }
+
+        fn xforward_trusted(&self, conn_meta: &ConnectionMetadata<()>) -> bool {
+            self.xclient_trusted(conn_meta)
+        }
+
+        fn max_mail_size(&self) -> Option<usize> {
+            self.max_size
+        }
+
+        fn command_line_endings(&self) -> LineEndings {
+            if self.lenient {
+                LineEndings::Lenient
+            } else {
+                LineEndings::Reject
+            }
+        }
+
+        fn data_line_endings(&self) -> LineEndings {
+            self.command_line_endings()
+        }
+
+        fn greeting_timeout(&self) -> Duration {
+            Duration::from_millis(50)
+        }
+
+        fn rcpt_timeout(&self) -> Duration {
+            Duration::from_millis(50)
+        }
+
+        fn data_block_timeout(&self) -> Duration {
+            Duration::from_millis(50)
+        }
+
         fn filter_from(
             self,
             addr: Option<Email>,
@@ -243,6 +1042,44 @@ mod tests {
                         msg:  "User 'bad' banned".into(),
                     }),
                 )))
+            } else if addr == Some(Email::parse_slice(b"close@quux.example.org").unwrap()) {
+                Box::new(future::ok((
+                    self,
+                    addr,
+                    conn_meta,
+                    Decision::RejectAndClose(Refusal {
+                        code: ReplyCode::TRANSACTION_FAILED,
+                        msg:  "Go away".into(),
+                    }),
+                )))
+            } else if addr == Some(Email::parse_slice(b"few@quux.example.org").unwrap()) {
+                let mut conn_meta = conn_meta;
+                conn_meta.max_rcpts = Some(1);
+                Box::new(future::ok((self, addr, conn_meta, Decision::Accept)))
+            } else if addr == Some(Email::parse_slice(b"slow@quux.example.org").unwrap()) {
+                let decision =
+                    Decision::Delay(Duration::from_millis(20), Box::new(Decision::Accept));
+                Box::new(future::ok((self, addr, conn_meta, decision)))
+            } else if addr == Some(Email::parse_slice(b"whois@quux.example.org").unwrap()) {
+                // Tells what is known of the client
+                let unknown = SmtpString::from_static(b"-");
+                let show = |s: &Option<SmtpString>| s.clone().unwrap_or_else(|| unknown.clone());
+                let peer_addr = conn_meta.peer_addr.map(|a| a.to_string());
+                let text = SmtpString::from(peer_addr.unwrap_or_else(|| "-".to_owned()).as_str())
+                    + SmtpString::from_static(b" ")
+                    + show(&conn_meta.peer_name)
+                    + SmtpString::from_static(b" ")
+                    + show(&conn_meta.login)
+                    + SmtpString::from_static(b" ")
+                    + show(&conn_meta.helo)
+                    + SmtpString::from_static(b" ")
+                    + show(&conn_meta.xforward.get(b"NAME").cloned());
+                Box::new(future::ok((
+                    self,
+                    addr,
+                    conn_meta,
+                    Decision::AcceptWith(text),
+                )))
             } else {
                 Box::new(future::ok((self, addr, conn_meta, Decision::Accept)))
             }
@@ -270,6 +1107,31 @@ mod tests {
                         msg:  "No user 'baz'".into(),
                     }),
                 )))
+            } else if email.localpart().bytes() == &b"later"[..] {
+                Box::new(future::ok((
+                    self,
+                    email,
+                    meta,
+                    conn_meta,
+                    Decision::Reject(Refusal {
+                        code: ReplyCode::LOCAL_ERROR,
+                        msg:  "Try again later".into(),
+                    }),
+                )))
+            } else if email.localpart().bytes() == &b"crlf-only"[..] && conn_meta.bare_lf {
+                Box::new(future::ok((
+                    self,
+                    email,
+                    meta,
+                    conn_meta,
+                    Decision::Reject(Refusal {
+                        code: ReplyCode::POLICY_REASON,
+                        msg:  "Bare LF not trusted".into(),
+                    }),
+                )))
+            } else if email.localpart().bytes() == &b"alias"[..] {
+                let email = Email::parse_slice(b"foo@bar.example.org").unwrap();
+                Box::new(future::ok((self, email, meta, conn_meta, Decision::Accept)))
             } else {
                 Box::new(future::ok((self, email, meta, conn_meta, Decision::Accept)))
             }
@@ -292,27 +1154,105 @@ mod tests {
                     Error = (),
                 >,
         > {
-            Box::new(reader.concat_and_recover().map_err(|_| ()).and_then(
-                move |(mail_text, reader)| {
-                    if mail_text.windows(5).position(|x| x == b"World").is_some() {
-                        future::ok((
+            Box::new(reader.concat_and_recover().then(move |res| {
+                let (mail_text, reader) = match res {
+                    Ok(res) => res,
+                    Err(_) => {
+                        return future::ok((
                             self,
-                            Some(reader.into_inner()),
+                            None,
                             conn_meta,
                             Decision::Reject(Refusal {
-                                code: ReplyCode::POLICY_REASON,
-                                msg:  "Don't you dare say 'World'!".into(),
+                                code: ReplyCode::TRANSACTION_FAILED,
+                                msg:  "Incomplete data".into(),
                             }),
                         ))
-                    } else {
-                        self.mails
-                            .borrow_mut()
-                            .push((meta.from, meta.to, mail_text));
-                        future::ok((self, Some(reader.into_inner()), conn_meta, Decision::Accept))
                     }
+                };
+                if mail_text.windows(5).position(|x| x == b"World").is_some() {
+                    future::ok((
+                        self,
+                        Some(reader.into_inner()),
+                        conn_meta,
+                        Decision::Reject(Refusal {
+                            code: ReplyCode::POLICY_REASON,
+                            msg:  "Don't you dare say 'World'!".into(),
+                        }),
+                    ))
+                } else {
+                    let decision = if mail_text.starts_with(b"Queue") {
+                        Decision::AcceptWith("Queued as 42".into())
+                    } else {
+                        Decision::Accept
+                    };
+                    self.mails
+                        .borrow_mut()
+                        .push((meta.from, meta.to, mail_text));
+                    future::ok((self, Some(reader.into_inner()), conn_meta, decision))
+                }
+            }))
+        }
+
+        // Refuses the recipients named "full" once the mail is handled
+        fn handle_mail_rcpts<'a, S: 'a + Stream<Item = BytesMut, Error = ()>>(
+            self,
+            reader: DataStream<S>,
+            meta: MailMetadata,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<
+            'a
+                + Future<
+                    Item = (
+                        Self,
+                        Option<Prependable<S>>,
+                        ConnectionMetadata<()>,
+                        Vec<Decision>,
+                    ),
+                    Error = (),
+                >,
+        > {
+            let full = meta
+                .to
+                .iter()
+                .map(|to| to.localpart().bytes() == &b"full"[..])
+                .collect::<Vec<_>>();
+            Box::new(self.handle_mail(reader, meta, conn_meta).map(
+                move |(cfg, reader, conn_meta, decision)| {
+                    let decisions = full
+                        .into_iter()
+                        .map(|full| {
+                            if full {
+                                Decision::Reject(Refusal {
+                                    code: ReplyCode::INSUFFICIENT_STORAGE,
+                                    msg:  "Mailbox full".into(),
+                                })
+                            } else {
+                                decision.clone()
+                            }
+                        })
+                        .collect();
+                    (cfg, reader, conn_meta, decisions)
                 },
             ))
         }
+
+        // Records a bounce to the sender, listing the failed recipients
+        fn rcpts_failed(
+            self,
+            failed: Vec<(Email, Refusal)>,
+            meta: MailMetadata,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<Future<Item = (Self, ConnectionMetadata<()>), Error = ()>> {
+            let mut text = BytesMut::new();
+            for (to, _) in failed {
+                text.extend_from_slice(&to.localpart().bytes()[..]);
+                text.extend_from_slice(b"\r\n");
+            }
+            self.mails
+                .borrow_mut()
+                .push((None, meta.from.into_iter().collect(), text));
+            Box::new(future::ok((self, conn_meta)))
+        }
     }
 
     #[test]
@@ -334,7 +1274,7 @@ mod tests {
                   250 Okay\r\n\
                   354 Start mail input; end with <CRLF>.<CRLF>\r\n\
                   250 Okay\r\n\
-                  502 Command not implemented\r\n",
+                  221 test.example.org Service closing transmission channel\r\n",
                 &[(
                     None,
                     &[b"foo2@bar.example.org", b"foo3@bar.example.org"],
@@ -355,7 +1295,7 @@ mod tests {
                   250 Okay\r\n\
                   354 Start mail input; end with <CRLF>.<CRLF>\r\n\
                   550 Don't you dare say 'World'!\r\n\
-                  502 Command not implemented\r\n",
+                  221 test.example.org Service closing transmission channel\r\n",
                 &[],
             ),
             (
@@ -382,7 +1322,7 @@ mod tests {
                   250 Okay\r\n\
                   354 Start mail input; end with <CRLF>.<CRLF>\r\n\
                   250 Okay\r\n\
-                  502 Command not implemented\r\n",
+                  221 test.example.org Service closing transmission channel\r\n",
                 &[(
                     Some(b"foo@bar.example.org"),
                     &[b"foo2@bar.example.org"],
@@ -396,7 +1336,27 @@ mod tests {
                 b"220 test.example.org Service ready\r\n\
                   250 Okay\r\n\
                   503 Bad sequence of commands\r\n\
-                  502 Command not implemented\r\n",
+                  221 test.example.org Service closing transmission channel\r\n",
+                &[],
+            ),
+            (
+                &[b"EHLO client.example.org\r\n\
+                    MAIL FROM:<foo@test.example.com>\r\n\
+                    RSET\r\n\
+                    RCPT TO:<foo@bar.example.org>\r\n\
+                    HELO client.example.org\r\n\
+                    NOOP\r\n\
+                    QUIT\r\n\
+                    NOOP\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250-test.example.org\r\n\
+                  250 PIPELINING\r\n\
+                  250 Okay\r\n\
+                  250 Okay\r\n\
+                  503 Bad sequence of commands\r\n\
+                  250 test.example.org\r\n\
+                  250 Okay\r\n\
+                  221 test.example.org Service closing transmission channel\r\n",
                 &[],
             ),
             (
@@ -416,11 +1376,10 @@ mod tests {
             );
             let stream = stream::iter_ok(inp.iter().map(|x| BytesMut::from(*x)));
             let resp_mail = Rc::new(RefCell::new(Vec::new()));
-            let mut cfg = TestConfig {
-                mails: resp_mail.clone(),
-            };
+            let mut cfg = cfg();
+            cfg.mails = resp_mail.clone();
             let mut resp = Vec::new();
-            interact(stream, &mut resp, (), cfg).wait().unwrap();
+            run(interact(stream, &mut resp, (), cfg)).unwrap();
             let resp = resp.into_iter().concat();
             println!("Expecting\n---\n{}---", std::str::from_utf8(out).unwrap());
             println!("Got\n---\n{}---", std::str::from_utf8(&resp).unwrap());
@@ -448,6 +1407,493 @@ mod tests {
         }
     }
 
+    #[test]
+    fn rejects_long_lines() {
+        let long_cmd = format!("NOOP {}\r\n", "a".repeat(600));
+        let long_text = format!("{}\r\n", "a".repeat(1000));
+        let inp = [
+            &long_cmd[..],
+            "MAIL FROM:<foo@bar.example.org>\r\n",
+            "RCPT TO:<foo2@bar.example.org>\r\n",
+            "DATA\r\n",
+            "Hello\r\n",
+            &long_text[..],
+            ".\r\n",
+            "QUIT\r\n",
+        ]
+        .concat();
+        let stream = stream::iter_ok(inp.as_bytes().chunks(100).map(BytesMut::from));
+        let mails = Rc::new(RefCell::new(Vec::new()));
+        let mut cfg = cfg();
+        cfg.mails = mails.clone();
+        let mut resp = Vec::new();
+        run(interact(stream, &mut resp, (), cfg)).unwrap();
+        assert_eq!(
+            resp.into_iter().concat(),
+            &b"220 test.example.org Service ready\r\n\
+               500 Line too long\r\n\
+               250 Okay\r\n\
+               250 Okay\r\n\
+               354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+               552 Line too long\r\n\
+               221 test.example.org Service closing transmission channel\r\n"[..]
+        );
+        assert!(mails.borrow().is_empty());
+    }
+
+    #[test]
+    fn times_out() {
+        let tests: &[(&[&[u8]], &[u8], DisconnectReason)] = &[
+            (
+                &[],
+                b"220 test.example.org Service ready\r\n\
+                  421 test.example.org Timeout exceeded, closing connection\r\n",
+                DisconnectReason::Timeout(Timeout::Greeting),
+            ),
+            (
+                &[b"MAIL FROM:<foo@bar.example.org>\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250 Okay\r\n\
+                  421 test.example.org Timeout exceeded, closing connection\r\n",
+                DisconnectReason::Timeout(Timeout::Rcpt),
+            ),
+            (
+                &[b"MAIL FROM:<foo@bar.example.org>\r\n\
+                    RCPT TO:<foo2@bar.example.org>\r\n\
+                    DATA\r\n\
+                    Hello\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250 Okay\r\n\
+                  250 Okay\r\n\
+                  354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+                  421 test.example.org Timeout exceeded, closing connection\r\n",
+                DisconnectReason::Timeout(Timeout::DataBlock),
+            ),
+        ];
+        for &(inp, out, reason) in tests {
+            // The client stops sending anything once it is done with `inp`
+            let stream = stream::iter_ok(inp.iter().map(|x| BytesMut::from(*x)))
+                .chain(stream::poll_fn(|| Ok(Async::NotReady)));
+            let disconnected = Rc::new(Cell::new(None));
+            let mut cfg = cfg();
+            cfg.disconnected = disconnected.clone();
+            let mut resp = Vec::new();
+            run(interact(stream, &mut resp, (), cfg)).unwrap();
+            assert_eq!(resp.into_iter().concat(), out);
+            assert_eq!(disconnected.get(), Some(reason));
+        }
+    }
+
+    #[test]
+    fn tells_why_session_ended() {
+        let tests: &[(&[u8], DisconnectReason)] = &[
+            (b"NOOP\r\nQUIT\r\nNOOP\r\n", DisconnectReason::Quit),
+            (b"NOOP\r\n", DisconnectReason::Eof),
+        ];
+        for &(inp, reason) in tests {
+            let stream = stream::iter_ok(vec![BytesMut::from(inp)]);
+            let disconnected = Rc::new(Cell::new(None));
+            let mut cfg = cfg();
+            cfg.disconnected = disconnected.clone();
+            let mut resp = Vec::new();
+            run(interact(stream, &mut resp, (), cfg)).unwrap();
+            assert_eq!(disconnected.get(), Some(reason));
+        }
+    }
+
+    #[test]
+    fn honors_decisions() {
+        let inp: &[u8] = b"MAIL FROM:<slow@quux.example.org>\r\n\
+                           RCPT TO:<later@bar.example.org>\r\n\
+                           RCPT TO:<alias@bar.example.org>\r\n\
+                           DATA\r\n\
+                           Queue me\r\n\
+                           .\r\n\
+                           MAIL FROM:<close@quux.example.org>\r\n\
+                           NOOP\r\n";
+        let stream = stream::iter_ok(vec![BytesMut::from(inp)]);
+        let mails = Rc::new(RefCell::new(Vec::new()));
+        let disconnected = Rc::new(Cell::new(None));
+        let mut cfg = cfg();
+        cfg.mails = mails.clone();
+        cfg.disconnected = disconnected.clone();
+        let mut resp = Vec::new();
+        let start = Instant::now();
+        run(interact(stream, &mut resp, (), cfg)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(
+            resp.into_iter().concat(),
+            &b"220 test.example.org Service ready\r\n\
+               250 Okay\r\n\
+               451 Try again later\r\n\
+               250 Okay\r\n\
+               354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+               250 Queued as 42\r\n\
+               554 Go away\r\n"[..]
+        );
+        assert_eq!(disconnected.get(), Some(DisconnectReason::Policy));
+        let mails = mails.borrow();
+        assert_eq!(mails.len(), 1);
+        assert!(mails[0].1 == vec![Email::parse_slice(b"foo@bar.example.org").unwrap()]);
+    }
+
+    #[test]
+    fn reports_rcpt_failures() {
+        let tests: &[(&[u8], &[u8], &[(Option<&[u8]>, &[&[u8]], &[u8])])] = &[
+            (
+                b"MAIL FROM:<foo@bar.example.org>\r\n\
+                  RCPT TO:<full@bar.example.org>\r\n\
+                  RCPT TO:<foo2@bar.example.org>\r\n\
+                  DATA\r\n\
+                  Hello\r\n\
+                  .\r\n",
+                b"250 Okay\r\n",
+                &[
+                    (
+                        Some(b"foo@bar.example.org"),
+                        &[b"full@bar.example.org", b"foo2@bar.example.org"],
+                        b"Hello\r\n",
+                    ),
+                    (None, &[b"foo@bar.example.org"], b"full\r\n"),
+                ],
+            ),
+            (
+                b"MAIL FROM:<foo@bar.example.org>\r\n\
+                  RCPT TO:<full@bar.example.org>\r\n\
+                  DATA\r\n\
+                  Hello\r\n\
+                  .\r\n",
+                b"452 Mailbox full\r\n",
+                &[(
+                    Some(b"foo@bar.example.org"),
+                    &[b"full@bar.example.org"],
+                    b"Hello\r\n",
+                )],
+            ),
+        ];
+        for &(inp, reply, expected) in tests {
+            let stream = stream::iter_ok(vec![BytesMut::from(inp)]);
+            let mails = Rc::new(RefCell::new(Vec::new()));
+            let mut cfg = cfg();
+            cfg.mails = mails.clone();
+            let mut resp = Vec::new();
+            run(interact(stream, &mut resp, (), cfg)).unwrap();
+            assert!(resp.into_iter().concat().ends_with(reply));
+            let mails = mails.borrow();
+            assert_eq!(mails.len(), expected.len());
+            for (&(ref fr, ref tr, ref cr), &(fo, to, co)) in mails.iter().zip(expected) {
+                assert!(*fr == fo.map(|x| Email::parse_slice(x).unwrap()));
+                assert!(
+                    *tr == to
+                        .iter()
+                        .map(|x| Email::parse_slice(x).unwrap())
+                        .collect::<Vec<_>>()
+                );
+                assert_eq!(&cr[..], co);
+            }
+        }
+    }
+
+    #[test]
+    fn merges_decisions() {
+        let refuse = |code, msg: &'static [u8]| Refusal {
+            code: ReplyCode::custom(code),
+            msg:  SmtpString::from_static(msg),
+        };
+        let rcpts = ["a@example.org", "b@example.org", "c@example.org"]
+            .iter()
+            .map(|r| Email::parse_slice(r.as_bytes()).unwrap())
+            .collect::<Vec<_>>();
+        let accepted = || (ReplyCode::OKAY, SmtpString::from_static(b"Okay"));
+        let merge = |decisions: Vec<Decision>| {
+            let decisions = one_per_rcpt(decisions, rcpts.len());
+            let ((code, msg), failed, outcome) = merge_decisions(decisions, &rcpts, accepted());
+            let closed = match outcome {
+                Outcome::Closed(_) => true,
+                _ => false,
+            };
+            (code.code(), msg, failed.len(), closed)
+        };
+
+        // A temporary refusal goes before a permanent one
+        let decisions = vec![
+            Decision::Reject(refuse(550, b"No such user")),
+            Decision::Reject(refuse(450, b"Try later")),
+            Decision::Reject(refuse(552, b"Full")),
+        ];
+        assert_eq!(merge(decisions), (450, "Try later".into(), 0, false));
+
+        // And the refusal closing the connection before all the others
+        let decisions = vec![
+            Decision::Reject(refuse(450, b"Try later")),
+            Decision::Reject(refuse(550, b"No such user")),
+            Decision::RejectAndClose(refuse(421, b"Bye")),
+        ];
+        assert_eq!(merge(decisions), (421, "Bye".into(), 0, true));
+
+        // Missing decisions are temporary failures, and extra ones dropped
+        let decisions = vec![Decision::Reject(refuse(550, b"No such user"))];
+        assert_eq!(
+            merge(decisions),
+            (451, "4.3.0 Local error in processing".into(), 0, false)
+        );
+        let decisions = vec![
+            Decision::Accept,
+            Decision::Reject(refuse(550, b"No such user")),
+            Decision::Accept,
+            Decision::Reject(refuse(550, b"Extra")),
+        ];
+        assert_eq!(merge(decisions), (250, "Okay".into(), 1, false));
+    }
+
+    #[test]
+    fn speaks_lmtp() {
+        let inp: &[u8] = b"EHLO client.example.org\r\n\
+                           MAIL FROM:<foo@bar.example.org>\r\n\
+                           LHLO client.example.org\r\n\
+                           MAIL FROM:<foo@bar.example.org>\r\n\
+                           RCPT TO:<foo2@bar.example.org>\r\n\
+                           RCPT TO:<full@bar.example.org>\r\n\
+                           DATA\r\n\
+                           Hello\r\n\
+                           .\r\n\
+                           QUIT\r\n";
+        let stream = stream::iter_ok(vec![BytesMut::from(inp)]);
+        let mails = Rc::new(RefCell::new(Vec::new()));
+        let mut cfg = cfg();
+        cfg.mails = mails.clone();
+        cfg.lmtp = true;
+        let mut resp = Vec::new();
+        run(interact(stream, &mut resp, (), cfg)).unwrap();
+        assert_eq!(
+            resp.into_iter().concat(),
+            &b"220 test.example.org Service ready\r\n\
+               500 Command not recognized\r\n\
+               503 Bad sequence of commands\r\n\
+               250-test.example.org\r\n\
+               250 PIPELINING\r\n\
+               250 Okay\r\n\
+               250 Okay\r\n\
+               250 Okay\r\n\
+               354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+               250 Okay\r\n\
+               452 Mailbox full\r\n\
+               221 test.example.org Service closing transmission channel\r\n"[..]
+        );
+        // No bounce, as the client got the failure
+        assert_eq!(mails.borrow().len(), 1);
+    }
+
+    #[test]
+    fn limits_mail_size() {
+        let inp: &[u8] = b"EHLO client.example.org\r\n\
+                           MAIL FROM:<foo@bar.example.org> SIZE=21\r\n\
+                           MAIL FROM:<foo@bar.example.org> SIZE=twenty\r\n\
+                           MAIL FROM:<foo@bar.example.org> SIZE=20\r\n\
+                           RCPT TO:<foo2@bar.example.org>\r\n\
+                           DATA\r\n\
+                           Hello\r\n\
+                           Hello again\r\n\
+                           Hello\r\n\
+                           .\r\n\
+                           QUIT\r\n";
+        let stream = stream::iter_ok(vec![BytesMut::from(inp)]);
+        let mails = Rc::new(RefCell::new(Vec::new()));
+        let mut cfg = cfg();
+        cfg.mails = mails.clone();
+        cfg.max_size = Some(20);
+        let mut resp = Vec::new();
+        run(interact(stream, &mut resp, (), cfg)).unwrap();
+        assert_eq!(
+            resp.into_iter().concat(),
+            &b"220 test.example.org Service ready\r\n\
+               250-test.example.org\r\n\
+               250-PIPELINING\r\n\
+               250 SIZE 20\r\n\
+               552 Message size exceeds fixed maximum message size\r\n\
+               501 Invalid SIZE parameter\r\n\
+               250 Okay\r\n\
+               250 Okay\r\n\
+               354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+               552 Message size exceeds fixed maximum message size\r\n\
+               221 test.example.org Service closing transmission channel\r\n"[..]
+        );
+        assert!(mails.borrow().is_empty());
+    }
+
+    #[test]
+    fn rejects_bare_line_endings() {
+        let inp: &[u8] = b"MAIL FROM:<foo@bar.example.org>\nRSET\r\n\
+                           MAIL FROM:<foo@bar.example.org>\r\n\
+                           RCPT TO:<foo2@bar.example.org>\r\n\
+                           DATA\r\n\
+                           Hello\n\
+                           .\n\
+                           MAIL FROM:<evil@quux.example.org>\r\n\
+                           .\r\n\
+                           QUIT\r\n";
+        let stream = stream::iter_ok(vec![BytesMut::from(inp)]);
+        let mails = Rc::new(RefCell::new(Vec::new()));
+        let mut cfg = cfg();
+        cfg.mails = mails.clone();
+        let mut resp = Vec::new();
+        run(interact(stream, &mut resp, (), cfg)).unwrap();
+        assert_eq!(
+            resp.into_iter().concat(),
+            &b"220 test.example.org Service ready\r\n\
+               500 Bare CR or LF not allowed\r\n\
+               250 Okay\r\n\
+               250 Okay\r\n\
+               354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+               554 Bare CR or LF not allowed in the data\r\n\
+               221 test.example.org Service closing transmission channel\r\n"[..]
+        );
+        assert!(mails.borrow().is_empty());
+    }
+
+    #[test]
+    fn accepts_bare_lf_when_lenient() {
+        let inp: &[u8] = b"EHLO client.example.org\n\
+                           MAIL FROM:<foo@bar.example.org>\n\
+                           RCPT TO:<crlf-only@bar.example.org>\n\
+                           RCPT TO:<foo2@bar.example.org>\n\
+                           DATA\n\
+                           Hello\n\
+                           ..\n\
+                           .\n\
+                           QUIT\n";
+        let stream = stream::iter_ok(vec![BytesMut::from(inp)]);
+        let mails = Rc::new(RefCell::new(Vec::new()));
+        let mut cfg = cfg();
+        cfg.mails = mails.clone();
+        cfg.lenient = true;
+        let mut resp = Vec::new();
+        run(interact(stream, &mut resp, (), cfg)).unwrap();
+        assert_eq!(
+            resp.into_iter().concat(),
+            &b"220 test.example.org Service ready\r\n\
+               250-test.example.org\r\n\
+               250 PIPELINING\r\n\
+               250 Okay\r\n\
+               550 Bare LF not trusted\r\n\
+               250 Okay\r\n\
+               354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+               250 Okay\r\n\
+               221 test.example.org Service closing transmission channel\r\n"[..]
+        );
+        let mails = mails.borrow();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(&mails[0].2[..], b"Hello\r\n.\r\n");
+    }
+
+    #[test]
+    fn limits_rcpts() {
+        let inp: &[u8] = b"MAIL FROM:<few@quux.example.org>\r\n\
+                           RCPT TO:<foo@bar.example.org>\r\n\
+                           RCPT TO:<foo2@bar.example.org>\r\n\
+                           DATA\r\n\
+                           Hello\r\n\
+                           .\r\n\
+                           QUIT\r\n";
+        let stream = stream::iter_ok(vec![BytesMut::from(inp)]);
+        let mails = Rc::new(RefCell::new(Vec::new()));
+        let mut cfg = cfg();
+        cfg.mails = mails.clone();
+        let mut resp = Vec::new();
+        run(interact(stream, &mut resp, (), cfg)).unwrap();
+        assert_eq!(
+            resp.into_iter().concat(),
+            &b"220 test.example.org Service ready\r\n\
+               250 Okay\r\n\
+               250 Okay\r\n\
+               452 4.5.3 Too many recipients\r\n\
+               354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+               250 Okay\r\n\
+               221 test.example.org Service closing transmission channel\r\n"[..]
+        );
+        let mails = mails.borrow();
+        assert_eq!(mails.len(), 1);
+        assert!(mails[0].1 == vec![Email::parse_slice(b"foo@bar.example.org").unwrap()]);
+    }
+
+    #[test]
+    fn honors_xclient_and_xforward() {
+        let inp: &[u8] = b"EHLO untrusted.example.org\r\n\
+                           XCLIENT ADDR=192.0.2.1\r\n\
+                           EHLO proxy.example.org\r\n\
+                           XCLIENT FOO=bar\r\n\
+                           XFORWARD NAME=spam.example.org\r\n\
+                           MAIL FROM:<whois@quux.example.org>\r\n\
+                           RCPT TO:<foo@bar.example.org>\r\n\
+                           DATA\r\n\
+                           Hello\r\n\
+                           .\r\n\
+                           MAIL FROM:<whois@quux.example.org>\r\n\
+                           RSET\r\n\
+                           XCLIENT NAME=foo.example.org ADDR=192.0.2.1 PORT=56324 LOGIN=jdoe\r\n\
+                           MAIL FROM:<whois@quux.example.org>\r\n\
+                           XCLIENT HELO=client.example.org\r\n\
+                           QUIT\r\n";
+        let stream = stream::iter_ok(vec![BytesMut::from(inp)]);
+        let cfg = cfg();
+        let mut resp = Vec::new();
+        run(interact(stream, &mut resp, (), cfg)).unwrap();
+        assert_eq!(
+            resp.into_iter().concat(),
+            &b"220 test.example.org Service ready\r\n\
+               250-test.example.org\r\n\
+               250 PIPELINING\r\n\
+               550 5.7.0 Insufficient authorization\r\n\
+               250-test.example.org\r\n\
+               250-PIPELINING\r\n\
+               250-XCLIENT NAME ADDR PORT HELO LOGIN\r\n\
+               250 XFORWARD NAME ADDR PORT PROTO HELO IDENT SOURCE\r\n\
+               501 5.5.4 Bad attribute\r\n\
+               250 Okay\r\n\
+               250 - - - proxy.example.org spam.example.org\r\n\
+               250 Okay\r\n\
+               354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+               250 Okay\r\n\
+               250 - - - proxy.example.org -\r\n\
+               250 Okay\r\n\
+               220 test.example.org Service ready\r\n\
+               250 192.0.2.1:56324 foo.example.org jdoe proxy.example.org -\r\n\
+               503 Bad sequence of commands\r\n\
+               221 test.example.org Service closing transmission channel\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn checks_clients_told_by_xclient() {
+        let inp: &[u8] = b"EHLO proxy.example.org\r\n\
+                           XCLIENT ADDR=192.0.2.1\r\n\
+                           EHLO proxy.example.org\r\n\
+                           XCLIENT ADDR=192.0.2.66\r\n\
+                           MAIL FROM:<whois@quux.example.org>\r\n\
+                           QUIT\r\n";
+        let stream = stream::iter_ok(vec![BytesMut::from(inp)]);
+        let disconnected = Rc::new(Cell::new(None));
+        let mut cfg = cfg();
+        cfg.disconnected = disconnected.clone();
+        let mut resp = Vec::new();
+        run(interact(stream, &mut resp, (), cfg)).unwrap();
+        assert_eq!(
+            resp.into_iter().concat(),
+            &b"220 test.example.org Service ready\r\n\
+               250-test.example.org\r\n\
+               250-PIPELINING\r\n\
+               250-XCLIENT NAME ADDR PORT HELO LOGIN\r\n\
+               250 XFORWARD NAME ADDR PORT PROTO HELO IDENT SOURCE\r\n\
+               220 test.example.org Service ready\r\n\
+               250-test.example.org\r\n\
+               250-PIPELINING\r\n\
+               250-XCLIENT NAME ADDR PORT HELO LOGIN\r\n\
+               250 XFORWARD NAME ADDR PORT PROTO HELO IDENT SOURCE\r\n\
+               554 5.7.1 Client host rejected: Access denied\r\n"[..]
+        );
+        assert_eq!(disconnected.get(), Some(DisconnectReason::Policy));
+    }
+
     // Fuzzer-found
     #[test]
     fn interrupted_data() {
@@ -456,12 +1902,15 @@ mod tests {
                                 DATA\r\n\
                                 hello"];
         let stream = stream::iter_ok(txt.iter().map(|x| BytesMut::from(*x)));
-        let cfg = TestConfig {
-            mails: Rc::new(RefCell::new(Vec::new())),
-        };
+        let mails = Rc::new(RefCell::new(Vec::new()));
+        let disconnected = Rc::new(Cell::new(None));
+        let mut cfg = cfg();
+        cfg.mails = mails.clone();
+        cfg.disconnected = disconnected.clone();
         let mut resp = Vec::new();
-        let res = interact(stream, &mut resp, (), cfg).wait();
-        assert!(res.is_err());
+        run(interact(stream, &mut resp, (), cfg)).unwrap();
+        assert!(mails.borrow().is_empty());
+        assert_eq!(disconnected.get(), Some(DisconnectReason::Eof));
     }
 
     // Fuzzer-found
@@ -522,9 +1971,7 @@ mod tests {
         ];
         let stream = stream::iter_ok(txt.iter().map(|x| BytesMut::from(*x)));
         let mut resp = Vec::new();
-        let cfg = TestConfig {
-            mails: Rc::new(RefCell::new(Vec::new())),
-        };
-        interact(stream, &mut resp, (), cfg).wait().unwrap();
+        let cfg = cfg();
+        run(interact(stream, &mut resp, (), cfg)).unwrap();
     }
 }
@@ -1,20 +1,57 @@
 #![type_length_limit = "4194304"]
 
-// TODO: (B) add deadlines
 extern crate bytes;
 extern crate itertools;
 extern crate smtp_message;
 extern crate tokio;
 
+#[cfg(test)]
+#[macro_use]
+extern crate quickcheck;
+
+mod access;
+mod bufio;
+mod chain;
+mod clientcheck;
 mod config;
 mod crlflines;
+mod dataguard;
 mod decision;
+mod disconnect;
+mod dns;
+mod dnsbl;
+mod greylist;
 mod interact;
+mod mailbox;
+mod mapfile;
 mod metadata;
+mod milter;
+mod proxy;
+mod relay;
 mod sendreply;
+mod server;
+mod shutdown;
 mod stupidfut;
+#[cfg(test)]
+mod testutil;
+mod timeout;
+mod xclient;
 
+pub use access::{Access, AccessAction, AccessMap};
+pub use bufio::interact_io;
+pub use chain::{Chain, Flow, Layer};
+pub use clientcheck::{ClientCheck, ClientRule, HeloCheck, ReverseDns};
 pub use config::Config;
+pub use crlflines::LineEndings;
 pub use decision::{Decision, Refusal};
+pub use disconnect::DisconnectReason;
+pub use dns::Resolver;
+pub use dnsbl::Dnsbl;
+pub use greylist::Greylist;
 pub use interact::interact;
-pub use metadata::{ConnectionMetadata, MailMetadata};
+pub use mailbox::{Mailbox, MailboxMap, Mailboxes};
+pub use metadata::{ConnectionMetadata, MailMetadata, State};
+pub use milter::{Milter, Modification, Verdict};
+pub use relay::Relay;
+pub use server::Server;
+pub use timeout::Timeout;
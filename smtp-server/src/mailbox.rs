@@ -0,0 +1,322 @@
+use smtp_message::{Email, ReplyCode, SmtpString};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::Path,
+    str,
+};
+use tokio::prelude::*;
+
+use chain::{Flow, Layer};
+use decision::{Decision, Refusal};
+use mapfile::MapFile;
+use metadata::{ConnectionMetadata, MailMetadata};
+
+// What a mailbox map says about a recipient
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Mailbox {
+    // The domain is not in the map
+    Foreign,
+    // The domain is in the map, but not the user
+    Unknown,
+    // Canonical mailbox of the recipient
+    Found(Email),
+}
+
+// Mailboxes of the recipients of the current mail that are in the domains of
+// a `MailboxMap`, recorded by it in `ConnectionMetadata::mail_state`, the
+// recipients themselves staying as given in `MailMetadata::to`
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Mailboxes(Vec<(Email, Email)>);
+
+impl Mailboxes {
+    pub fn get(&self, to: &Email) -> Option<&Email> {
+        self.0
+            .iter()
+            .find(|(t, _)| t == to)
+            .map(|(_, mailbox)| mailbox)
+    }
+
+    // Mailboxes to deliver to for the recipients `to` (eg. `MailMetadata::to`),
+    // each once even when several recipients are aliases of it, those that
+    // have no mailbox standing for themselves
+    pub fn targets(&self, to: &[Email]) -> Vec<Email> {
+        let mut targets = Vec::new();
+        for to in to {
+            let target = self.get(to).unwrap_or(to);
+            if !targets.contains(target) {
+                targets.push(target.clone());
+            }
+        }
+        targets
+    }
+
+    fn insert(&mut self, to: Email, mailbox: Email) {
+        self.0.retain(|(t, _)| *t != to);
+        self.0.push((to, mailbox));
+    }
+}
+
+#[derive(Default)]
+struct Table {
+    domains:   HashSet<String>,
+    // Keyed on `user@domain`, or `@domain` for catch-all entries, the
+    // mailbox being the recipient itself for catch-alls without one
+    mailboxes: HashMap<String, Option<Email>>,
+}
+
+// Virtual mailbox map, read from a text file with one entry per line:
+//   <user@domain> [<mailbox>]
+//   @<domain> [<mailbox>]
+// where the mailbox defaults to the address of the entry, or for the catch-all
+// entries of the second form, to the recipient. Empty lines and those starting
+// with `#` are ignored, and addresses are case-insensitive.
+//
+// As a `Layer`, recipients in the domains of the map that have no mailbox are
+// refused, and the mailboxes of the others are recorded in `Mailboxes`.
+// Subaddresses (`user+detail@domain`, see RFC 5233) are
+// looked up as is, then without their detail. The file is read again when it
+// changed, on the next lookup.
+#[derive(Clone)]
+pub struct MailboxMap {
+    file:      MapFile<Table>,
+    delimiter: Option<char>,
+}
+
+impl MailboxMap {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<MailboxMap> {
+        Ok(MailboxMap {
+            file:      MapFile::open(path, Table::parse)?,
+            delimiter: Some('+'),
+        })
+    }
+
+    // Character separating the user from the detail in subaddresses, `+` by
+    // default, or `None` for not handling them
+    pub fn delimiter(mut self, delimiter: Option<char>) -> MailboxMap {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn lookup(&self, to: &Email) -> Mailbox {
+        let domain = match to.hostname() {
+            Some(domain) => SmtpString::from_sendable(domain)
+                .map(|d| String::from_utf8_lossy(&d.bytes()[..]).to_ascii_lowercase()),
+            None => return Mailbox::Foreign,
+        };
+        let domain = match domain {
+            Ok(domain) => domain,
+            Err(_) => return Mailbox::Foreign,
+        };
+        let table = self.file.table();
+        if !table.domains.contains(&domain) {
+            return Mailbox::Foreign;
+        }
+        let user = to.localpart();
+        let user = String::from_utf8_lossy(&user.bytes()[..]).to_ascii_lowercase();
+        let mut keys = vec![format!("{}@{}", user, domain)];
+        if let Some(i) = self.delimiter.and_then(|d| user.find(d)).filter(|&i| i > 0) {
+            keys.push(format!("{}@{}", &user[..i], domain));
+        }
+        keys.push(format!("@{}", domain));
+        for key in keys {
+            match table.mailboxes.get(&key) {
+                Some(Some(mailbox)) => return Mailbox::Found(mailbox.clone()),
+                Some(None) => return Mailbox::Found(to.clone()),
+                None => (),
+            }
+        }
+        Mailbox::Unknown
+    }
+}
+
+impl Table {
+    fn parse(content: &[u8]) -> io::Result<Table> {
+        let content = str::from_utf8(content)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "mailbox map is not UTF-8"))?;
+        let mut table = Table::default();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                let msg = format!("invalid mailbox map entry on line {}", i + 1);
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            };
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (key, mailbox) = match &fields[..] {
+                [key] => (*key, None),
+                [key, mailbox] => (*key, Some(*mailbox)),
+                _ => return Err(invalid()),
+            };
+            let at = key.rfind('@').ok_or_else(invalid)?;
+            let domain = key[at + 1..].trim_end_matches('.').to_ascii_lowercase();
+            if domain.is_empty() {
+                return Err(invalid());
+            }
+            let mailbox = match (mailbox, at) {
+                (Some(mailbox), _) => {
+                    Some(Email::parse_slice(mailbox.as_bytes()).map_err(|_| invalid())?)
+                }
+                (None, 0) => None,
+                (None, _) => Some(Email::parse_slice(key.as_bytes()).map_err(|_| invalid())?),
+            };
+            let key = format!("{}@{}", key[..at].to_ascii_lowercase(), domain);
+            table.mailboxes.insert(key, mailbox);
+            table.domains.insert(domain);
+        }
+        Ok(table)
+    }
+}
+
+impl<U: 'static> Layer<U> for MailboxMap {
+    fn filter_to(
+        &self,
+        to: Email,
+        meta: MailMetadata,
+        mut conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Email, MailMetadata, ConnectionMetadata<U>, Flow), Error = ()>> {
+        let flow = match self.lookup(&to) {
+            Mailbox::Foreign => Flow::Next,
+            Mailbox::Found(mailbox) => {
+                if conn_meta.mail_state.get::<Mailboxes>().is_none() {
+                    conn_meta.mail_state.insert(Mailboxes::default());
+                }
+                let mailboxes = conn_meta.mail_state.get_mut::<Mailboxes>().unwrap();
+                mailboxes.insert(to.clone(), mailbox);
+                Flow::Next
+            }
+            Mailbox::Unknown => {
+                let mut msg = b"5.1.1 <".to_vec();
+                msg.extend_from_slice(&SmtpString::from_sendable(&to).unwrap().bytes()[..]);
+                msg.extend_from_slice(b">: Recipient address rejected: User unknown");
+                let r = Refusal {
+                    code: ReplyCode::MAILBOX_UNAVAILABLE,
+                    msg:  SmtpString::from(msg),
+                };
+                Flow::Stop(Decision::Reject(r))
+            }
+        };
+        Box::new(future::ok((to, meta, conn_meta, flow)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tokio::runtime::current_thread::Runtime;
+
+    use testutil::{email, map, refusal, TempFile};
+
+    fn open(path: &Path) -> io::Result<MailboxMap> {
+        MailboxMap::open(path)
+    }
+
+    const MAP: &str = "# Users
+                       John@Example.org
+                       jane@example.org    jane.doe@example.org
+                       info@example.org    jane.doe@example.org
+                       @example.net        postmaster@example.org
+                       @example.com";
+
+    #[test]
+    fn looks_up_mailboxes() {
+        let (_file, m) = map("mailbox-lookup", MAP, open);
+        let found = |a: &str| Mailbox::Found(email(a));
+        let lookup = |a: &str| m.lookup(&email(a));
+        assert_eq!(lookup("john@example.org"), found("John@Example.org"));
+        assert_eq!(lookup("JOHN@EXAMPLE.ORG"), found("John@Example.org"));
+        assert_eq!(lookup("jane@example.org"), found("jane.doe@example.org"));
+        assert_eq!(lookup("Info@example.org"), found("jane.doe@example.org"));
+        assert_eq!(lookup("jack@example.org"), Mailbox::Unknown);
+        assert_eq!(lookup("jack@example.net"), found("postmaster@example.org"));
+        assert_eq!(lookup("jack@Example.com"), found("jack@Example.com"));
+        assert_eq!(lookup("jack@example.info"), Mailbox::Foreign);
+        assert_eq!(lookup("jack@mail.example.org"), Mailbox::Foreign);
+
+        let file = TempFile::new("mailbox-bad");
+        for bad in &[
+            "john\n",
+            "john@\n",
+            "john@example.org a b\n",
+            "@example.org @\n",
+        ] {
+            fs::write(file.path(), bad).unwrap();
+            assert!(MailboxMap::open(file.path()).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn resolves_subaddresses() {
+        let (_file, m) = map("mailbox-subaddress", MAP, open);
+        let found = |a: &str| Mailbox::Found(email(a));
+        assert_eq!(
+            m.lookup(&email("john+lists@example.org")),
+            found("John@Example.org")
+        );
+        assert_eq!(
+            m.lookup(&email("jane+a+b@example.org")),
+            found("jane.doe@example.org")
+        );
+        assert_eq!(m.lookup(&email("jack+x@example.org")), Mailbox::Unknown);
+
+        let m = m.delimiter(Some('-'));
+        assert_eq!(m.lookup(&email("john+lists@example.org")), Mailbox::Unknown);
+        assert_eq!(
+            m.lookup(&email("john-lists@example.org")),
+            found("John@Example.org")
+        );
+        let m = m.delimiter(None);
+        assert_eq!(m.lookup(&email("john-lists@example.org")), Mailbox::Unknown);
+    }
+
+    #[test]
+    fn records_mailboxes() {
+        let mut rt = Runtime::new().unwrap();
+        let (_file, m) = map("mailbox-layer", MAP, open);
+        let mut accepted = Vec::new();
+        let mut conn_meta = ConnectionMetadata::new(());
+        let mut rcpt = |to: &str| {
+            let meta = MailMetadata {
+                from: None,
+                to:   Vec::new(),
+            };
+            let c = std::mem::replace(&mut conn_meta, ConnectionMetadata::new(()));
+            let (to, _, c, flow) = rt.block_on(m.filter_to(email(to), meta, c)).unwrap();
+            conn_meta = c;
+            let refused = refusal(&flow);
+            if refused.is_none() {
+                accepted.push(to);
+            }
+            refused
+        };
+        assert_eq!(rcpt("info+web@example.org"), None);
+        assert_eq!(rcpt("jack@example.info"), None);
+        assert_eq!(rcpt("jane@example.org"), None);
+        assert_eq!(
+            rcpt("Jack@example.org"),
+            Some((
+                550,
+                "5.1.1 <Jack@example.org>: Recipient address rejected: User unknown".to_owned()
+            ))
+        );
+
+        // The recipients stay as given, with their detail
+        let to = vec![
+            email("info+web@example.org"),
+            email("jack@example.info"),
+            email("jane@example.org"),
+        ];
+        assert_eq!(accepted, to);
+        let mailboxes = conn_meta.mail_state.get::<Mailboxes>().unwrap();
+        let jane = email("jane.doe@example.org");
+        assert_eq!(mailboxes.get(&email("info+web@example.org")), Some(&jane));
+        assert_eq!(mailboxes.get(&email("jack@example.info")), None);
+        assert_eq!(
+            mailboxes.targets(&to),
+            vec![jane, email("jack@example.info")]
+        );
+    }
+}
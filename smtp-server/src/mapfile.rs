@@ -0,0 +1,114 @@
+use std::{
+    cell::{Ref, RefCell},
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, SystemTime},
+};
+
+// Modification time and size of the file a table was read from
+type Stamp = (Option<SystemTime>, u64);
+
+// Coarsest granularity of modification times among the usual filesystems, in
+// seconds (FAT has 2)
+const MTIME_GRANULARITY: u64 = 2;
+
+struct Loaded<T> {
+    stamp: Stamp,
+    // When the file was last read, and the hash of what was read
+    read:  SystemTime,
+    hash:  u64,
+    table: T,
+}
+
+// Table parsed from a file (eg. by `AccessMap` or `MailboxMap`), that is read
+// again when it changed, on the next access. Clones share the same table.
+//
+// As a file rewritten with the same size soon after it was read may keep the
+// same modification time, the file is read again on each access until its
+// modification time is older than the last read by more than the granularity
+// of the filesystem, and parsed again only if its content changed.
+pub struct MapFile<T> {
+    path:   PathBuf,
+    parse:  fn(&[u8]) -> io::Result<T>,
+    loaded: Rc<RefCell<Loaded<T>>>,
+}
+
+impl<T> Clone for MapFile<T> {
+    fn clone(&self) -> MapFile<T> {
+        MapFile {
+            path:   self.path.clone(),
+            parse:  self.parse,
+            loaded: self.loaded.clone(),
+        }
+    }
+}
+
+impl<T> MapFile<T> {
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        parse: fn(&[u8]) -> io::Result<T>,
+    ) -> io::Result<MapFile<T>> {
+        let path = path.as_ref().to_owned();
+        let stamp = stamp(&path)?;
+        let read = SystemTime::now();
+        let content = fs::read(&path)?;
+        let loaded = Loaded {
+            stamp,
+            read,
+            hash: hash(&content),
+            table: parse(&content)?,
+        };
+        Ok(MapFile {
+            path,
+            parse,
+            loaded: Rc::new(RefCell::new(loaded)),
+        })
+    }
+
+    fn reload(&self) -> io::Result<()> {
+        let stamp = stamp(&self.path)?;
+        {
+            let loaded = self.loaded.borrow();
+            let racy = match stamp.0 {
+                Some(modified) => modified + Duration::from_secs(MTIME_GRANULARITY) >= loaded.read,
+                None => true,
+            };
+            if stamp == loaded.stamp && !racy {
+                return Ok(());
+            }
+        }
+        let read = SystemTime::now();
+        let content = fs::read(&self.path)?;
+        let hash = hash(&content);
+        let mut loaded = self.loaded.borrow_mut();
+        if hash != loaded.hash {
+            loaded.table = (self.parse)(&content)?;
+            loaded.hash = hash;
+        }
+        loaded.stamp = stamp;
+        loaded.read = read;
+        Ok(())
+    }
+
+    // Keeps using the table last read when the file cannot be read again,
+    // eg. while it is being replaced
+    pub fn table(&self) -> Ref<T> {
+        let _ = self.reload();
+        Ref::map(self.loaded.borrow(), |loaded| &loaded.table)
+    }
+}
+
+fn stamp(path: &Path) -> io::Result<Stamp> {
+    let meta = fs::metadata(path)?;
+    Ok((meta.modified().ok(), meta.len()))
+}
+
+fn hash(content: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
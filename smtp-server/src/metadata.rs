@@ -1,5 +1,13 @@
-use smtp_message::Email;
+use smtp_message::{Attributes, Email, SmtpString};
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    net::SocketAddr,
+};
 
+use clientcheck::{HeloCheck, ReverseDns};
+
+#[derive(Clone)]
 pub struct MailMetadata {
     pub from: Option<Email>,
     pub to:   Vec<Email>,
@@ -7,4 +15,132 @@ pub struct MailMetadata {
 
 pub struct ConnectionMetadata<U> {
     pub user: U,
+
+    // Addresses of the client and of the server, when the connection has some.
+    // Behind a proxy, these are the ones told by its PROXY protocol header
+    // (see `Server::proxy_protocol`).
+    pub peer_addr:  Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+
+    // Reverse DNS name of the client and user it logged in as, when told by a
+    // trusted proxy with XCLIENT (see `Config::xclient_trusted`), or for the
+    // former when confirmed by `ClientCheck`
+    pub peer_name: Option<SmtpString>,
+    pub login:     Option<SmtpString>,
+
+    // Results of the checks of the reverse DNS name of the client and of its
+    // HELO name, when done by `ClientCheck`
+    pub reverse_dns: ReverseDns,
+    pub helo_check:  Option<HeloCheck>,
+
+    // Whether the client greeted with HELO, EHLO or LHLO, and with which name
+    // (or the one told with XCLIENT HELO)
+    pub greeted: bool,
+    pub helo: Option<SmtpString>,
+    // Whether the client greeted with EHLO or LHLO, and is thus allowed to pipeline
+    // commands
+    pub pipelining: bool,
+    // Whether the client sent commands without waiting for the replies when it
+    // was not allowed to (before the greeting, before EHLO or after a
+    // synchronizing command)
+    pub illegal_pipelining: bool,
+    // Whether the client talked before the end of the banner, which a
+    // legitimate client never does (see `Config::pregreet_delay`)
+    pub early_talker: bool,
+    // Whether the client ended lines with a bare LF, as only allowed with
+    // `LineEndings::Lenient`. For the mail data, this is known only once
+    // `handle_mail` is done.
+    pub bare_lf: bool,
+
+    // Numbers of commands received, of commands refused for being invalid or
+    // unknown, and of recipients refused, as limited by the `Config::*_limit`
+    pub commands: usize,
+    pub errors: usize,
+    pub rejected_rcpts: usize,
+
+    // Maximum number of recipients of a mail, that overrides
+    // `Config::max_rcpts` when set (eg. by `Config::filter_from`, for
+    // authenticated users)
+    pub max_rcpts: Option<usize>,
+
+    // Attributes of the original client, as forwarded by a trusted content
+    // filter with XFORWARD (see `Config::xforward_trusted`) for the next mail
+    // transaction
+    pub xforward: Attributes,
+
+    // Sum of the weights of the DNS blocklists listing the client, and during
+    // a mail the domain of its sender (see `Dnsbl`)
+    pub dnsbl_score: i32,
+
+    // Values stored by the configuration (eg. by the layers of a `Chain`) for
+    // the whole connection, and for the current mail transaction only, the
+    // latter being emptied on each MAIL FROM
+    pub state:      State,
+    pub mail_state: State,
+}
+
+impl<U> ConnectionMetadata<U> {
+    pub fn new(user: U) -> ConnectionMetadata<U> {
+        ConnectionMetadata {
+            user,
+            peer_addr: None,
+            local_addr: None,
+            peer_name: None,
+            login: None,
+            reverse_dns: ReverseDns::Unchecked,
+            helo_check: None,
+            greeted: false,
+            helo: None,
+            pipelining: false,
+            illegal_pipelining: false,
+            early_talker: false,
+            bare_lf: false,
+            commands: 0,
+            errors: 0,
+            rejected_rcpts: 0,
+            max_rcpts: None,
+            xforward: Attributes::default(),
+            dnsbl_score: 0,
+            state: State::default(),
+            mail_state: State::default(),
+        }
+    }
+}
+
+// Map holding at most one value of each type, so that unrelated code can each
+// store its own without clashing
+#[derive(Default)]
+pub struct State(HashMap<TypeId, Box<Any>>);
+
+impl State {
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.0
+            .get(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_ref())
+    }
+
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.0
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_mut())
+    }
+
+    // Returns the value of the same type that was replaced, if any
+    pub fn insert<T: 'static>(&mut self, value: T) -> Option<T> {
+        self.0
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|v| v.downcast().ok())
+            .map(|v| *v)
+    }
+
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.0
+            .remove(&TypeId::of::<T>())
+            .and_then(|v| v.downcast().ok())
+            .map(|v| *v)
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear()
+    }
 }
@@ -0,0 +1,1081 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use smtp_message::{Email, ReplyCode, Sendable, SmtpString};
+use std::{io, net::SocketAddr};
+use tokio::{
+    io::{read_exact, write_all},
+    prelude::{
+        future::{self, Either, Loop},
+        *,
+    },
+};
+
+use decision::{is_reply_text, Decision, Refusal};
+use metadata::{ConnectionMetadata, MailMetadata};
+
+// Sendmail mail filter protocol, v6 (see libmilter's mfdef.h)
+const VERSION: u32 = 6;
+
+const SMFIC_ABORT: u8 = b'A';
+const SMFIC_BODY: u8 = b'B';
+const SMFIC_CONNECT: u8 = b'C';
+const SMFIC_BODYEOB: u8 = b'E';
+const SMFIC_HELO: u8 = b'H';
+const SMFIC_HEADER: u8 = b'L';
+const SMFIC_MAIL: u8 = b'M';
+const SMFIC_EOH: u8 = b'N';
+const SMFIC_OPTNEG: u8 = b'O';
+const SMFIC_QUIT: u8 = b'Q';
+const SMFIC_RCPT: u8 = b'R';
+const SMFIC_DATA: u8 = b'T';
+
+const SMFIR_ADDRCPT: u8 = b'+';
+const SMFIR_DELRCPT: u8 = b'-';
+const SMFIR_ADDRCPT_PAR: u8 = b'2';
+const SMFIR_SHUTDOWN: u8 = b'4';
+const SMFIR_ACCEPT: u8 = b'a';
+const SMFIR_REPLBODY: u8 = b'b';
+const SMFIR_CONTINUE: u8 = b'c';
+const SMFIR_DISCARD: u8 = b'd';
+const SMFIR_CHGFROM: u8 = b'e';
+const SMFIR_ADDHEADER: u8 = b'h';
+const SMFIR_INSHEADER: u8 = b'i';
+const SMFIR_CHGHEADER: u8 = b'm';
+const SMFIR_PROGRESS: u8 = b'p';
+const SMFIR_QUARANTINE: u8 = b'q';
+const SMFIR_REJECT: u8 = b'r';
+const SMFIR_SKIP: u8 = b's';
+const SMFIR_TEMPFAIL: u8 = b't';
+const SMFIR_REPLYCODE: u8 = b'y';
+
+// Actions the milter may take at the end of a message: all those of v6 but
+// changing the macros it is sent, as none is
+const ACTIONS: u32 = 0xff;
+
+// Protocol flags the milter may ask for, to not be told about an event or to
+// not reply to it
+const SMFIP_NOCONNECT: u32 = 0x1;
+const SMFIP_NOHELO: u32 = 0x2;
+const SMFIP_NOMAIL: u32 = 0x4;
+const SMFIP_NORCPT: u32 = 0x8;
+const SMFIP_NOBODY: u32 = 0x10;
+const SMFIP_NOHDRS: u32 = 0x20;
+const SMFIP_NOEOH: u32 = 0x40;
+const SMFIP_NR_HDR: u32 = 0x80;
+const SMFIP_NOUNKNOWN: u32 = 0x100;
+const SMFIP_NODATA: u32 = 0x200;
+const SMFIP_SKIP: u32 = 0x400;
+const SMFIP_NR_CONN: u32 = 0x1000;
+const SMFIP_NR_HELO: u32 = 0x2000;
+const SMFIP_NR_MAIL: u32 = 0x4000;
+const SMFIP_NR_RCPT: u32 = 0x8000;
+const SMFIP_NR_DATA: u32 = 0x10000;
+const SMFIP_NR_UNKN: u32 = 0x20000;
+const SMFIP_NR_EOH: u32 = 0x40000;
+const SMFIP_NR_BODY: u32 = 0x80000;
+const PROTOCOL: u32 = SMFIP_NOCONNECT
+    | SMFIP_NOHELO
+    | SMFIP_NOMAIL
+    | SMFIP_NORCPT
+    | SMFIP_NOBODY
+    | SMFIP_NOHDRS
+    | SMFIP_NOEOH
+    | SMFIP_NR_HDR
+    | SMFIP_NOUNKNOWN
+    | SMFIP_NODATA
+    | SMFIP_SKIP
+    | SMFIP_NR_CONN
+    | SMFIP_NR_HELO
+    | SMFIP_NR_MAIL
+    | SMFIP_NR_RCPT
+    | SMFIP_NR_DATA
+    | SMFIP_NR_UNKN
+    | SMFIP_NR_EOH
+    | SMFIP_NR_BODY;
+
+// Biggest packet accepted from the milter, and biggest body chunk sent to it
+const MAX_PACKET_LEN: usize = 1 << 20;
+const BODY_CHUNK_LEN: usize = 65535;
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Change to the mail asked for by the milter at the end of the message, to be
+// applied with `Verdict::apply`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Modification {
+    AddHeader(SmtpString, SmtpString),
+    // Inserts the header at this position among the headers, 0 being the top
+    InsertHeader(usize, SmtpString, SmtpString),
+    // Replaces the value of the n-th header (counting from 1) with this name,
+    // or removes it if the value is empty
+    ChangeHeader(usize, SmtpString, SmtpString),
+    ChangeFrom(Option<Email>),
+    AddRcpt(Email),
+    DelRcpt(Email),
+    // Chunk of the new body, that replaces the message body with all the
+    // chunks sent
+    ReplaceBody(Bytes),
+    // Asks for the mail to be put aside, for this reason
+    Quarantine(SmtpString),
+}
+
+// What the milter thinks of a message
+#[derive(Clone)]
+pub struct Verdict {
+    pub decision: Decision,
+    // Whether the mail is to be accepted, then silently dropped
+    pub discard: bool,
+    pub modifications: Vec<Modification>,
+}
+
+// Reply of the milter to an event
+enum Response {
+    Continue,
+    // Stop sending body chunks, only allowed with `SMFIP_SKIP`
+    Skip,
+    Accept,
+    Discard,
+    Refuse(Decision),
+}
+
+#[derive(Clone, Copy)]
+enum Scope {
+    Connection,
+    Mail,
+    Rcpt,
+}
+
+// Client of a milter, that gets told the events of a session and whose replies
+// are turned into `Decision`s. It is meant to be kept in the `Config`, and
+// called from `on_connect`, `filter_from`, `filter_to` and `handle_mail`, then
+// `on_disconnect`.
+pub struct Milter<IO> {
+    io: IO,
+    // Protocol flags asked for by the milter
+    protocol: u32,
+    // Set once the milter accepted the connection, or accepted or discarded
+    // the current mail, after which it is told nothing more about them
+    conn_done: bool,
+    mail_done: bool,
+    discard: bool,
+}
+
+impl<IO: 'static + AsyncRead + AsyncWrite> Milter<IO> {
+    // Negotiates the protocol version, actions and events with the milter
+    // listening on `io`
+    pub fn negotiate(io: IO) -> impl Future<Item = Milter<IO>, Error = io::Error> {
+        let mut data = Vec::with_capacity(12);
+        data.put_u32_be(VERSION);
+        data.put_u32_be(ACTIONS);
+        data.put_u32_be(PROTOCOL);
+        write_all(io, packet(SMFIC_OPTNEG, &data))
+            .and_then(|(io, _)| read_packet(io))
+            .and_then(|(io, cmd, data)| {
+                if cmd != SMFIC_OPTNEG || data.len() < 12 {
+                    return Err(invalid("invalid milter negotiation reply"));
+                }
+                let mut data = io::Cursor::new(data);
+                let version = data.get_u32_be();
+                let actions = data.get_u32_be();
+                let protocol = data.get_u32_be();
+                if version < 2 || actions & !ACTIONS != 0 || protocol & !PROTOCOL != 0 {
+                    return Err(invalid("milter asks for unsupported features"));
+                }
+                Ok(Milter {
+                    io,
+                    protocol,
+                    conn_done: false,
+                    mail_done: false,
+                    discard: false,
+                })
+            })
+    }
+
+    // Tells the milter about the client, from the name and address told by
+    // XCLIENT if any
+    pub fn connect<U>(
+        self,
+        conn_meta: &ConnectionMetadata<U>,
+    ) -> impl Future<Item = (Self, Decision), Error = io::Error> {
+        let mut data = Vec::new();
+        match (&conn_meta.peer_name, conn_meta.peer_addr) {
+            (Some(name), _) => push_str(&mut data, &name.bytes()[..]),
+            (None, Some(addr)) => push_str(&mut data, format!("[{}]", addr.ip()).as_bytes()),
+            (None, None) => push_str(&mut data, b"localhost"),
+        }
+        match conn_meta.peer_addr {
+            Some(addr) => {
+                data.push(if let SocketAddr::V4(_) = addr {
+                    b'4'
+                } else {
+                    b'6'
+                });
+                data.put_u16_be(addr.port());
+                push_str(&mut data, addr.ip().to_string().as_bytes());
+            }
+            None => data.push(b'U'),
+        }
+        self.event(
+            Scope::Connection,
+            (SMFIC_CONNECT, data, SMFIP_NOCONNECT, SMFIP_NR_CONN),
+        )
+    }
+
+    pub fn helo(
+        self,
+        helo: &SmtpString,
+    ) -> impl Future<Item = (Self, Decision), Error = io::Error> {
+        let mut data = Vec::new();
+        push_str(&mut data, &helo.bytes()[..]);
+        self.event(
+            Scope::Connection,
+            (SMFIC_HELO, data, SMFIP_NOHELO, SMFIP_NR_HELO),
+        )
+    }
+
+    // Starts a new mail, forgetting whether the milter accepted or discarded
+    // the previous one
+    pub fn mail(
+        mut self,
+        from: &Option<Email>,
+    ) -> impl Future<Item = (Self, Decision), Error = io::Error> {
+        self.mail_done = false;
+        self.discard = false;
+        let mut data = Vec::new();
+        push_addr(&mut data, from);
+        self.event(Scope::Mail, (SMFIC_MAIL, data, SMFIP_NOMAIL, SMFIP_NR_MAIL))
+    }
+
+    pub fn rcpt(self, to: &Email) -> impl Future<Item = (Self, Decision), Error = io::Error> {
+        let mut data = Vec::new();
+        push_addr(&mut data, to);
+        self.event(Scope::Rcpt, (SMFIC_RCPT, data, SMFIP_NORCPT, SMFIP_NR_RCPT))
+    }
+
+    // Sends the headers and body of the message, then collects the changes
+    // the milter asks for along with its final reply
+    pub fn message(self, mail: &[u8]) -> impl Future<Item = (Self, Verdict), Error = io::Error> {
+        if self.conn_done || self.mail_done {
+            let verdict = Verdict {
+                decision: Decision::Accept,
+                discard: self.discard,
+                modifications: Vec::new(),
+            };
+            return Either::A(future::ok((self, verdict)));
+        }
+        let (headers, body) = split_headers(mail);
+        let mut steps = vec![(SMFIC_DATA, Vec::new(), SMFIP_NODATA, SMFIP_NR_DATA)];
+        for h in headers {
+            let mut data = Vec::new();
+            push_str(&mut data, h.name);
+            push_str(&mut data, &to_lf(h.value));
+            steps.push((SMFIC_HEADER, data, SMFIP_NOHDRS, SMFIP_NR_HDR));
+        }
+        steps.push((SMFIC_EOH, Vec::new(), SMFIP_NOEOH, SMFIP_NR_EOH));
+        for chunk in body.chunks(BODY_CHUNK_LEN) {
+            steps.push((SMFIC_BODY, chunk.to_vec(), SMFIP_NOBODY, SMFIP_NR_BODY));
+        }
+        let sent = future::loop_fn((self, steps.into_iter()), |(m, mut steps)| {
+            match steps.next() {
+                None => Either::A(future::ok(Loop::Break((m, None)))),
+                Some(step) => Either::B(m.send(step).map(move |(m, resp)| match resp {
+                    Response::Continue => Loop::Continue((m, steps)),
+                    // The body chunks being the last step, skipping them is
+                    // going to the end of the message
+                    Response::Skip => Loop::Break((m, None)),
+                    resp => Loop::Break((m, Some(resp))),
+                })),
+            }
+        });
+        Either::B(sent.and_then(|(m, resp)| match resp {
+            Some(resp) => Either::A(future::ok(m.verdict(resp, Vec::new()))),
+            None => Either::B(m.end_of_message()),
+        }))
+    }
+
+    // Tells the milter that the current mail is over without it having seen
+    // the end of the message (eg. on RSET, or when all recipients were
+    // refused)
+    pub fn abort(mut self) -> impl Future<Item = Self, Error = io::Error> {
+        self.mail_done = false;
+        self.discard = false;
+        if self.conn_done {
+            return Either::A(future::ok(self));
+        }
+        let Milter {
+            io,
+            protocol,
+            conn_done,
+            mail_done,
+            discard,
+        } = self;
+        Either::B(
+            write_all(io, packet(SMFIC_ABORT, &[])).map(move |(io, _)| Milter {
+                io,
+                protocol,
+                conn_done,
+                mail_done,
+                discard,
+            }),
+        )
+    }
+
+    pub fn quit(self) -> impl Future<Item = (), Error = io::Error> {
+        write_all(self.io, packet(SMFIC_QUIT, &[]))
+            .and_then(|(mut io, _)| future::poll_fn(move || io.shutdown()))
+    }
+
+    fn event(
+        self,
+        scope: Scope,
+        step: (u8, Vec<u8>, u32, u32),
+    ) -> impl Future<Item = (Self, Decision), Error = io::Error> {
+        let done = match scope {
+            Scope::Connection => self.conn_done,
+            Scope::Mail | Scope::Rcpt => self.conn_done || self.mail_done,
+        };
+        if done {
+            return Either::A(future::ok((self, Decision::Accept)));
+        }
+        Either::B(self.send(step).map(move |(mut m, resp)| {
+            let decision = m.decide(scope, resp);
+            (m, decision)
+        }))
+    }
+
+    // Sends the event, unless the milter asked not to be told about it, and
+    // reads the reply, unless the milter asked not to send one
+    fn send(
+        self,
+        (cmd, data, no_send, no_reply): (u8, Vec<u8>, u32, u32),
+    ) -> impl Future<Item = (Self, Response), Error = io::Error> {
+        if self.protocol & no_send != 0 {
+            return Either::A(future::ok((self, Response::Continue)));
+        }
+        let Milter {
+            io,
+            protocol,
+            conn_done,
+            mail_done,
+            discard,
+        } = self;
+        let rebuild = move |io| Milter {
+            io,
+            protocol,
+            conn_done,
+            mail_done,
+            discard,
+        };
+        Either::B(write_all(io, packet(cmd, &data)).and_then(move |(io, _)| {
+            if protocol & no_reply != 0 {
+                return Either::A(future::ok((rebuild(io), Response::Continue)));
+            }
+            Either::B(
+                read_packet(io)
+                    .and_then(move |(io, cmd, data)| Ok((rebuild(io), response(cmd, &data)?))),
+            )
+        }))
+    }
+
+    fn end_of_message(self) -> impl Future<Item = (Self, Verdict), Error = io::Error> {
+        let Milter {
+            io,
+            protocol,
+            conn_done,
+            mail_done,
+            discard,
+        } = self;
+        write_all(io, packet(SMFIC_BODYEOB, &[]))
+            .and_then(|(io, _)| {
+                future::loop_fn((io, Vec::new()), |(io, mut mods)| {
+                    read_packet(io).and_then(move |(io, cmd, data)| {
+                        if cmd == SMFIR_PROGRESS {
+                            return Ok(Loop::Continue((io, mods)));
+                        }
+                        match modification(cmd, &data)? {
+                            Some(m) => {
+                                mods.push(m);
+                                Ok(Loop::Continue((io, mods)))
+                            }
+                            None => Ok(Loop::Break((io, mods, response(cmd, &data)?))),
+                        }
+                    })
+                })
+            })
+            .map(move |(io, mods, resp)| {
+                let m = Milter {
+                    io,
+                    protocol,
+                    conn_done,
+                    mail_done,
+                    discard,
+                };
+                m.verdict(resp, mods)
+            })
+    }
+
+    fn verdict(mut self, resp: Response, modifications: Vec<Modification>) -> (Self, Verdict) {
+        let decision = self.decide(Scope::Mail, resp);
+        let verdict = Verdict {
+            decision,
+            discard: self.discard,
+            modifications,
+        };
+        (self, verdict)
+    }
+
+    fn decide(&mut self, scope: Scope, resp: Response) -> Decision {
+        match (resp, scope) {
+            (Response::Continue, _) | (Response::Skip, _) => Decision::Accept,
+            (Response::Accept, Scope::Connection) => {
+                self.conn_done = true;
+                Decision::Accept
+            }
+            (Response::Accept, Scope::Mail) => {
+                self.mail_done = true;
+                Decision::Accept
+            }
+            // Accepting a recipient says nothing of the other ones
+            (Response::Accept, Scope::Rcpt) => Decision::Accept,
+            (Response::Discard, _) => {
+                self.mail_done = true;
+                self.discard = true;
+                Decision::Accept
+            }
+            (Response::Refuse(decision), _) => decision,
+        }
+    }
+}
+
+impl Verdict {
+    // Applies the changes to the envelope, and returns the mail with the
+    // changes to its headers and body. Quarantining is left to the caller.
+    pub fn apply(&self, meta: &mut MailMetadata, mail: &[u8]) -> BytesMut {
+        let (headers, body) = split_headers(mail);
+        let mut headers = headers
+            .into_iter()
+            .map(|h| (h.name.to_vec(), h.raw.to_vec()))
+            .collect::<Vec<_>>();
+        let mut new_body: Option<Vec<u8>> = None;
+        for m in self.modifications.iter() {
+            match m {
+                Modification::AddHeader(name, value) => {
+                    headers.push((name.bytes().to_vec(), header_line(name, value)))
+                }
+                Modification::InsertHeader(idx, name, value) => {
+                    let idx = std::cmp::min(*idx, headers.len());
+                    headers.insert(idx, (name.bytes().to_vec(), header_line(name, value)));
+                }
+                Modification::ChangeHeader(idx, name, value) => {
+                    let pos = headers
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, (n, _))| n.eq_ignore_ascii_case(&name.bytes()[..]))
+                        .map(|(pos, _)| pos)
+                        .nth(idx.saturating_sub(1));
+                    match pos {
+                        Some(pos) if value.byte_len() == 0 => {
+                            headers.remove(pos);
+                        }
+                        Some(pos) => headers[pos].1 = header_line(name, value),
+                        // Changing a missing header adds it
+                        None if value.byte_len() != 0 => {
+                            headers.push((name.bytes().to_vec(), header_line(name, value)))
+                        }
+                        None => (),
+                    }
+                }
+                Modification::ChangeFrom(from) => meta.from = from.clone(),
+                Modification::AddRcpt(to) => {
+                    if !meta.to.contains(to) {
+                        meta.to.push(to.clone());
+                    }
+                }
+                Modification::DelRcpt(to) => meta.to.retain(|t| t != to),
+                Modification::ReplaceBody(chunk) => new_body
+                    .get_or_insert_with(Vec::new)
+                    .extend_from_slice(&chunk[..]),
+                Modification::Quarantine(_) => (),
+            }
+        }
+        let body = new_body.as_ref().map(|b| &b[..]).unwrap_or(body);
+        let mut res = BytesMut::with_capacity(mail.len());
+        for (_, raw) in headers {
+            res.extend_from_slice(&raw);
+        }
+        res.extend_from_slice(b"\r\n");
+        res.extend_from_slice(body);
+        res
+    }
+}
+
+fn packet(cmd: u8, data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(5 + data.len());
+    res.put_u32_be(data.len() as u32 + 1);
+    res.put_u8(cmd);
+    res.put_slice(data);
+    res
+}
+
+fn read_packet<IO: AsyncRead>(io: IO) -> impl Future<Item = (IO, u8, Bytes), Error = io::Error> {
+    read_exact(io, [0; 4]).and_then(|(io, len)| {
+        let len = io::Cursor::new(len).get_u32_be() as usize;
+        if len == 0 || len > MAX_PACKET_LEN {
+            return Either::A(future::err(invalid("invalid milter packet length")));
+        }
+        Either::B(read_exact(io, vec![0; len]).map(|(io, buf)| {
+            let mut buf = Bytes::from(buf);
+            let cmd = buf.split_to(1)[0];
+            (io, cmd, buf)
+        }))
+    })
+}
+
+// Strings are NUL-terminated
+fn push_str(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.extend_from_slice(s);
+    buf.push(0);
+}
+
+fn strs(data: &[u8]) -> Vec<&[u8]> {
+    let mut res = data.split(|&c| c == 0).collect::<Vec<_>>();
+    if res.last().map(|s| s.is_empty()).unwrap_or(false) {
+        res.pop();
+    }
+    res
+}
+
+fn push_addr<T: Sendable>(buf: &mut Vec<u8>, addr: &T) {
+    buf.push(b'<');
+    addr.send_to(buf).unwrap();
+    buf.push(b'>');
+    buf.push(0);
+}
+
+fn parse_addr(addr: &[u8]) -> io::Result<Option<Email>> {
+    let addr = if addr.starts_with(b"<") && addr.ends_with(b">") {
+        &addr[1..addr.len() - 1]
+    } else {
+        addr
+    };
+    if addr.is_empty() {
+        return Ok(None);
+    }
+    Email::parse_slice(addr)
+        .map(Some)
+        .map_err(|_| invalid("invalid address from milter"))
+}
+
+fn response(cmd: u8, data: &[u8]) -> io::Result<Response> {
+    let refusal = |code, msg: &'static [u8]| Refusal {
+        code: ReplyCode::custom(code),
+        msg:  SmtpString::from_static(msg),
+    };
+    match cmd {
+        SMFIR_CONTINUE => Ok(Response::Continue),
+        SMFIR_SKIP => Ok(Response::Skip),
+        SMFIR_ACCEPT => Ok(Response::Accept),
+        SMFIR_DISCARD => Ok(Response::Discard),
+        SMFIR_REJECT => Ok(Response::Refuse(Decision::Reject(refusal(
+            550,
+            b"5.7.1 Command rejected",
+        )))),
+        SMFIR_TEMPFAIL => Ok(Response::Refuse(Decision::Reject(refusal(
+            451,
+            b"4.7.1 Service unavailable - try again later",
+        )))),
+        SMFIR_SHUTDOWN => Ok(Response::Refuse(Decision::RejectAndClose(refusal(
+            421,
+            b"4.7.0 Closing connection",
+        )))),
+        SMFIR_REPLYCODE => reply_code(data).map(Response::Refuse),
+        _ => Err(invalid("unexpected milter reply")),
+    }
+}
+
+// Custom reply, like `550 5.7.1 Message refused`, of which only the first line
+// is kept. Its text has to be fit for a reply, as it is sent to the client.
+fn reply_code(data: &[u8]) -> io::Result<Decision> {
+    let line = strs(data).into_iter().next().unwrap_or(b"");
+    let line = line
+        .split(|&c| c == b'\r' || c == b'\n')
+        .next()
+        .unwrap_or(b"");
+    if line.len() < 3
+        || !(line[0] == b'4' || line[0] == b'5')
+        || !line[..3].iter().all(u8::is_ascii_digit)
+        || line
+            .get(3)
+            .map(|&c| c != b' ' && c != b'-')
+            .unwrap_or(false)
+    {
+        return Err(invalid("invalid reply code from milter"));
+    }
+    let text = line.get(4..).unwrap_or(b"");
+    if !is_reply_text(text) {
+        return Err(invalid("invalid reply text from milter"));
+    }
+    let code = line[..3]
+        .iter()
+        .fold(0, |acc, c| acc * 10 + (c - b'0') as u16);
+    let refusal = Refusal {
+        code: ReplyCode::custom(code),
+        msg:  SmtpString::from(text),
+    };
+    if code == 421 {
+        Ok(Decision::RejectAndClose(refusal))
+    } else {
+        Ok(Decision::Reject(refusal))
+    }
+}
+
+fn modification(cmd: u8, data: &[u8]) -> io::Result<Option<Modification>> {
+    let bad = || invalid("invalid modification from milter");
+    let header = |data: &[u8]| -> io::Result<(usize, SmtpString, SmtpString)> {
+        if data.len() < 4 {
+            return Err(bad());
+        }
+        let idx = io::Cursor::new(&data[..4]).get_u32_be() as usize;
+        match &strs(&data[4..])[..] {
+            [name, value] => Ok((idx, SmtpString::from(*name), SmtpString::from(*value))),
+            // An empty value is not sent at all by some milters
+            [name] => Ok((idx, SmtpString::from(*name), SmtpString::from(&b""[..]))),
+            _ => Err(bad()),
+        }
+    };
+    let rcpt = |data: &[u8]| match strs(data).first() {
+        Some(addr) => parse_addr(addr)?.ok_or_else(bad),
+        None => Err(bad()),
+    };
+    Ok(Some(match cmd {
+        SMFIR_ADDHEADER => match &strs(data)[..] {
+            [name, value] => Modification::AddHeader((*name).into(), (*value).into()),
+            _ => return Err(bad()),
+        },
+        SMFIR_INSHEADER => {
+            let (idx, name, value) = header(data)?;
+            Modification::InsertHeader(idx, name, value)
+        }
+        SMFIR_CHGHEADER => {
+            let (idx, name, value) = header(data)?;
+            Modification::ChangeHeader(idx, name, value)
+        }
+        SMFIR_CHGFROM => match strs(data).first() {
+            Some(addr) => Modification::ChangeFrom(parse_addr(addr)?),
+            None => return Err(bad()),
+        },
+        // ESMTP parameters of the recipient are not supported, and dropped
+        SMFIR_ADDRCPT | SMFIR_ADDRCPT_PAR => Modification::AddRcpt(rcpt(data)?),
+        SMFIR_DELRCPT => Modification::DelRcpt(rcpt(data)?),
+        SMFIR_REPLBODY => Modification::ReplaceBody(Bytes::from(data)),
+        SMFIR_QUARANTINE => Modification::Quarantine(SmtpString::from(
+            strs(data).into_iter().next().unwrap_or(b""),
+        )),
+        _ => return Ok(None),
+    }))
+}
+
+struct Header<'a> {
+    name:  &'a [u8],
+    // Without the leading whitespace and the final line ending
+    value: &'a [u8],
+    // The whole header, line endings included
+    raw:   &'a [u8],
+}
+
+// Splits the mail into its headers and body. The headers end at the first
+// empty line, or at the first line that is not a header.
+fn split_headers(mail: &[u8]) -> (Vec<Header>, &[u8]) {
+    // Start, colon and end of each header
+    let mut headers: Vec<(usize, usize, usize)> = Vec::new();
+    let mut pos = 0;
+    let mut body = mail.len();
+    while pos < mail.len() {
+        let end = mail[pos..]
+            .iter()
+            .position(|&c| c == b'\n')
+            .map(|i| pos + i + 1)
+            .unwrap_or(mail.len());
+        let line = &mail[pos..end];
+        if line == b"\r\n" || line == b"\n" {
+            body = end;
+            break;
+        }
+        let folded = line[0] == b' ' || line[0] == b'\t';
+        let colon = line.iter().position(|&c| c == b':');
+        match (headers.last_mut(), colon) {
+            (Some(last), _) if folded => last.2 = end,
+            (_, Some(colon))
+                if colon > 0 && line[..colon].iter().all(|&c| c > b' ' && c <= b'~') =>
+            {
+                headers.push((pos, pos + colon, end))
+            }
+            _ => {
+                body = pos;
+                break;
+            }
+        }
+        pos = end;
+    }
+    let headers = headers
+        .into_iter()
+        .map(|(start, colon, end)| {
+            let mut value = &mail[colon + 1..end];
+            while value.ends_with(b"\n") || value.ends_with(b"\r") {
+                value = &value[..value.len() - 1];
+            }
+            while value.starts_with(b" ") || value.starts_with(b"\t") {
+                value = &value[1..];
+            }
+            Header {
+                name: &mail[start..colon],
+                value,
+                raw: &mail[start..end],
+            }
+        })
+        .collect();
+    (headers, &mail[body..])
+}
+
+// Folded header values are sent to milters with LF line endings, and come
+// back from them so
+fn to_lf(value: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(value.len());
+    for (i, &c) in value.iter().enumerate() {
+        if !(c == b'\r' && value.get(i + 1) == Some(&b'\n')) {
+            res.push(c);
+        }
+    }
+    res
+}
+
+fn header_line(name: &SmtpString, value: &SmtpString) -> Vec<u8> {
+    let mut res = name.bytes().to_vec();
+    res.extend_from_slice(b": ");
+    for &c in value.iter_bytes() {
+        if c == b'\n' && res.last() != Some(&b'\r') {
+            res.push(b'\r');
+        }
+        res.push(c);
+    }
+    res.extend_from_slice(b"\r\n");
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+    #[cfg(unix)]
+    use tokio::{
+        net::{UnixListener, UnixStream},
+        runtime::current_thread::Runtime,
+    };
+
+    use testutil::email;
+    #[cfg(unix)]
+    use testutil::TempFile;
+
+    type Events = Rc<RefCell<Vec<(u8, Bytes)>>>;
+
+    // Milter negotiating these protocol flags, then answering each event with
+    // the packets `reply` gives, and recording the events
+    #[cfg(unix)]
+    fn fake_milter(
+        name: &str,
+        protocol: u32,
+        reply: fn(u8, &[u8]) -> Vec<(u8, Vec<u8>)>,
+    ) -> (TempFile, impl Future<Item = (), Error = ()>, Events) {
+        let socket = TempFile::new(&format!("milter-{}", name));
+        let listener = UnixListener::bind(socket.path()).unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let recorded = events.clone();
+        let milter = listener
+            .incoming()
+            .into_future()
+            .map_err(|_| ())
+            .and_then(move |(io, _)| {
+                future::loop_fn(io.unwrap(), move |io| {
+                    let events = events.clone();
+                    read_packet(io).and_then(move |(io, cmd, data)| {
+                        events.borrow_mut().push((cmd, data.clone()));
+                        let mut out = Vec::new();
+                        if cmd == SMFIC_OPTNEG {
+                            let mut neg = Vec::new();
+                            neg.put_u32_be(VERSION);
+                            neg.put_u32_be(ACTIONS);
+                            neg.put_u32_be(protocol);
+                            out.extend(packet(SMFIC_OPTNEG, &neg));
+                        }
+                        for (cmd, data) in reply(cmd, &data) {
+                            out.extend(packet(cmd, &data));
+                        }
+                        write_all(io, out).map(move |(io, _)| match cmd {
+                            SMFIC_QUIT => Loop::Break(()),
+                            _ => Loop::Continue(io),
+                        })
+                    })
+                })
+                .map_err(|_| ())
+            });
+        (socket, milter, recorded)
+    }
+
+    fn refusal(decision: &Decision) -> Option<(u16, SmtpString, bool)> {
+        match decision {
+            Decision::Accept | Decision::AcceptWith(_) => None,
+            Decision::Reject(r) => Some((r.code.code(), r.msg.clone(), false)),
+            Decision::RejectAndClose(r) => Some((r.code.code(), r.msg.clone(), true)),
+            Decision::Delay(..) => panic!("milters do not delay"),
+        }
+    }
+
+    fn commands(events: &Events) -> Vec<u8> {
+        events.borrow().iter().map(|(cmd, _)| *cmd).collect()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn maps_replies_to_decisions() {
+        let (socket, milter, events) = fake_milter("decisions", 0, |cmd, data| match cmd {
+            SMFIC_OPTNEG | SMFIC_ABORT | SMFIC_QUIT => vec![],
+            SMFIC_MAIL if data.starts_with(b"<spam@") => vec![(SMFIR_REJECT, vec![])],
+            SMFIC_MAIL if data.starts_with(b"<dull@") => vec![(SMFIR_DISCARD, vec![])],
+            SMFIC_RCPT if data.starts_with(b"<busy@") => vec![(SMFIR_TEMPFAIL, vec![])],
+            SMFIC_RCPT if data.starts_with(b"<gone@") => {
+                vec![(SMFIR_REPLYCODE, b"550 5.1.1 No such user\0".to_vec())]
+            }
+            SMFIC_RCPT if data.starts_with(b"<evil@") => vec![(SMFIR_SHUTDOWN, vec![])],
+            _ => vec![(SMFIR_CONTINUE, vec![])],
+        });
+        let mut rt = Runtime::new().unwrap();
+        rt.spawn(milter);
+
+        let mut conn_meta = ConnectionMetadata::new(());
+        conn_meta.peer_addr = Some("192.0.2.1:4567".parse().unwrap());
+        let m = rt
+            .block_on(UnixStream::connect(socket.path()).and_then(Milter::negotiate))
+            .unwrap();
+        let mut decisions = Vec::new();
+        let (m, d) = rt.block_on(m.connect(&conn_meta)).unwrap();
+        decisions.push(d);
+        let (m, d) = rt.block_on(m.helo(&"foo.example.org".into())).unwrap();
+        decisions.push(d);
+        let (m, d) = rt
+            .block_on(m.mail(&Some(email("spam@example.org"))))
+            .unwrap();
+        decisions.push(d);
+        let m = rt.block_on(m.abort()).unwrap();
+        let (mut m, d) = rt.block_on(m.mail(&None)).unwrap();
+        decisions.push(d);
+        for to in &["busy@example.org", "gone@example.org", "jdoe@example.org"] {
+            let (m2, d) = rt.block_on(m.rcpt(&email(to))).unwrap();
+            decisions.push(d);
+            m = m2;
+        }
+        let m = rt.block_on(m.abort()).unwrap();
+        let (m, d) = rt
+            .block_on(m.mail(&Some(email("dull@example.org"))))
+            .unwrap();
+        decisions.push(d);
+        // The milter is not told about a mail it discarded
+        let (m, d) = rt.block_on(m.rcpt(&email("evil@example.org"))).unwrap();
+        decisions.push(d);
+        let (m, verdict) = rt
+            .block_on(m.message(b"Subject: hi\r\n\r\nHello\r\n"))
+            .unwrap();
+        assert!(verdict.discard);
+        decisions.push(verdict.decision);
+        let m = rt.block_on(m.abort()).unwrap();
+        let (m, d) = rt.block_on(m.mail(&None)).unwrap();
+        decisions.push(d);
+        let (m, d) = rt.block_on(m.rcpt(&email("evil@example.org"))).unwrap();
+        decisions.push(d);
+        rt.block_on(m.quit()).unwrap();
+        rt.run().unwrap();
+
+        let decisions = decisions.iter().map(refusal).collect::<Vec<_>>();
+        assert_eq!(
+            decisions,
+            vec![
+                None,
+                None,
+                Some((550, "5.7.1 Command rejected".into(), false)),
+                None,
+                Some((
+                    451,
+                    "4.7.1 Service unavailable - try again later".into(),
+                    false
+                )),
+                Some((550, "5.1.1 No such user".into(), false)),
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some((421, "4.7.0 Closing connection".into(), true)),
+            ]
+        );
+        assert_eq!(commands(&events), b"OCHMAMRRRAMAMRQ".to_vec());
+        assert_eq!(
+            events.borrow()[1].1,
+            Bytes::from(&b"[192.0.2.1]\x004\x11\xd7192.0.2.1\x00"[..])
+        );
+        assert_eq!(
+            events.borrow()[3].1,
+            Bytes::from(&b"<spam@example.org>\x00"[..])
+        );
+        assert_eq!(events.borrow()[5].1, Bytes::from(&b"<>\x00"[..]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_invalid_reply_texts() {
+        let (socket, milter, _) = fake_milter("replytext", 0, |cmd, _| match cmd {
+            SMFIC_OPTNEG | SMFIC_QUIT => vec![],
+            SMFIC_RCPT => vec![(
+                SMFIR_REPLYCODE,
+                b"550 5.1.1 Utilisateur inconnu \xe9\x1b[2J\0".to_vec(),
+            )],
+            _ => vec![(SMFIR_CONTINUE, vec![])],
+        });
+        let mut rt = Runtime::new().unwrap();
+        rt.spawn(milter);
+
+        let m = rt
+            .block_on(UnixStream::connect(socket.path()).and_then(Milter::negotiate))
+            .unwrap();
+        let (m, _) = rt.block_on(m.mail(&None)).unwrap();
+        let err = rt.block_on(m.rcpt(&email("jdoe@example.org"))).err();
+        assert_eq!(err.map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_unwanted_events() {
+        let protocol = SMFIP_NOCONNECT
+            | SMFIP_NOHELO
+            | SMFIP_NR_MAIL
+            | SMFIP_NR_RCPT
+            | SMFIP_NOHDRS
+            | SMFIP_NR_EOH
+            | SMFIP_SKIP;
+        let (socket, milter, events) = fake_milter("skips", protocol, |cmd, _| match cmd {
+            SMFIC_DATA | SMFIC_BODYEOB => vec![(SMFIR_CONTINUE, vec![])],
+            SMFIC_BODY => vec![(SMFIR_SKIP, vec![])],
+            _ => vec![],
+        });
+        let mut rt = Runtime::new().unwrap();
+        rt.spawn(milter);
+
+        let mut mail = b"Subject: hi\r\n\r\n".to_vec();
+        mail.extend(std::iter::repeat(b'a').take(2 * BODY_CHUNK_LEN));
+        let m = rt
+            .block_on(UnixStream::connect(socket.path()).and_then(Milter::negotiate))
+            .unwrap();
+        let (m, _) = rt
+            .block_on(m.connect(&ConnectionMetadata::new(())))
+            .unwrap();
+        let (m, _) = rt.block_on(m.helo(&"foo.example.org".into())).unwrap();
+        let (m, _) = rt.block_on(m.mail(&None)).unwrap();
+        let (m, _) = rt.block_on(m.rcpt(&email("jdoe@example.org"))).unwrap();
+        let (m, verdict) = rt.block_on(m.message(&mail)).unwrap();
+        rt.block_on(m.quit()).unwrap();
+        rt.run().unwrap();
+
+        assert!(refusal(&verdict.decision).is_none());
+        assert!(!verdict.discard);
+        assert!(verdict.modifications.is_empty());
+        assert_eq!(commands(&events), b"OMRTNBEQ".to_vec());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn collects_modifications() {
+        let (socket, milter, events) = fake_milter("modifications", 0, |cmd, _| match cmd {
+            SMFIC_OPTNEG | SMFIC_QUIT => vec![],
+            SMFIC_BODYEOB => vec![
+                (SMFIR_PROGRESS, vec![]),
+                (SMFIR_ADDHEADER, b"X-Spam\0yes\0".to_vec()),
+                (SMFIR_CHGHEADER, b"\0\0\0\x01Subject\0[SPAM] hi\0".to_vec()),
+                (SMFIR_ADDRCPT, b"<quarantine@example.org>\0".to_vec()),
+                (SMFIR_DELRCPT, b"<jdoe@example.org>\0".to_vec()),
+                (SMFIR_ACCEPT, vec![]),
+            ],
+            _ => vec![(SMFIR_CONTINUE, vec![])],
+        });
+        let mut rt = Runtime::new().unwrap();
+        rt.spawn(milter);
+
+        let mail = b"Subject: hi\r\nX-Folded: a\r\n\tb\r\n\r\nHello\r\n";
+        let m = rt
+            .block_on(UnixStream::connect(socket.path()).and_then(Milter::negotiate))
+            .unwrap();
+        let (m, _) = rt.block_on(m.mail(&None)).unwrap();
+        let (m, verdict) = rt.block_on(m.message(mail)).unwrap();
+        rt.block_on(m.quit()).unwrap();
+        rt.run().unwrap();
+
+        assert!(refusal(&verdict.decision).is_none());
+        assert_eq!(
+            verdict.modifications,
+            vec![
+                Modification::AddHeader("X-Spam".into(), "yes".into()),
+                Modification::ChangeHeader(1, "Subject".into(), "[SPAM] hi".into()),
+                Modification::AddRcpt(email("quarantine@example.org")),
+                Modification::DelRcpt(email("jdoe@example.org")),
+            ]
+        );
+        assert_eq!(commands(&events), b"OMTLLNBEQ".to_vec());
+        assert_eq!(
+            events.borrow()[4].1,
+            Bytes::from(&b"X-Folded\0a\n\tb\0"[..])
+        );
+        assert_eq!(events.borrow()[6].1, Bytes::from(&b"Hello\r\n"[..]));
+
+        let mut meta = MailMetadata {
+            from: None,
+            to:   vec![email("jdoe@example.org"), email("foo@example.org")],
+        };
+        let res = verdict.apply(&mut meta, mail);
+        assert_eq!(
+            &res[..],
+            &b"Subject: [SPAM] hi\r\nX-Folded: a\r\n\tb\r\nX-Spam: yes\r\n\r\nHello\r\n"[..]
+        );
+        assert_eq!(
+            meta.to,
+            vec![email("foo@example.org"), email("quarantine@example.org")]
+        );
+    }
+
+    #[test]
+    fn applies_modifications() {
+        let verdict = Verdict {
+            decision: Decision::Accept,
+            discard: false,
+            modifications: vec![
+                Modification::ChangeHeader(2, "received".into(), "".into()),
+                Modification::InsertHeader(0, "Received".into(), "by milter\n\tfor you".into()),
+                Modification::ChangeHeader(1, "X-Missing".into(), "here".into()),
+                Modification::ChangeFrom(Some(email("bounces@example.org"))),
+                Modification::ReplaceBody(Bytes::from(&b"New "[..])),
+                Modification::ReplaceBody(Bytes::from(&b"body\r\n"[..])),
+                Modification::Quarantine("spam".into()),
+            ],
+        };
+        let mut meta = MailMetadata {
+            from: None,
+            to:   vec![email("jdoe@example.org")],
+        };
+        let mail = b"Received: by mx\r\nReceived: by relay\r\nnot a header\r\n";
+        let res = verdict.apply(&mut meta, mail);
+        assert_eq!(
+            &res[..],
+            &b"Received: by milter\r\n\tfor you\r\nReceived: by mx\r\nX-Missing: here\r\n\
+               \r\nNew body\r\n"[..]
+        );
+        assert_eq!(meta.from, Some(email("bounces@example.org")));
+        assert_eq!(meta.to, vec![email("jdoe@example.org")]);
+    }
+}
@@ -0,0 +1,330 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::{
+    cmp, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str,
+};
+use tokio::prelude::*;
+
+// See https://www.haproxy.org/download/1.8/doc/proxy-protocol.txt
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+// Original source and destination addresses of a proxied connection
+pub type ProxyAddrs = (SocketAddr, SocketAddr);
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Header {
+    // More bytes are needed to tell
+    Incomplete,
+    Invalid,
+    // The header is that many bytes long. The addresses are missing when the
+    // proxy does not know them or opened the connection itself (eg. for a
+    // health check), in which case those of the connection are to be used.
+    Complete(usize, Option<ProxyAddrs>),
+}
+
+// Parses the PROXY protocol header, v1 or v2, at the start of `buf`
+pub fn parse_header(buf: &[u8]) -> Header {
+    let n = cmp::min(buf.len(), V2_SIGNATURE.len());
+    if buf[..n] == V2_SIGNATURE[..n] {
+        if n < V2_SIGNATURE.len() {
+            return Header::Incomplete;
+        }
+        return parse_v2(buf);
+    }
+    let n = cmp::min(buf.len(), V1_PREFIX.len());
+    if buf[..n] == V1_PREFIX[..n] {
+        if n < V1_PREFIX.len() {
+            return Header::Incomplete;
+        }
+        return parse_v1(buf);
+    }
+    Header::Invalid
+}
+
+fn parse_v1(buf: &[u8]) -> Header {
+    let end = match buf
+        .windows(2)
+        .take(V1_MAX_LEN - 1)
+        .position(|w| w == b"\r\n")
+    {
+        Some(end) => end,
+        None if buf.len() >= V1_MAX_LEN => return Header::Invalid,
+        None => return Header::Incomplete,
+    };
+    let line = match str::from_utf8(&buf[V1_PREFIX.len()..end]) {
+        Ok(line) => line,
+        Err(_) => return Header::Invalid,
+    };
+    let fields = line.split(' ').collect::<Vec<_>>();
+    // Whatever follows UNKNOWN is to be ignored
+    if fields[0] == "UNKNOWN" {
+        return Header::Complete(end + 2, None);
+    }
+    if fields.len() != 5 {
+        return Header::Invalid;
+    }
+    let ips = match fields[0] {
+        "TCP4" => fields[1]
+            .parse::<Ipv4Addr>()
+            .and_then(|src| Ok((IpAddr::V4(src), IpAddr::V4(fields[2].parse()?)))),
+        "TCP6" => fields[1]
+            .parse::<Ipv6Addr>()
+            .and_then(|src| Ok((IpAddr::V6(src), IpAddr::V6(fields[2].parse()?)))),
+        _ => return Header::Invalid,
+    };
+    match (ips, fields[3].parse::<u16>(), fields[4].parse::<u16>()) {
+        (Ok((src, dst)), Ok(sport), Ok(dport)) => Header::Complete(
+            end + 2,
+            Some((SocketAddr::new(src, sport), SocketAddr::new(dst, dport))),
+        ),
+        _ => Header::Invalid,
+    }
+}
+
+fn parse_v2(buf: &[u8]) -> Header {
+    if buf.len() < V2_HEADER_LEN {
+        return Header::Incomplete;
+    }
+    let len = V2_HEADER_LEN + ((buf[14] as usize) << 8 | buf[15] as usize);
+    if buf.len() < len {
+        return Header::Incomplete;
+    }
+    let mut addrs = io::Cursor::new(&buf[V2_HEADER_LEN..len]);
+    match (buf[12], buf[13]) {
+        // LOCAL command, the family is to be ignored
+        (0x20, _) => Header::Complete(len, None),
+        // PROXY command, over an unspecified or Unix socket
+        (0x21, 0x00) | (0x21, 0x31) => Header::Complete(len, None),
+        // PROXY command, over TCP on IPv4
+        (0x21, 0x11) if addrs.remaining() >= 12 => {
+            let src = Ipv4Addr::from(addrs.get_u32_be());
+            let dst = Ipv4Addr::from(addrs.get_u32_be());
+            let (sport, dport) = (addrs.get_u16_be(), addrs.get_u16_be());
+            Header::Complete(
+                len,
+                Some((
+                    SocketAddr::new(IpAddr::V4(src), sport),
+                    SocketAddr::new(IpAddr::V4(dst), dport),
+                )),
+            )
+        }
+        // PROXY command, over TCP on IPv6
+        (0x21, 0x21) if addrs.remaining() >= 36 => {
+            let mut src = [0; 16];
+            let mut dst = [0; 16];
+            addrs.copy_to_slice(&mut src);
+            addrs.copy_to_slice(&mut dst);
+            let (sport, dport) = (addrs.get_u16_be(), addrs.get_u16_be());
+            Header::Complete(
+                len,
+                Some((
+                    SocketAddr::new(IpAddr::V6(Ipv6Addr::from(src)), sport),
+                    SocketAddr::new(IpAddr::V6(Ipv6Addr::from(dst)), dport),
+                )),
+            )
+        }
+        // Unknown version or command, datagrams, or truncated addresses
+        _ => Header::Invalid,
+    }
+}
+
+// Connection with some bytes already read from it, that are read again first
+pub struct Prefixed<IO> {
+    prefix: BytesMut,
+    io:     IO,
+}
+
+impl<IO: io::Read> io::Read for Prefixed<IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.prefix.is_empty() {
+            return self.io.read(buf);
+        }
+        let n = cmp::min(buf.len(), self.prefix.len());
+        buf[..n].copy_from_slice(&self.prefix.split_to(n));
+        Ok(n)
+    }
+}
+
+impl<IO: AsyncRead> AsyncRead for Prefixed<IO> {}
+
+impl<IO: io::Write> io::Write for Prefixed<IO> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<IO: AsyncWrite> AsyncWrite for Prefixed<IO> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+// Future reading the PROXY protocol header at the start of a connection, and
+// resolving to the connection along with the addresses it carries. Whatever
+// the client sent after the header is kept for the session.
+pub struct ReadHeader<IO> {
+    io:  Option<IO>,
+    buf: BytesMut,
+}
+
+pub fn read_header<IO: AsyncRead>(io: IO) -> ReadHeader<IO> {
+    ReadHeader {
+        io:  Some(io),
+        buf: BytesMut::new(),
+    }
+}
+
+impl<IO: AsyncRead> Future for ReadHeader<IO> {
+    type Item = (Prefixed<IO>, Option<ProxyAddrs>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        loop {
+            match parse_header(&self.buf) {
+                Header::Incomplete => (),
+                Header::Invalid => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid PROXY protocol header",
+                    ))
+                }
+                Header::Complete(len, addrs) => {
+                    self.buf.advance(len);
+                    let io = self.io.take().expect("polled ReadHeader after completion");
+                    let prefix = self.buf.take();
+                    return Ok(Async::Ready((Prefixed { prefix, io }, addrs)));
+                }
+            }
+            if self.buf.remaining_mut() == 0 {
+                self.buf.reserve(512);
+            }
+            let io = self
+                .io
+                .as_mut()
+                .expect("polled ReadHeader after completion");
+            match io.read_buf(&mut self.buf)? {
+                Async::Ready(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Async::Ready(_) => (),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn addrs(src: &str, dst: &str) -> Option<ProxyAddrs> {
+        Some((src.parse().unwrap(), dst.parse().unwrap()))
+    }
+
+    #[test]
+    fn parses_v1_headers() {
+        let tests: &[(&[u8], Header)] = &[
+            (
+                b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 25\r\nEHLO",
+                Header::Complete(44, addrs("192.0.2.1:56324", "198.51.100.2:25")),
+            ),
+            (
+                b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 25\r\n",
+                Header::Complete(45, addrs("[2001:db8::1]:56324", "[2001:db8::2]:25")),
+            ),
+            (b"PROXY UNKNOWN\r\n", Header::Complete(15, None)),
+            (
+                b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n",
+                Header::Complete(59, None),
+            ),
+            (b"PROX", Header::Incomplete),
+            (b"PROXY TCP4 192.0.2.1 198.5", Header::Incomplete),
+            (
+                b"PROXY TCP4 192.0.2.1 2001:db8::2 56324 25\r\n",
+                Header::Invalid,
+            ),
+            (
+                b"PROXY TCP4 192.0.2.1 198.51.100.2 56324\r\n",
+                Header::Invalid,
+            ),
+            (
+                b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 65536\r\n",
+                Header::Invalid,
+            ),
+            (
+                b"PROXY UDP4 192.0.2.1 198.51.100.2 56324 25\r\n",
+                Header::Invalid,
+            ),
+            (b"EHLO foo.example.org\r\n", Header::Invalid),
+        ];
+        for (inp, res) in tests {
+            assert_eq!(parse_header(inp), *res);
+        }
+        let mut long = b"PROXY UNKNOWN ".to_vec();
+        long.resize(200, b'a');
+        assert_eq!(parse_header(&long), Header::Invalid);
+    }
+
+    #[test]
+    fn parses_v2_headers() {
+        let tests: &[(&[u8], Header)] = &[
+            (
+                b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c\
+                  \xc0\x00\x02\x01\xc6\x33\x64\x02\xdc\x04\x00\x19EHLO",
+                Header::Complete(28, addrs("192.0.2.1:56324", "198.51.100.2:25")),
+            ),
+            (
+                b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24\
+                  \x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x01\
+                  \x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x02\xdc\x04\x00\x19",
+                Header::Complete(52, addrs("[2001:db8::1]:56324", "[2001:db8::2]:25")),
+            ),
+            // TLVs after the addresses are skipped
+            (
+                b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x10\
+                  \xc0\x00\x02\x01\xc6\x33\x64\x02\xdc\x04\x00\x19\x04\x00\x01\x00",
+                Header::Complete(32, addrs("192.0.2.1:56324", "198.51.100.2:25")),
+            ),
+            (
+                b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00",
+                Header::Complete(16, None),
+            ),
+            (b"\r\n\r\n\0\r\nQU", Header::Incomplete),
+            (
+                b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c\xc0\x00",
+                Header::Incomplete,
+            ),
+            (
+                b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x04\xc0\x00\x02\x01",
+                Header::Invalid,
+            ),
+            (
+                b"\r\n\r\n\0\r\nQUIT\n\x21\x12\x00\x0c\
+                  \xc0\x00\x02\x01\xc6\x33\x64\x02\xdc\x04\x00\x19",
+                Header::Invalid,
+            ),
+            (b"\r\n\r\n\0\r\nQUIT\n\x11\x00\x00\x00", Header::Invalid),
+            (b"\r\n\r\n\0\r\nQUIT?", Header::Invalid),
+        ];
+        for (inp, res) in tests {
+            assert_eq!(parse_header(inp), *res);
+        }
+    }
+
+    #[test]
+    fn keeps_what_follows_the_header() {
+        let conn = io::Cursor::new(b"PROXY UNKNOWN\r\nEHLO foo.example.org\r\n".to_vec());
+        let (mut conn, addrs) = read_header(conn).wait().unwrap();
+        assert_eq!(addrs, None);
+        let mut rest = Vec::new();
+        conn.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"EHLO foo.example.org\r\n");
+    }
+}
@@ -0,0 +1,264 @@
+use smtp_message::{Email, ReplyCode, SmtpString};
+use std::{collections::HashSet, net::IpAddr, rc::Rc};
+use tokio::prelude::*;
+
+use access::contains;
+use chain::{Flow, Layer};
+use decision::{Decision, Refusal};
+use metadata::{ConnectionMetadata, MailMetadata};
+
+// Relay authorization policy, that lets through the recipients in the local
+// domains, and all recipients for clients in the trusted networks or that
+// logged in (see `ConnectionMetadata::login`), refusing the others with
+// `554 5.7.1 Relay access denied`.
+//
+// Recipients whose local part holds a `%`, `!` or `@` (eg. `a%b@c`, `a!b@c`
+// or `"a@b"@c`) could be routed further by the mail system, and are thus not
+// taken as local. Source routes (`<@a:b@c>`) are dropped while parsing, so it
+// is the final domain that is checked. Address literals are never local.
+#[derive(Clone)]
+pub struct Relay {
+    domains:  Rc<HashSet<String>>,
+    networks: Rc<Vec<(IpAddr, u8)>>,
+}
+
+impl Relay {
+    pub fn new() -> Relay {
+        Relay {
+            domains:  Rc::new(HashSet::new()),
+            networks: Rc::new(Vec::new()),
+        }
+    }
+
+    // Adds a domain mail is accepted for, without its subdomains
+    pub fn domain(mut self, domain: &str) -> Relay {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        Rc::make_mut(&mut self.domains).insert(domain);
+        self
+    }
+
+    // Adds a network whose clients may relay, eg. `network(ip, 24)`. Panics
+    // if the prefix is longer than the address.
+    pub fn network(mut self, net: IpAddr, prefix: u8) -> Relay {
+        let net = network(net, prefix).expect("invalid network prefix");
+        Rc::make_mut(&mut self.networks).push(net);
+        self
+    }
+
+    // Whether `to` is a mailbox of the local domains. Recipients without a
+    // domain are local, as `postmaster` must be (see RFC 5321 § 4.1.1.3).
+    pub fn is_local(&self, to: &Email) -> bool {
+        let localpart = to.localpart();
+        if localpart
+            .bytes()
+            .iter()
+            .any(|&c| c == b'%' || c == b'!' || c == b'@')
+        {
+            return false;
+        }
+        let domain = match to.hostname() {
+            Some(domain) => domain,
+            None => return true,
+        };
+        match SmtpString::from_sendable(domain) {
+            Ok(domain) => {
+                let domain = String::from_utf8_lossy(&domain.bytes()[..]).to_ascii_lowercase();
+                self.domains.contains(domain.trim_end_matches('.'))
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Whether the client may send mail to any domain
+    pub fn is_trusted<U>(&self, conn_meta: &ConnectionMetadata<U>) -> bool {
+        if conn_meta.login.is_some() {
+            return true;
+        }
+        let ip = match conn_meta.peer_addr {
+            Some(addr) => unmap(addr.ip()),
+            None => return false,
+        };
+        self.networks
+            .iter()
+            .any(|&(net, prefix)| contains(net, prefix, ip))
+    }
+
+    // Decision for `to`, for use from `Config::filter_to`
+    pub fn check<U>(&self, to: &Email, conn_meta: &ConnectionMetadata<U>) -> Decision {
+        if self.is_trusted(conn_meta) || self.is_local(to) {
+            Decision::Accept
+        } else {
+            Decision::Reject(Refusal {
+                code: ReplyCode::TRANSACTION_FAILED,
+                msg:  SmtpString::from_static(b"5.7.1 Relay access denied"),
+            })
+        }
+    }
+}
+
+impl Default for Relay {
+    fn default() -> Relay {
+        Relay::new()
+    }
+}
+
+impl<U: 'static> Layer<U> for Relay {
+    fn filter_to(
+        &self,
+        to: Email,
+        meta: MailMetadata,
+        conn_meta: ConnectionMetadata<U>,
+    ) -> Box<Future<Item = (Email, MailMetadata, ConnectionMetadata<U>, Flow), Error = ()>> {
+        let flow = match self.check(&to, &conn_meta) {
+            Decision::Accept => Flow::Next,
+            decision => Flow::Stop(decision),
+        };
+        Box::new(future::ok((to, meta, conn_meta, flow)))
+    }
+}
+
+// IPv4 clients of IPv6 sockets show up as IPv4-mapped addresses
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+            IpAddr::V4(v6.to_ipv4().unwrap())
+        }
+        ip => ip,
+    }
+}
+
+// Checks the prefix length of a network, turning IPv4-mapped ones (eg.
+// `::ffff:192.0.2.0/120`) into IPv4 networks as their clients are
+fn network(net: IpAddr, prefix: u8) -> Option<(IpAddr, u8)> {
+    match (net, unmap(net)) {
+        (IpAddr::V4(_), _) if prefix <= 32 => Some((net, prefix)),
+        (IpAddr::V6(_), IpAddr::V4(v4)) if prefix >= 96 && prefix <= 128 => {
+            Some((IpAddr::V4(v4), prefix - 96))
+        }
+        (IpAddr::V6(_), IpAddr::V6(_)) if prefix <= 128 => Some((net, prefix)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use smtp_message::Command;
+
+    fn relay() -> Relay {
+        Relay::new()
+            .domain("Example.org")
+            .domain("example.net.")
+            .network("192.0.2.0".parse().unwrap(), 24)
+            .network("2001:db8::".parse().unwrap(), 32)
+            .network("::ffff:198.51.100.0".parse().unwrap(), 120)
+    }
+
+    // Parses the address as a client would send it
+    fn rcpt(to: &str) -> Email {
+        let line = Bytes::from(format!("RCPT TO:{}\r\n", to));
+        match Command::parse(line) {
+            Ok(Command::Rcpt(rcpt)) => rcpt.to,
+            _ => panic!("invalid recipient {}", to),
+        }
+    }
+
+    fn client(ip: &str) -> ConnectionMetadata<()> {
+        let mut conn_meta = ConnectionMetadata::new(());
+        conn_meta.peer_addr = Some(format!("{}:25", ip).parse().unwrap());
+        conn_meta
+    }
+
+    fn denied(to: &str, conn_meta: &ConnectionMetadata<()>) -> bool {
+        match relay().check(&rcpt(to), conn_meta) {
+            Decision::Accept => false,
+            Decision::Reject(r) => {
+                assert_eq!(r.code.code(), 554);
+                assert_eq!(&r.msg.bytes()[..], &b"5.7.1 Relay access denied"[..]);
+                true
+            }
+            _ => panic!("unexpected decision"),
+        }
+    }
+
+    #[test]
+    fn accepts_local_domains() {
+        let outsider = client("198.51.100.1");
+        for to in &[
+            "<john@example.org>",
+            "<John@EXAMPLE.ORG>",
+            "<john@example.net>",
+            "<\"john doe\"@example.org>",
+            "<postmaster>",
+            "<@example.com:john@example.org>",
+        ] {
+            assert!(!denied(to, &outsider), "{}", to);
+        }
+        assert!(denied("<john@example.com>", &outsider));
+        assert!(denied("<john@mail.example.org>", &outsider));
+        assert!(denied("<john@example.org.example.com>", &outsider));
+        assert!(denied("<john@[192.0.2.1]>", &outsider));
+    }
+
+    #[test]
+    fn refuses_relay_tricks() {
+        let outsider = client("198.51.100.1");
+        for to in &[
+            // Percent hack
+            "<john%example.com@example.org>",
+            "<john%example.com%example.org@example.org>",
+            // Bang path
+            "<example.com!john@example.org>",
+            // Quoted @
+            "<\"john@example.com\"@example.org>",
+            "<\"john\\@example.com\"@example.org>",
+            "<\"john%example.com\"@example.org>",
+            // Source routes through a local domain
+            "<@example.org:john@example.com>",
+            "<@example.org,@example.net:john@example.com>",
+        ] {
+            assert!(denied(to, &outsider), "{}", to);
+        }
+    }
+
+    #[test]
+    fn lets_trusted_clients_relay() {
+        assert!(!denied("<john@example.com>", &client("192.0.2.7")));
+        assert!(!denied("<john@example.com>", &client("[2001:db8::7]")));
+        assert!(!denied("<john@example.com>", &client("[::ffff:192.0.2.7]")));
+        assert!(denied("<john@example.com>", &client("[2001:db9::7]")));
+        assert!(denied("<john@example.com>", &ConnectionMetadata::new(())));
+
+        assert!(!denied("<john@example.com>", &client("198.51.100.7")));
+        assert!(!denied(
+            "<john@example.com>",
+            &client("[::ffff:198.51.100.7]")
+        ));
+        assert!(denied("<john@example.com>", &client("198.51.101.7")));
+
+        let mut user = client("203.0.113.1");
+        user.login = Some("jdoe".into());
+        assert!(!denied("<john@example.com>", &user));
+    }
+
+    #[test]
+    fn checks_network_prefixes() {
+        let net = |net: &str, prefix| network(net.parse().unwrap(), prefix);
+        assert_eq!(
+            net("192.0.2.0", 32),
+            Some(("192.0.2.0".parse().unwrap(), 32))
+        );
+        assert_eq!(net("192.0.2.0", 40), None);
+        assert_eq!(
+            net("2001:db8::", 128),
+            Some(("2001:db8::".parse().unwrap(), 128))
+        );
+        assert_eq!(net("2001:db8::", 129), None);
+        assert_eq!(
+            net("::ffff:192.0.2.0", 120),
+            Some(("192.0.2.0".parse().unwrap(), 24))
+        );
+        assert_eq!(net("::ffff:192.0.2.0", 90), None);
+    }
+}
@@ -1,20 +1,67 @@
 use itertools::Itertools;
-use smtp_message::{IsLastLine, ReplyCode, ReplyLine, SmtpString, StreamExt};
+use smtp_message::{IsLastLine, ReplyCode, ReplyLine, SmtpString};
+use std::vec;
 use tokio::prelude::*;
 
+// Future that queues all the lines of a reply into a sink, without flushing it.
+// Flushing is left to the caller, so that the replies to pipelined commands can
+// be coalesced into a single write.
+pub struct SendReply<W: Sink<SinkItem = ReplyLine>> {
+    writer:  Option<W>,
+    lines:   vec::IntoIter<ReplyLine>,
+    pending: Option<ReplyLine>,
+}
+
+impl<W: Sink<SinkItem = ReplyLine>> Future for SendReply<W> {
+    type Item = W;
+    type Error = W::SinkError;
+
+    fn poll(&mut self) -> Poll<W, W::SinkError> {
+        loop {
+            let line = match self.pending.take().or_else(|| self.lines.next()) {
+                Some(line) => line,
+                None => {
+                    return Ok(Async::Ready(
+                        self.writer
+                            .take()
+                            .expect("polled SendReply after completion"),
+                    ))
+                }
+            };
+            let writer = self
+                .writer
+                .as_mut()
+                .expect("polled SendReply after completion");
+            if let AsyncSink::NotReady(line) = writer.start_send(line)? {
+                self.pending = Some(line);
+                // The sink is full, so let it make room before trying again
+                if let Async::NotReady = writer.poll_complete()? {
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+    }
+}
+
 // TODO: (B) move to smtp_message's Reply builder id:tcHW
 // Panics if `text` has a byte not in {9} \union [32; 126]
 // TODO: (B) move sending logic to smtp_message::Reply
-pub fn send_reply<'a, W>(
-    writer: W,
-    (code, text): (ReplyCode, SmtpString),
-) -> impl Future<Item = W, Error = W::SinkError> + 'a
+pub fn send_reply<W>(writer: W, (code, text): (ReplyCode, SmtpString)) -> SendReply<W>
 where
-    W: 'a + Sink<SinkItem = ReplyLine>,
-    W::SinkError: 'a,
+    W: Sink<SinkItem = ReplyLine>,
 {
-    let replies = text
-        .byte_chunks(ReplyLine::MAX_LEN)
+    send_reply_lines(writer, (code, vec![text]))
+}
+
+// Same as `send_reply`, for a multi-line reply (eg. the reply to EHLO). Each
+// element of `texts` starts a new line.
+pub fn send_reply_lines<W>(writer: W, (code, texts): (ReplyCode, Vec<SmtpString>)) -> SendReply<W>
+where
+    W: Sink<SinkItem = ReplyLine>,
+{
+    let lines = texts
+        .iter()
+        .flat_map(|text| text.byte_chunks(ReplyLine::MAX_LEN))
         .with_position()
         .map(move |t| {
             use itertools::Position::*;
@@ -22,9 +69,30 @@ where
                 First(t) | Middle(t) => ReplyLine::build(code, IsLastLine::No, t).unwrap(),
                 Last(t) | Only(t) => ReplyLine::build(code, IsLastLine::Yes, t).unwrap(),
             }
-        });
+        })
+        .collect::<Vec<_>>();
+
+    SendReply {
+        writer:  Some(writer),
+        lines:   lines.into_iter(),
+        pending: None,
+    }
+}
+
+// Sends `text` as the first line(s) of a reply that is continued by a later
+// `send_reply`
+pub fn send_reply_start<W>(writer: W, (code, text): (ReplyCode, SmtpString)) -> SendReply<W>
+where
+    W: Sink<SinkItem = ReplyLine>,
+{
+    let lines = text
+        .byte_chunks(ReplyLine::MAX_LEN)
+        .map(|t| ReplyLine::build(code, IsLastLine::No, t).unwrap())
+        .collect::<Vec<_>>();
 
-    stream::iter_ok(replies)
-        .forward_not_closing(writer)
-        .map(|(_, w)| w)
+    SendReply {
+        writer:  Some(writer),
+        lines:   lines.into_iter(),
+        pending: None,
+    }
 }
@@ -0,0 +1,703 @@
+use smtp_message::{ReplyCode, SmtpString};
+#[cfg(unix)]
+use std::path::Path;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+    executor::current_thread,
+    net::{TcpListener, TcpStream},
+    prelude::{future::Either, *},
+    timer::Delay,
+};
+
+use bufio::{refuse_io, session_io};
+use config::Config;
+use metadata::ConnectionMetadata;
+use proxy::read_header;
+use shutdown::Shutdown;
+
+// Sessions currently running, overall and per client address
+struct Connections {
+    total:   usize,
+    per_ip:  HashMap<IpAddr, usize>,
+    // Task waiting for all the sessions to be over
+    waiting: Option<task::Task>,
+}
+
+// Accounts for a running session until dropped. Only the clients connecting
+// over TCP have an IP address.
+struct Slot {
+    conns: Rc<RefCell<Connections>>,
+    ip:    Option<IpAddr>,
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+// Admits the connections into sessions, within the connection limits
+#[derive(Clone)]
+struct Admission {
+    conns: Rc<RefCell<Connections>>,
+    max_conns: Option<usize>,
+    max_conns_per_ip: Option<usize>,
+    signal: Shutdown,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut conns = self.conns.borrow_mut();
+        conns.total -= 1;
+        if let Some(ip) = self.ip {
+            let ip_done = {
+                let count = conns.per_ip.get_mut(&ip).unwrap();
+                *count -= 1;
+                *count == 0
+            };
+            if ip_done {
+                conns.per_ip.remove(&ip);
+            }
+        }
+        if conns.total == 0 {
+            if let Some(t) = conns.waiting.take() {
+                t.notify();
+            }
+        }
+    }
+}
+
+// Accepts connections on TCP or Unix listeners, and runs an SMTP session for
+// each.
+//
+// As a `Config` need not be `Send`, the sessions are spawned on the current
+// thread, so the future returned by `serve` must be run on a `current_thread`
+// runtime.
+pub struct Server<F> {
+    listeners: Vec<Listener>,
+    factory: F,
+    max_conns: Option<usize>,
+    max_conns_per_ip: Option<usize>,
+    proxy_timeout: Option<Duration>,
+}
+
+impl<F> Server<F> {
+    // `factory` is called for each accepted connection, and returns the
+    // user-provided metadata and the configuration for its session.
+    pub fn new(factory: F) -> Server<F> {
+        Server {
+            listeners: Vec::new(),
+            factory,
+            max_conns: None,
+            max_conns_per_ip: None,
+            proxy_timeout: None,
+        }
+    }
+
+    pub fn bind(self, addr: &SocketAddr) -> io::Result<Server<F>> {
+        Ok(self.listener(TcpListener::bind(addr)?))
+    }
+
+    pub fn listener(mut self, listener: TcpListener) -> Server<F> {
+        self.listeners.push(Listener::Tcp(listener));
+        self
+    }
+
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<Path>>(self, path: P) -> io::Result<Server<F>> {
+        Ok(self.unix_listener(UnixListener::bind(path)?))
+    }
+
+    // Clients connecting through Unix sockets are only subject to the global
+    // connection limit.
+    #[cfg(unix)]
+    pub fn unix_listener(mut self, listener: UnixListener) -> Server<F> {
+        self.listeners.push(Listener::Unix(listener));
+        self
+    }
+
+    // Clients connecting while `max` sessions are running get a 421 reply.
+    pub fn max_connections(mut self, max: usize) -> Server<F> {
+        self.max_conns = Some(max);
+        self
+    }
+
+    // Clients connecting while `max` sessions are running from the same IP
+    // address get a 421 reply.
+    pub fn max_connections_per_ip(mut self, max: usize) -> Server<F> {
+        self.max_conns_per_ip = Some(max);
+        self
+    }
+
+    // Requires each connection to start with a PROXY protocol header (v1 or
+    // v2), as sent by HAProxy and other load balancers, within `timeout`.
+    //
+    // The addresses it carries then stand for those of the connection, for
+    // the connection limits and in `ConnectionMetadata`. Connections that
+    // send an invalid header, or none in time, are closed without a reply.
+    pub fn proxy_protocol(mut self, timeout: Duration) -> Server<F> {
+        self.proxy_timeout = Some(timeout);
+        self
+    }
+
+    // Serves SMTP until `shutdown` resolves.
+    //
+    // Then, the listeners are closed, and each session gets a 421 reply the
+    // next time it waits for a command, so that the mails being received are
+    // not cut short. The returned future resolves once all the sessions are
+    // over.
+    pub fn serve<U, Cfg, S>(self, shutdown: S) -> impl Future<Item = (), Error = ()>
+    where
+        F: 'static + FnMut() -> (U, Cfg),
+        U: 'static,
+        Cfg: Config<U>,
+        S: Future<Item = (), Error = ()>,
+    {
+        let Server {
+            listeners,
+            mut factory,
+            max_conns,
+            max_conns_per_ip,
+            proxy_timeout,
+        } = self;
+        let conns = Rc::new(RefCell::new(Connections {
+            total:   0,
+            per_ip:  HashMap::new(),
+            waiting: None,
+        }));
+        let signal = Shutdown::new();
+
+        let incoming = listeners.into_iter().fold(
+            Box::new(stream::empty()) as Box<Stream<Item = Socket, Error = io::Error>>,
+            |all, l| match l {
+                Listener::Tcp(l) => Box::new(all.select(l.incoming().map(Socket::Tcp))),
+                #[cfg(unix)]
+                Listener::Unix(l) => Box::new(all.select(l.incoming().map(Socket::Unix))),
+            },
+        );
+        let admission = Admission {
+            conns: conns.clone(),
+            max_conns,
+            max_conns_per_ip,
+            signal: signal.clone(),
+        };
+        let accept = pause_on_errors(incoming, Duration::from_millis(ACCEPT_ERROR_PAUSE)).for_each(
+            move |socket| {
+                let addrs = match socket {
+                    Socket::Tcp(ref s) => {
+                        s.peer_addr().map(|peer| (Some(peer), s.local_addr().ok()))
+                    }
+                    #[cfg(unix)]
+                    Socket::Unix(_) => Ok((None, None)),
+                };
+                let addrs = match addrs {
+                    Ok(addrs) => addrs,
+                    Err(_) => return Ok(()),
+                };
+                let (user, cfg) = factory();
+                match (socket, proxy_timeout) {
+                    (Socket::Tcp(s), None) => admission.admit(s, addrs, user, cfg),
+                    #[cfg(unix)]
+                    (Socket::Unix(s), None) => admission.admit(s, addrs, user, cfg),
+                    (Socket::Tcp(s), Some(t)) => {
+                        admission.clone().admit_proxied(s, t, addrs, user, cfg)
+                    }
+                    #[cfg(unix)]
+                    (Socket::Unix(s), Some(t)) => {
+                        admission.clone().admit_proxied(s, t, addrs, user, cfg)
+                    }
+                }
+                Ok(())
+            },
+        );
+
+        // Dropping the accept loop closes the listeners
+        accept.select2(shutdown).then(move |_| {
+            signal.trigger();
+            future::poll_fn(move || {
+                let mut conns = conns.borrow_mut();
+                if conns.total == 0 {
+                    Ok(Async::Ready(()))
+                } else {
+                    conns.waiting = Some(task::current());
+                    Ok(Async::NotReady)
+                }
+            })
+        })
+    }
+}
+
+impl Admission {
+    // Runs a session for the client at `addrs`, or refuses it if it is past
+    // the connection limits
+    fn admit<IO, U, Cfg>(
+        &self,
+        io: IO,
+        addrs: (Option<SocketAddr>, Option<SocketAddr>),
+        user: U,
+        cfg: Cfg,
+    ) where
+        IO: 'static + AsyncRead + AsyncWrite,
+        U: 'static,
+        Cfg: Config<U>,
+    {
+        match self.take_slot() {
+            Some(slot) => self.admit_in(slot, io, addrs, user, cfg),
+            None => spawn_refusal(io, cfg.too_many_connections()),
+        }
+    }
+
+    // Takes a slot within the global connection limit, that does not count
+    // for any IP address yet
+    fn take_slot(&self) -> Option<Slot> {
+        let mut conns = self.conns.borrow_mut();
+        if self.max_conns.map(|m| conns.total >= m).unwrap_or(false) {
+            return None;
+        }
+        conns.total += 1;
+        Some(Slot {
+            conns: self.conns.clone(),
+            ip:    None,
+        })
+    }
+
+    // Same as `admit`, with the global slot already taken
+    fn admit_in<IO, U, Cfg>(
+        &self,
+        mut slot: Slot,
+        io: IO,
+        (peer_addr, local_addr): (Option<SocketAddr>, Option<SocketAddr>),
+        user: U,
+        cfg: Cfg,
+    ) where
+        IO: 'static + AsyncRead + AsyncWrite,
+        U: 'static,
+        Cfg: Config<U>,
+    {
+        if let Some(ip) = peer_addr.map(|addr| addr.ip()) {
+            let admitted = {
+                let mut conns = self.conns.borrow_mut();
+                let ip_conns = conns.per_ip.get(&ip).cloned().unwrap_or(0);
+                if self
+                    .max_conns_per_ip
+                    .map(|m| ip_conns >= m)
+                    .unwrap_or(false)
+                {
+                    false
+                } else {
+                    conns.per_ip.insert(ip, ip_conns + 1);
+                    true
+                }
+            };
+            if !admitted {
+                spawn_refusal(io, cfg.too_many_connections());
+                return;
+            }
+            slot.ip = Some(ip);
+        }
+
+        let mut conn_meta = ConnectionMetadata::new(user);
+        conn_meta.peer_addr = peer_addr;
+        conn_meta.local_addr = local_addr;
+        spawn_session(io, conn_meta, cfg, Some(self.signal.clone()), slot);
+    }
+
+    // Same as `admit`, once the PROXY protocol header has told the addresses
+    // of the client. The connection takes a slot within the global limit
+    // while waiting for it, and only counts for the IP address of the client
+    // afterwards.
+    fn admit_proxied<IO, U, Cfg>(
+        self,
+        io: IO,
+        timeout: Duration,
+        addrs: (Option<SocketAddr>, Option<SocketAddr>),
+        user: U,
+        cfg: Cfg,
+    ) where
+        IO: 'static + AsyncRead + AsyncWrite,
+        U: 'static,
+        Cfg: Config<U>,
+    {
+        let slot = match self.take_slot() {
+            Some(slot) => slot,
+            None => {
+                spawn_refusal(io, cfg.too_many_connections());
+                return;
+            }
+        };
+        // Timer errors, eg. for lack of a running timer, just end the wait
+        let delay = Delay::new(Instant::now() + timeout).then(|_| Ok::<_, ()>(()));
+        // Clients still sending their header when the server shuts down are
+        // dropped like the other ones, and do not delay its shutdown
+        let signal = self.signal.clone();
+        let shutdown = future::poll_fn(move || {
+            if signal.poll_triggered() {
+                Ok::<_, ()>(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        });
+        let header = read_header(io).select2(delay.select(shutdown));
+        current_thread::spawn(header.then(move |res| {
+            match res {
+                Ok(Either::A(_)) if self.signal.poll_triggered() => (),
+                Ok(Either::A(((io, proxy_addrs), _))) => {
+                    let addrs = proxy_addrs
+                        .map(|(peer, local)| (Some(peer), Some(local)))
+                        .unwrap_or(addrs);
+                    self.admit_in(slot, io, addrs, user, cfg);
+                }
+                // The header was invalid, or did not come in time
+                Ok(Either::B(_)) | Err(_) => (),
+            }
+            Ok(())
+        }));
+    }
+}
+
+// Milliseconds during which the listeners are not polled after failing to
+// accept a connection
+const ACCEPT_ERROR_PAUSE: u64 = 100;
+
+// Passes on the items of `s`, waiting for `pause` after each error instead.
+// Errors accepting a connection may not be about that connection alone (eg.
+// EMFILE when out of file descriptors), and would then come back right away
+// if the listeners were polled again.
+fn pause_on_errors<S: Stream>(
+    mut s: S,
+    pause: Duration,
+) -> impl Stream<Item = S::Item, Error = ()> {
+    let mut delay: Option<Delay> = None;
+    stream::poll_fn(move || loop {
+        if let Some(ref mut d) = delay {
+            // Timer errors, eg. for lack of a running timer, end the pause
+            if let Ok(Async::NotReady) = d.poll() {
+                return Ok(Async::NotReady);
+            }
+        }
+        delay = None;
+        match s.poll() {
+            Ok(item) => return Ok(item),
+            Err(_) => delay = Some(Delay::new(Instant::now() + pause)),
+        }
+    })
+}
+
+fn spawn_refusal<IO: 'static + AsyncWrite>(io: IO, reply: (ReplyCode, SmtpString)) {
+    current_thread::spawn(refuse_io(io, reply).map_err(|_| ()));
+}
+
+fn spawn_session<IO, U, Cfg>(
+    io: IO,
+    conn_meta: ConnectionMetadata<U>,
+    cfg: Cfg,
+    shutdown: Option<Shutdown>,
+    slot: Slot,
+) where
+    IO: 'static + AsyncRead + AsyncWrite,
+    U: 'static,
+    Cfg: Config<U>,
+{
+    let session = session_io(io, conn_meta, cfg, shutdown);
+    current_thread::spawn(session.then(move |_| {
+        drop(slot);
+        Ok(())
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use smtp_message::{DataStream, Email, Prependable};
+    use std::{
+        cell::Cell,
+        time::{Duration, Instant},
+    };
+    use tokio::{
+        io::{read_to_end, write_all},
+        runtime::current_thread::Runtime,
+        timer::Delay,
+    };
+
+    use decision::Decision;
+    use metadata::MailMetadata;
+
+    // Records the addresses of the connection when receiving MAIL FROM
+    struct TestConfig {
+        addrs: Rc<Cell<Option<(SocketAddr, SocketAddr)>>>,
+    }
+
+    impl Config<()> for TestConfig {
+        fn hostname(&self) -> SmtpString {
+            SmtpString::from_static(b"test.example.org")
+        }
+
+        fn filter_from(
+            self,
+            addr: Option<Email>,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<Future<Item = (Self, Option<Email>, ConnectionMetadata<()>, Decision), Error = ()>>
+        {
+            self.addrs.set(Some((
+                conn_meta.peer_addr.unwrap(),
+                conn_meta.local_addr.unwrap(),
+            )));
+            Box::new(future::ok((self, addr, conn_meta, Decision::Accept)))
+        }
+
+        fn filter_to(
+            self,
+            email: Email,
+            meta: MailMetadata,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<
+            Future<
+                Item = (Self, Email, MailMetadata, ConnectionMetadata<()>, Decision),
+                Error = (),
+            >,
+        > {
+            Box::new(future::ok((self, email, meta, conn_meta, Decision::Accept)))
+        }
+
+        fn handle_mail<'a, S: 'a + Stream<Item = BytesMut, Error = ()>>(
+            self,
+            reader: DataStream<S>,
+            _meta: MailMetadata,
+            conn_meta: ConnectionMetadata<()>,
+        ) -> Box<
+            'a
+                + Future<
+                    Item = (
+                        Self,
+                        Option<Prependable<S>>,
+                        ConnectionMetadata<()>,
+                        Decision,
+                    ),
+                    Error = (),
+                >,
+        > {
+            Box::new(
+                reader
+                    .concat_and_recover()
+                    .map_err(|_| ())
+                    .map(move |(_, reader)| {
+                        (self, Some(reader.into_inner()), conn_meta, Decision::Accept)
+                    }),
+            )
+        }
+    }
+
+    fn after(ms: u64) -> impl Future<Item = (), Error = io::Error> {
+        Delay::new(Instant::now() + Duration::from_millis(ms))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    #[test]
+    fn pauses_after_accept_errors() {
+        let start = Instant::now();
+        let items = stream::iter_result(vec![Ok(1), Err(()), Err(()), Ok(2)]);
+        let mut rt = Runtime::new().unwrap();
+        let items = rt
+            .block_on(pause_on_errors(items, Duration::from_millis(50)).collect())
+            .unwrap();
+        assert_eq!(items, vec![1, 2]);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn limits_connections_and_shuts_down() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let addrs = Rc::new(Cell::new(None));
+        let cfg_addrs = addrs.clone();
+        let server = Server::new(move || {
+            let cfg = TestConfig {
+                addrs: cfg_addrs.clone(),
+            };
+            ((), cfg)
+        })
+        .listener(listener)
+        .max_connections_per_ip(1);
+
+        // The first client is in a session when the second one connects, and
+        // when the server shuts down
+        let first = TcpStream::connect(&addr)
+            .and_then(|s| write_all(s, b"MAIL FROM:<foo@example.org>\r\n"))
+            .and_then(|(s, _)| read_to_end(s, Vec::new()))
+            .map(|(_, resp)| resp);
+        let second = after(50)
+            .and_then(move |()| TcpStream::connect(&addr))
+            .and_then(|s| read_to_end(s, Vec::new()))
+            .map(|(_, resp)| resp);
+        let shutdown = after(100).map_err(|_| ());
+
+        let mut rt = Runtime::new().unwrap();
+        let (first, second, ()) = rt
+            .block_on(
+                first
+                    .join(second)
+                    .map_err(|_| ())
+                    .join(server.serve(shutdown))
+                    .map(|((first, second), ())| (first, second, ())),
+            )
+            .unwrap();
+        assert_eq!(
+            first,
+            &b"220 test.example.org Service ready\r\n\
+               250 Okay\r\n\
+               421 test.example.org Service shutting down, try again later\r\n"[..]
+        );
+        assert_eq!(
+            second,
+            &b"421 test.example.org Too many connections, try again later\r\n"[..]
+        );
+        let (peer_addr, local_addr) = addrs.get().unwrap();
+        assert_eq!(local_addr, addr);
+        assert_eq!(peer_addr.ip(), addr.ip());
+    }
+
+    #[test]
+    fn reads_proxy_headers() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let addrs = Rc::new(Cell::new(None));
+        let cfg_addrs = addrs.clone();
+        let server = Server::new(move || {
+            let cfg = TestConfig {
+                addrs: cfg_addrs.clone(),
+            };
+            ((), cfg)
+        })
+        .listener(listener)
+        .proxy_protocol(Duration::from_secs(1));
+
+        let proxied = TcpStream::connect(&addr)
+            .and_then(|s| {
+                write_all(
+                    s,
+                    &b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 25\r\n\
+                       MAIL FROM:<foo@example.org>\r\n"[..],
+                )
+            })
+            .and_then(|(s, _)| read_to_end(s, Vec::new()))
+            .map(|(_, resp)| resp);
+        let invalid = TcpStream::connect(&addr)
+            .and_then(|s| write_all(s, b"EHLO foo.example.org\r\n"))
+            .and_then(|(s, _)| read_to_end(s, Vec::new()))
+            .map(|(_, resp)| resp);
+        let shutdown = after(100).map_err(|_| ());
+
+        let mut rt = Runtime::new().unwrap();
+        let ((proxied, invalid), ()) = rt
+            .block_on(
+                proxied
+                    .join(invalid)
+                    .map_err(|_| ())
+                    .join(server.serve(shutdown)),
+            )
+            .unwrap();
+        assert_eq!(
+            proxied,
+            &b"220 test.example.org Service ready\r\n\
+               250 Okay\r\n\
+               421 test.example.org Service shutting down, try again later\r\n"[..]
+        );
+        assert!(invalid.is_empty());
+        assert_eq!(
+            addrs.get(),
+            Some((
+                "192.0.2.1:56324".parse().unwrap(),
+                "198.51.100.2:25".parse().unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn limits_connections_waiting_for_proxy_headers() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(|| {
+            let cfg = TestConfig {
+                addrs: Rc::new(Cell::new(None)),
+            };
+            ((), cfg)
+        })
+        .listener(listener)
+        .max_connections(1)
+        .proxy_protocol(Duration::from_secs(60));
+
+        // The first client never sends its header, and the server shuts down
+        // without waiting for it
+        let silent = TcpStream::connect(&addr)
+            .and_then(|s| read_to_end(s, Vec::new()))
+            .map(|(_, resp)| resp);
+        let second = after(50)
+            .and_then(move |()| TcpStream::connect(&addr))
+            .and_then(|s| read_to_end(s, Vec::new()))
+            .map(|(_, resp)| resp);
+        let shutdown = after(100).map_err(|_| ());
+
+        let mut rt = Runtime::new().unwrap();
+        let ((silent, second), ()) = rt
+            .block_on(
+                silent
+                    .join(second)
+                    .map_err(|_| ())
+                    .join(server.serve(shutdown)),
+            )
+            .unwrap();
+        assert!(silent.is_empty());
+        assert_eq!(
+            second,
+            &b"421 test.example.org Too many connections, try again later\r\n"[..]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serves_unix_sockets() {
+        let path = std::env::temp_dir().join(format!("smtp-server-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = Server::new(|| {
+            let cfg = TestConfig {
+                addrs: Rc::new(Cell::new(None)),
+            };
+            ((), cfg)
+        })
+        .bind_unix(&path)
+        .unwrap();
+
+        let client = UnixStream::connect(&path)
+            .and_then(|s| write_all(s, b"QUIT\r\n"))
+            .and_then(|(s, _)| read_to_end(s, Vec::new()))
+            .map(|(_, resp)| resp);
+        let shutdown = after(100).map_err(|_| ());
+
+        let mut rt = Runtime::new().unwrap();
+        let (resp, ()) = rt
+            .block_on(client.map_err(|_| ()).join(server.serve(shutdown)))
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            resp,
+            &b"220 test.example.org Service ready\r\n\
+               221 test.example.org Service closing transmission channel\r\n"[..]
+        );
+    }
+}
@@ -0,0 +1,64 @@
+use std::{cell::RefCell, rc::Rc};
+use tokio::prelude::*;
+
+struct State {
+    triggered: bool,
+    waiting:   Vec<task::Task>,
+}
+
+// Signal telling the sessions of a server to end as soon as they are waiting
+// for a command
+#[derive(Clone)]
+pub struct Shutdown(Rc<RefCell<State>>);
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown(Rc::new(RefCell::new(State {
+            triggered: false,
+            waiting:   Vec::new(),
+        })))
+    }
+
+    pub fn trigger(&self) {
+        let mut state = self.0.borrow_mut();
+        state.triggered = true;
+        for t in state.waiting.drain(..) {
+            t.notify();
+        }
+    }
+
+    // Returns whether the signal was triggered, and otherwise arranges for the
+    // current task to be notified when it is
+    pub fn poll_triggered(&self) -> bool {
+        let mut state = self.0.borrow_mut();
+        if !state.triggered && !state.waiting.iter().any(|t| t.will_notify_current()) {
+            state.waiting.push(task::current());
+        }
+        state.triggered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn notifies_waiting_tasks() {
+        let shutdown = Shutdown::new();
+        let waiter = shutdown.clone();
+        let waiting = future::poll_fn(move || {
+            if waiter.poll_triggered() {
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        });
+        let trigger = future::lazy(move || {
+            shutdown.trigger();
+            Ok::<_, ()>(())
+        });
+        // Would never return if the waiting future was not polled again
+        Runtime::new().unwrap().block_on(waiting.join(trigger)).unwrap();
+    }
+}
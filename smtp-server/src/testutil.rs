@@ -0,0 +1,62 @@
+// Helpers shared by the tests of the policies
+use smtp_message::Email;
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use chain::Flow;
+use decision::Decision;
+
+pub fn email(addr: &str) -> Email {
+    Email::parse_slice(addr.as_bytes()).unwrap()
+}
+
+// Code and text of the refusal `flow` stops with, an acceptance that skips the
+// next layers showing as `(250, "")`
+pub fn refusal(flow: &Flow) -> Option<(u16, String)> {
+    match flow {
+        Flow::Next => None,
+        Flow::Stop(Decision::Accept) => Some((250, String::new())),
+        Flow::Stop(Decision::Reject(r)) => Some((
+            r.code.code(),
+            String::from_utf8_lossy(&r.msg.bytes()[..]).into_owned(),
+        )),
+        Flow::Stop(_) => panic!("unexpected decision"),
+    }
+}
+
+// Path in the temporary directory, unique to `name` and to the test process,
+// where nothing is left once dropped
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    pub fn new(name: &str) -> TempFile {
+        let path = env::temp_dir().join(format!("smtp-server-{}-{}", name, process::id()));
+        let _ = fs::remove_file(&path);
+        TempFile(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// Writes `content` to a temporary file and opens it with `open`, eg. as an
+// `AccessMap`. The file is removed once the returned `TempFile` is dropped.
+pub fn map<T, F>(name: &str, content: &str, open: F) -> (TempFile, T)
+where
+    F: FnOnce(&Path) -> io::Result<T>,
+{
+    let file = TempFile::new(name);
+    fs::write(file.path(), content).unwrap();
+    let map = open(file.path()).unwrap();
+    (file, map)
+}
@@ -0,0 +1,139 @@
+use bytes::BytesMut;
+use std::time::{Duration, Instant};
+use tokio::{prelude::*, timer::Delay};
+
+use crlflines::{CrlfLines, Line};
+use disconnect::DisconnectReason;
+use shutdown::Shutdown;
+
+// The waits for the client that are bounded in time (RFC 5321 § 4.5.3.2)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Timeout {
+    // Waiting for the first command after the banner
+    Greeting,
+    // Waiting for a command outside of a mail transaction
+    Mail,
+    // Waiting for a command during a mail transaction
+    Rcpt,
+    // Waiting for the mail data after the 354 reply
+    DataInit,
+    // Waiting for the next block of mail data
+    DataBlock,
+    // Waiting for `Config::handle_mail` after the end of the mail data
+    DataTermination,
+    // The whole session took too long
+    Session,
+}
+
+// Returns when a wait of `duration` should end, which may be earlier than
+// planned if the session is over by then
+pub fn deadline(
+    duration: Duration,
+    timeout: Timeout,
+    session_end: Option<Instant>,
+) -> (Instant, Timeout) {
+    let end = Instant::now() + duration;
+    match session_end {
+        Some(session_end) if session_end < end => (session_end, Timeout::Session),
+        _ => (end, timeout),
+    }
+}
+
+// Future resolving to the next line of `lines`, or to the reason for giving up
+// on waiting for it, ie. a timeout, the server shutting down or a read error.
+// The lines are handed back in all cases.
+pub struct NextLine<S: Stream<Item = BytesMut>> {
+    lines:    Option<CrlfLines<S>>,
+    delay:    Delay,
+    timeout:  Timeout,
+    shutdown: Option<Shutdown>,
+}
+
+pub fn next_line<S: Stream<Item = BytesMut>>(
+    lines: CrlfLines<S>,
+    (end, timeout): (Instant, Timeout),
+    shutdown: Option<Shutdown>,
+) -> NextLine<S> {
+    NextLine {
+        lines: Some(lines),
+        delay: Delay::new(end),
+        timeout,
+        shutdown,
+    }
+}
+
+impl<S: Stream<Item = BytesMut, Error = ()>> Future for NextLine<S> {
+    type Item = (Result<Option<Line>, DisconnectReason>, CrlfLines<S>);
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, ()> {
+        let res = match self
+            .lines
+            .as_mut()
+            .expect("polled NextLine after completion")
+            .poll()
+        {
+            Ok(Async::Ready(line)) => Ok(line),
+            // The error itself is for the reader to report
+            Err(()) => Err(DisconnectReason::IoError),
+            Ok(Async::NotReady) => {
+                if self.shutdown.as_ref().map(|s| s.poll_triggered()) == Some(true) {
+                    Err(DisconnectReason::Shutdown)
+                } else {
+                    match self.delay.poll().map_err(|_| ())? {
+                        Async::Ready(()) => Err(DisconnectReason::Timeout(self.timeout)),
+                        Async::NotReady => return Ok(Async::NotReady),
+                    }
+                }
+            }
+        };
+        Ok(Async::Ready((res, self.lines.take().unwrap())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smtp_message::StreamExt;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn session_end_comes_first() {
+        let now = Instant::now();
+        let minute = Duration::from_secs(60);
+        let (end, timeout) = deadline(minute, Timeout::Mail, Some(now));
+        assert_eq!((end, timeout), (now, Timeout::Session));
+        let (end, timeout) = deadline(minute, Timeout::Mail, Some(now + 2 * minute));
+        assert!(end < now + 2 * minute);
+        assert_eq!(timeout, Timeout::Mail);
+        assert_eq!(deadline(minute, Timeout::Rcpt, None).1, Timeout::Rcpt);
+    }
+
+    #[test]
+    fn next_line_gives_up() {
+        let lines = CrlfLines::new(
+            stream::iter_ok(vec![BytesMut::from(&b"NOOP\r\n"[..])])
+                .chain(stream::poll_fn(|| Ok(Async::NotReady)))
+                .prependable(),
+        );
+        let end = Instant::now() + Duration::from_millis(10);
+        let mut rt = Runtime::new().unwrap();
+        let shutdown = Shutdown::new();
+        let (line, lines) = rt
+            .block_on(next_line(lines, (end, Timeout::Mail), Some(shutdown.clone())))
+            .unwrap();
+        assert_eq!(
+            line,
+            Ok(Some(Line::Complete(BytesMut::from(&b"NOOP\r\n"[..]))))
+        );
+        let (line, lines) = rt
+            .block_on(next_line(lines, (end, Timeout::Mail), Some(shutdown.clone())))
+            .unwrap();
+        assert_eq!(line, Err(DisconnectReason::Timeout(Timeout::Mail)));
+        shutdown.trigger();
+        let (line, _) = rt
+            .block_on(next_line(lines, (end, Timeout::Mail), Some(shutdown)))
+            .unwrap();
+        assert_eq!(line, Err(DisconnectReason::Shutdown));
+    }
+}
@@ -0,0 +1,237 @@
+use smtp_message::{Attributes, SmtpString};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str,
+};
+
+use metadata::ConnectionMetadata;
+
+// Attributes honored in XCLIENT and XFORWARD, as advertised in the reply to
+// EHLO (see http://www.postfix.org/XCLIENT_README.html and
+// http://www.postfix.org/XFORWARD_README.html)
+pub const XCLIENT_ATTRIBUTES: &[&str] = &["NAME", "ADDR", "PORT", "HELO", "LOGIN"];
+pub const XFORWARD_ATTRIBUTES: &[&str] =
+    &["NAME", "ADDR", "PORT", "PROTO", "HELO", "IDENT", "SOURCE"];
+
+// Values telling that the proxy does not know the attribute
+fn unavailable(value: &SmtpString) -> bool {
+    let value = &value.bytes()[..];
+    value.eq_ignore_ascii_case(b"[UNAVAILABLE]") || value.eq_ignore_ascii_case(b"[TEMPUNAVAIL]")
+}
+
+pub fn parse_ip(value: &SmtpString) -> Result<IpAddr, ()> {
+    let value = str::from_utf8(&value.bytes()[..]).map_err(|_| ())?;
+    let value = match value.get(..5) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("IPV6:") => &value[5..],
+        _ => value,
+    };
+    value.parse().map_err(|_| ())
+}
+
+fn parse_port(value: &SmtpString) -> Result<u16, ()> {
+    let value = str::from_utf8(&value.bytes()[..]).map_err(|_| ())?;
+    value.parse().map_err(|_| ())
+}
+
+// Values that end up in replies and milter packets, and thus may not hold a
+// CR, LF, NUL or other control byte
+fn parse_text(value: &SmtpString) -> Result<SmtpString, ()> {
+    if value.bytes().iter().all(|&c| c >= b' ' && c != 0x7f) {
+        Ok(value.clone())
+    } else {
+        Err(())
+    }
+}
+
+// Client names are checked as Postfix does: labels of letters, digits, `-`
+// and `_`, not starting nor ending with a `-`, up to 63 bytes each and 255 in
+// all, and not all numeric
+fn parse_name(value: &SmtpString) -> Result<SmtpString, ()> {
+    let name = &value.bytes()[..];
+    let name = if name.ends_with(b".") {
+        &name[..name.len() - 1]
+    } else {
+        name
+    };
+    let label_ok = |label: &[u8]| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with(b"-")
+            && !label.ends_with(b"-")
+            && label
+                .iter()
+                .all(|&c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+    };
+    let numeric = name.iter().all(|&c| c.is_ascii_digit() || c == b'.');
+    if name.len() <= 255 && !numeric && name.split(|&c| c == b'.').all(label_ok) {
+        Ok(value.clone())
+    } else {
+        Err(())
+    }
+}
+
+// Overrides the attributes of the client with those told by XCLIENT, the name
+// being dropped along with the address it was for if only the latter is told.
+// Fails, leaving `conn_meta` untouched, if one of them is unknown or invalid,
+// eg. a name that is not a hostname.
+pub fn apply_xclient<U>(
+    attrs: &Attributes,
+    conn_meta: &mut ConnectionMetadata<U>,
+) -> Result<(), ()> {
+    let mut name = None;
+    let mut addr = None;
+    let mut port = None;
+    let mut helo = None;
+    let mut login = None;
+    for (k, v) in attrs.0.iter() {
+        let k = str::from_utf8(&k.bytes()[..])
+            .map_err(|_| ())?
+            .to_ascii_uppercase();
+        let v = if unavailable(v) { None } else { Some(v) };
+        match &k[..] {
+            "NAME" => {
+                name = Some(match v {
+                    Some(v) => Some(parse_name(v)?),
+                    None => None,
+                })
+            }
+            "ADDR" => {
+                addr = Some(match v {
+                    Some(v) => Some(parse_ip(v)?),
+                    None => None,
+                })
+            }
+            "PORT" => {
+                port = Some(match v {
+                    Some(v) => Some(parse_port(v)?),
+                    None => None,
+                })
+            }
+            "HELO" => {
+                helo = Some(match v {
+                    Some(v) => Some(parse_text(v)?),
+                    None => None,
+                })
+            }
+            "LOGIN" => {
+                login = Some(match v {
+                    Some(v) => Some(parse_text(v)?),
+                    None => None,
+                })
+            }
+            _ => return Err(()),
+        }
+    }
+    if addr.is_some() && name.is_none() {
+        name = Some(None);
+    }
+    let ip = addr.unwrap_or_else(|| conn_meta.peer_addr.map(|a| a.ip()));
+    let port = port.unwrap_or_else(|| conn_meta.peer_addr.map(|a| a.port()));
+    conn_meta.peer_addr = ip.map(|ip| SocketAddr::new(ip, port.unwrap_or(0)));
+    if let Some(name) = name {
+        conn_meta.peer_name = name;
+    }
+    if let Some(helo) = helo {
+        conn_meta.helo = helo;
+    }
+    if let Some(login) = login {
+        conn_meta.login = login;
+    }
+    Ok(())
+}
+
+// Records the attributes told by XFORWARD, which hold until the end of the
+// next mail transaction. Fails, leaving `conn_meta` untouched, if one of them
+// is unknown.
+pub fn apply_xforward<U>(
+    attrs: &Attributes,
+    conn_meta: &mut ConnectionMetadata<U>,
+) -> Result<(), ()> {
+    let known = |k: &SmtpString| {
+        XFORWARD_ATTRIBUTES
+            .iter()
+            .any(|a| k.bytes()[..].eq_ignore_ascii_case(a.as_bytes()))
+    };
+    if !attrs.0.iter().all(|(k, _)| known(k)) {
+        return Err(());
+    }
+    conn_meta.xforward.0.extend(attrs.0.iter().cloned());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attrs(attrs: &[(&str, &str)]) -> Attributes {
+        Attributes(attrs.iter().map(|&(k, v)| (k.into(), v.into())).collect())
+    }
+
+    #[test]
+    fn overrides_client_attributes() {
+        let mut conn_meta = ConnectionMetadata::new(());
+        conn_meta.peer_addr = Some("127.0.0.1:41234".parse().unwrap());
+        conn_meta.helo = Some("localhost".into());
+
+        apply_xclient(&attrs(&[("addr", "IPv6:2001:db8::1")]), &mut conn_meta).unwrap();
+        assert_eq!(
+            conn_meta.peer_addr,
+            Some("[2001:db8::1]:41234".parse().unwrap())
+        );
+
+        let new = attrs(&[
+            ("NAME", "foo-1_a.example.org."),
+            ("ADDR", "192.0.2.1"),
+            ("PORT", "56324"),
+            ("HELO", "[UNAVAILABLE]"),
+            ("LOGIN", "jdoe"),
+        ]);
+        apply_xclient(&new, &mut conn_meta).unwrap();
+        assert_eq!(
+            conn_meta.peer_addr,
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(conn_meta.peer_name, Some("foo-1_a.example.org.".into()));
+        assert_eq!(conn_meta.helo, None);
+        assert_eq!(conn_meta.login, Some("jdoe".into()));
+
+        apply_xclient(&attrs(&[("ADDR", "[TEMPUNAVAIL]")]), &mut conn_meta).unwrap();
+        assert_eq!(conn_meta.peer_addr, None);
+        assert_eq!(conn_meta.peer_name, None);
+        assert_eq!(conn_meta.login, Some("jdoe".into()));
+    }
+
+    #[test]
+    fn refuses_invalid_xclient_attributes() {
+        let tests: &[&[(&str, &str)]] = &[
+            &[("NAME", "foo.example.org"), ("ADDR", "foo.example.org")],
+            &[("PORT", "65536")],
+            &[("IDENT", "jdoe")],
+            &[("NAME", "foo.example.org\r\n250 ok")],
+            &[("NAME", "foo_.-bar.example.org")],
+            &[("NAME", "192.0.2.1")],
+            &[("NAME", "foo..example.org")],
+            &[("NAME", "foo.example.org"), ("HELO", "foo\0bar")],
+            &[("NAME", "foo.example.org"), ("LOGIN", "jdoe\n")],
+        ];
+        for test in tests {
+            let mut conn_meta = ConnectionMetadata::new(());
+            assert!(apply_xclient(&attrs(test), &mut conn_meta).is_err());
+            assert_eq!(conn_meta.peer_name, None);
+        }
+    }
+
+    #[test]
+    fn records_forwarded_attributes() {
+        let mut conn_meta = ConnectionMetadata::new(());
+        apply_xforward(&attrs(&[("NAME", "foo.example.org")]), &mut conn_meta).unwrap();
+        apply_xforward(&attrs(&[("addr", "192.0.2.1")]), &mut conn_meta).unwrap();
+        assert!(apply_xforward(&attrs(&[("LOGIN", "jdoe")]), &mut conn_meta).is_err());
+        assert_eq!(
+            conn_meta.xforward.get(b"NAME"),
+            Some(&"foo.example.org".into())
+        );
+        assert_eq!(conn_meta.xforward.get(b"ADDR"), Some(&"192.0.2.1".into()));
+        assert_eq!(conn_meta.xforward.get(b"LOGIN"), None);
+    }
+}